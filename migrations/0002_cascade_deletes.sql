@@ -0,0 +1,75 @@
+-- Add the users.created_at column that create_user already writes, and
+-- rebuild every table with a foreign key so deleting a user or playlist
+-- cascades to the rows that depend on it. SQLite cannot alter constraints
+-- in place, so each table is copied into a new definition. Rows orphaned
+-- by earlier manual deletes are dropped during the copy.
+
+ALTER TABLE users ADD COLUMN created_at TIMESTAMP;
+
+CREATE TABLE audio_files_new (
+    id TEXT PRIMARY KEY,
+    filename TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    mime_type TEXT NOT NULL,
+    user_folder TEXT NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+INSERT INTO audio_files_new (id, filename, user_id, created_at, mime_type, user_folder)
+SELECT id, filename, user_id, created_at, mime_type, user_folder FROM audio_files
+WHERE user_id IN (SELECT id FROM users);
+
+CREATE TABLE playlists_new (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+INSERT INTO playlists_new (id, name, user_id, created_at)
+SELECT id, name, user_id, created_at FROM playlists
+WHERE user_id IN (SELECT id FROM users);
+
+CREATE TABLE playlist_items_new (
+    id TEXT PRIMARY KEY,
+    playlist_id TEXT NOT NULL,
+    audio_id TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    FOREIGN KEY (playlist_id) REFERENCES playlists(id) ON DELETE CASCADE,
+    FOREIGN KEY (audio_id) REFERENCES audio_files(id) ON DELETE CASCADE
+);
+INSERT INTO playlist_items_new (id, playlist_id, audio_id, position)
+SELECT id, playlist_id, audio_id, position FROM playlist_items
+WHERE playlist_id IN (SELECT id FROM playlists_new)
+  AND audio_id IN (SELECT id FROM audio_files_new);
+
+CREATE TABLE refresh_tokens_new (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    token_hash TEXT UNIQUE NOT NULL,
+    access_jti TEXT NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    revoked BOOLEAN NOT NULL DEFAULT 0,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+INSERT INTO refresh_tokens_new (id, user_id, token_hash, access_jti, expires_at, created_at, revoked)
+SELECT id, user_id, token_hash, access_jti, expires_at, created_at, revoked FROM refresh_tokens
+WHERE user_id IN (SELECT id FROM users);
+
+DROP TABLE playlist_items;
+DROP TABLE playlists;
+DROP TABLE audio_files;
+DROP TABLE refresh_tokens;
+
+ALTER TABLE audio_files_new RENAME TO audio_files;
+ALTER TABLE playlists_new RENAME TO playlists;
+ALTER TABLE playlist_items_new RENAME TO playlist_items;
+ALTER TABLE refresh_tokens_new RENAME TO refresh_tokens;
+
+CREATE INDEX idx_audio_files_user_id ON audio_files(user_id);
+CREATE INDEX idx_playlists_user_id ON playlists(user_id);
+CREATE INDEX idx_playlist_items_playlist_id ON playlist_items(playlist_id, position);
+CREATE INDEX idx_playlist_items_audio_id ON playlist_items(audio_id);
+CREATE INDEX idx_refresh_tokens_user_id ON refresh_tokens(user_id);
+CREATE INDEX idx_refresh_tokens_access_jti ON refresh_tokens(access_jti);
@@ -0,0 +1,14 @@
+-- Tags and stream properties extracted from each file at upload time.
+
+ALTER TABLE audio_files ADD COLUMN title TEXT;
+ALTER TABLE audio_files ADD COLUMN artist TEXT;
+ALTER TABLE audio_files ADD COLUMN album TEXT;
+ALTER TABLE audio_files ADD COLUMN album_artist TEXT;
+ALTER TABLE audio_files ADD COLUMN track_number INTEGER;
+ALTER TABLE audio_files ADD COLUMN disc_number INTEGER;
+ALTER TABLE audio_files ADD COLUMN year INTEGER;
+ALTER TABLE audio_files ADD COLUMN genre TEXT;
+ALTER TABLE audio_files ADD COLUMN duration_ms INTEGER;
+ALTER TABLE audio_files ADD COLUMN sample_rate INTEGER;
+ALTER TABLE audio_files ADD COLUMN channels INTEGER;
+ALTER TABLE audio_files ADD COLUMN bitrate_kbps INTEGER;
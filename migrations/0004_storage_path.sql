@@ -0,0 +1,5 @@
+-- Location of each file relative to the upload root. Rows uploaded before
+-- this migration keep NULL and are found through their legacy
+-- `{user_folder}/{id}_{filename}` path.
+
+ALTER TABLE audio_files ADD COLUMN storage_path TEXT;
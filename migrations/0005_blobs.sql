@@ -0,0 +1,29 @@
+-- Content-addressed storage: identical uploads share one stored blob,
+-- keyed by the SHA-256 of its contents. ref_count is kept in step with the
+-- audio_files rows pointing at a blob by triggers, so it stays right when
+-- rows disappear through ON DELETE CASCADE. Blobs whose count drops to zero
+-- are removed by the server. Rows uploaded before this migration have no
+-- blob and keep their own file.
+
+CREATE TABLE blobs (
+    hash TEXT PRIMARY KEY,
+    storage_path TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    ref_count INTEGER NOT NULL DEFAULT 0,
+    created_at TIMESTAMP NOT NULL
+);
+
+ALTER TABLE audio_files ADD COLUMN blob_hash TEXT REFERENCES blobs(hash);
+CREATE INDEX idx_audio_files_blob_hash ON audio_files(blob_hash);
+
+CREATE TRIGGER audio_files_blob_ref AFTER INSERT ON audio_files
+WHEN NEW.blob_hash IS NOT NULL
+BEGIN
+    UPDATE blobs SET ref_count = ref_count + 1 WHERE hash = NEW.blob_hash;
+END;
+
+CREATE TRIGGER audio_files_blob_unref AFTER DELETE ON audio_files
+WHEN OLD.blob_hash IS NOT NULL
+BEGIN
+    UPDATE blobs SET ref_count = ref_count - 1 WHERE hash = OLD.blob_hash;
+END;
@@ -0,0 +1,10 @@
+-- Per-user storage quotas (NULL means unlimited) and the size of each
+-- audio file, which quotas are measured in. Sizes are filled in from the
+-- blobs for files that have one; older files stay NULL and are not counted.
+
+ALTER TABLE users ADD COLUMN quota_bytes INTEGER;
+ALTER TABLE audio_files ADD COLUMN size INTEGER;
+
+UPDATE audio_files
+SET size = (SELECT size FROM blobs WHERE blobs.hash = audio_files.blob_hash)
+WHERE blob_hash IS NOT NULL;
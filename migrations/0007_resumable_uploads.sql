@@ -0,0 +1,19 @@
+-- Resumable uploads (tus protocol) in progress. The data received so far is
+-- kept in the staging directory as `{id}.tus`; a row is removed once its
+-- upload completes, is terminated or expires. upload_metadata is the raw
+-- Upload-Metadata header, which is echoed back to clients.
+
+CREATE TABLE resumable_uploads (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    length INTEGER NOT NULL,
+    received INTEGER NOT NULL DEFAULT 0,
+    filename TEXT NOT NULL,
+    playlist TEXT,
+    upload_metadata TEXT,
+    created_at TIMESTAMP NOT NULL,
+    expires_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX idx_resumable_uploads_user_id ON resumable_uploads(user_id);
+CREATE INDEX idx_resumable_uploads_expires_at ON resumable_uploads(expires_at);
@@ -0,0 +1,5 @@
+-- Per-user default transcoding for streams that don't ask for a format.
+-- NULL format means the original file is streamed.
+
+ALTER TABLE users ADD COLUMN transcode_format TEXT;
+ALTER TABLE users ADD COLUMN transcode_bitrate_kbps INTEGER;
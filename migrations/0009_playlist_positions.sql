@@ -0,0 +1,30 @@
+-- Keep playlist item positions numbered 1, 2, 3, ... within each
+-- playlist, and give playlists a version that every edit increments so
+-- clients can detect concurrent changes. Existing positions, which could
+-- repeat or leave gaps, are renumbered in their current order.
+
+ALTER TABLE playlists ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+
+UPDATE playlist_items SET position = (
+    SELECT ranked.n FROM (
+        SELECT id, ROW_NUMBER() OVER (PARTITION BY playlist_id ORDER BY position, id) AS n
+        FROM playlist_items
+    ) AS ranked
+    WHERE ranked.id = playlist_items.id
+);
+
+DROP INDEX idx_playlist_items_playlist_id;
+CREATE UNIQUE INDEX idx_playlist_items_position ON playlist_items(playlist_id, position);
+
+-- Close the gap left by a removed item, including items removed by a
+-- cascading delete of their audio file, and count it as a change to the
+-- playlist. The later items are moved through negative positions so no
+-- two rows ever share one.
+CREATE TRIGGER playlist_items_close_gap AFTER DELETE ON playlist_items
+BEGIN
+    UPDATE playlists SET version = version + 1 WHERE id = OLD.playlist_id;
+    UPDATE playlist_items SET position = 1 - position
+    WHERE playlist_id = OLD.playlist_id AND position > OLD.position;
+    UPDATE playlist_items SET position = -position
+    WHERE playlist_id = OLD.playlist_id AND position < 0;
+END;
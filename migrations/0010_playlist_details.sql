@@ -0,0 +1,22 @@
+-- Playlist descriptions, cover images and visibility, and the time a
+-- playlist or its items last changed. Existing playlists count as last
+-- changed when they were created.
+
+ALTER TABLE playlists ADD COLUMN description TEXT;
+ALTER TABLE playlists ADD COLUMN cover_image_url TEXT;
+ALTER TABLE playlists ADD COLUMN visibility TEXT NOT NULL DEFAULT 'private'
+    CHECK (visibility IN ('private', 'public'));
+ALTER TABLE playlists ADD COLUMN updated_at TIMESTAMP;
+UPDATE playlists SET updated_at = created_at;
+
+DROP TRIGGER playlist_items_close_gap;
+CREATE TRIGGER playlist_items_close_gap AFTER DELETE ON playlist_items
+BEGIN
+    UPDATE playlists
+    SET version = version + 1, updated_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now')
+    WHERE id = OLD.playlist_id;
+    UPDATE playlist_items SET position = 1 - position
+    WHERE playlist_id = OLD.playlist_id AND position > OLD.position;
+    UPDATE playlist_items SET position = -position
+    WHERE playlist_id = OLD.playlist_id AND position < 0;
+END;
@@ -0,0 +1,40 @@
+-- Groups of users, playlists shared with users or groups at read or edit
+-- level, and who added each playlist item. Existing items count as added
+-- by the playlist's owner.
+
+CREATE TABLE user_groups (
+    id TEXT PRIMARY KEY,
+    name TEXT UNIQUE NOT NULL,
+    created_at TIMESTAMP NOT NULL
+);
+
+CREATE TABLE user_group_members (
+    group_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    PRIMARY KEY (group_id, user_id),
+    FOREIGN KEY (group_id) REFERENCES user_groups(id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+CREATE INDEX idx_user_group_members_user_id ON user_group_members(user_id);
+
+-- Each share is with exactly one user or one group
+CREATE TABLE playlist_shares (
+    id TEXT PRIMARY KEY,
+    playlist_id TEXT NOT NULL,
+    user_id TEXT,
+    group_id TEXT,
+    access TEXT NOT NULL CHECK (access IN ('read', 'edit')),
+    created_at TIMESTAMP NOT NULL,
+    CHECK ((user_id IS NULL) != (group_id IS NULL)),
+    FOREIGN KEY (playlist_id) REFERENCES playlists(id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+    FOREIGN KEY (group_id) REFERENCES user_groups(id) ON DELETE CASCADE
+);
+CREATE UNIQUE INDEX idx_playlist_shares_user ON playlist_shares(playlist_id, user_id);
+CREATE UNIQUE INDEX idx_playlist_shares_group ON playlist_shares(playlist_id, group_id);
+CREATE INDEX idx_playlist_shares_user_id ON playlist_shares(user_id);
+CREATE INDEX idx_playlist_shares_group_id ON playlist_shares(group_id);
+
+ALTER TABLE playlist_items ADD COLUMN added_by TEXT REFERENCES users(id) ON DELETE SET NULL;
+UPDATE playlist_items
+SET added_by = (SELECT user_id FROM playlists WHERE playlists.id = playlist_items.playlist_id);
@@ -1,61 +1,688 @@
-use actix_web::{web, HttpResponse};
-use chrono::Utc;
+use actix_web::dev::Payload;
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params};
+use chrono::{DateTime, Duration, Utc};
+use futures::future::LocalBoxFuture;
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use sqlx::{SqliteConnection, SqlitePool};
+use uuid::Uuid;
 
-use crate::config::AppState;
+use crate::config::{AppState, AuthSettings};
 use crate::error::AppError;
-use crate::models::{AuthResponse, Claims, LoginRequest};
+use crate::models::{AuthResponse, Claims, LoginRequest, RefreshTokenRequest, StreamClaims, User};
 
-pub async fn validate_token(token: &str, secret: &str) -> Option<String> {
+/// Audience of stream tokens.
+const STREAM_AUDIENCE: &str = "stream";
+
+/// Decodes an access token and rejects it if its `jti` has been revoked.
+pub async fn validate_token(
+    token: &str,
+    secret: &str,
+    pool: &SqlitePool,
+) -> Result<Claims, AppError> {
     let validation = Validation::default();
-    match decode::<Claims>(
+    let token_data = decode::<Claims>(
         token,
         &DecodingKey::from_secret(secret.as_ref()),
         &validation,
-    ) {
-        Ok(token_data) => Some(token_data.claims.sub),
-        Err(_) => None,
+    )?;
+
+    let revoked = sqlx::query("SELECT jti FROM revoked_tokens WHERE jti = ?")
+        .bind(&token_data.claims.jti)
+        .fetch_optional(pool)
+        .await?;
+    if revoked.is_some() {
+        return Err(AppError::Unauthorized("Token has been revoked".to_string()));
+    }
+
+    Ok(token_data.claims)
+}
+
+/// What a stream token is good for.
+#[derive(Debug, Clone)]
+enum StreamScope {
+    Audio(String),
+    Playlist(String),
+}
+
+/// Signs a token that lets its holder stream `audio_id` as `user_id` until
+/// it expires. Media players can't send an `Authorization` header, so links
+/// in exported playlists carry one of these instead.
+pub fn issue_stream_token(
+    user_id: &str,
+    audio_id: &str,
+    settings: &AuthSettings,
+) -> Result<String, AppError> {
+    sign_stream_token(user_id, StreamScope::Audio(audio_id.to_string()), settings)
+}
+
+/// Signs a token that lets its holder fetch the HLS playlists of
+/// `playlist_id` as `user_id` until it expires. The tracks themselves need
+/// tokens of their own, which those playlists carry.
+pub fn issue_playlist_stream_token(
+    user_id: &str,
+    playlist_id: &str,
+    settings: &AuthSettings,
+) -> Result<String, AppError> {
+    sign_stream_token(
+        user_id,
+        StreamScope::Playlist(playlist_id.to_string()),
+        settings,
+    )
+}
+
+fn sign_stream_token(
+    user_id: &str,
+    scope: StreamScope,
+    settings: &AuthSettings,
+) -> Result<String, AppError> {
+    let (audio, playlist) = match scope {
+        StreamScope::Audio(id) => (Some(id), None),
+        StreamScope::Playlist(id) => (None, Some(id)),
+    };
+    let claims = StreamClaims {
+        sub: user_id.to_string(),
+        exp: (Utc::now() + settings.stream_token_ttl()).timestamp() as usize,
+        jti: Uuid::new_v4().to_string(),
+        aud: STREAM_AUDIENCE.to_string(),
+        audio,
+        playlist,
+    };
+    Ok(encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(settings.secret_key.as_ref()),
+    )?)
+}
+
+/// Decodes a stream token and checks that it is for `scope`.
+fn validate_stream_token(
+    token: &str,
+    scope: &StreamScope,
+    secret: &str,
+) -> Result<StreamClaims, AppError> {
+    let mut validation = Validation::default();
+    validation.set_audience(&[STREAM_AUDIENCE]);
+    validation.set_required_spec_claims(&["exp", "aud"]);
+    let claims = decode::<StreamClaims>(
+        token,
+        &DecodingKey::from_secret(secret.as_ref()),
+        &validation,
+    )?
+    .claims;
+
+    match scope {
+        StreamScope::Audio(id) if claims.audio.as_ref() != Some(id) => Err(AppError::Forbidden(
+            "Stream token is for another audio file".to_string(),
+        )),
+        StreamScope::Playlist(id) if claims.playlist.as_ref() != Some(id) => Err(
+            AppError::Forbidden("Stream token is for another playlist".to_string()),
+        ),
+        _ => Ok(claims),
     }
 }
 
+/// Hashes a password with Argon2id and a freshly generated per-user salt.
+pub fn hash_password(password: &str) -> Result<String, AppError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| AppError::Internal(e.to_string()))
+}
+
+/// Checks a password against the value stored in `users.password`.
+///
+/// Rows created before hashing was introduced hold the password in plaintext;
+/// those are compared directly so the caller can upgrade them after login.
+pub fn verify_password(password: &str, stored: &str) -> bool {
+    match PasswordHash::new(stored) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => constant_time_eq(password.as_bytes(), stored.as_bytes()),
+    }
+}
+
+/// Returns true if the stored password is plaintext, or was hashed with
+/// another algorithm or weaker parameters than `hash_password` uses now.
+pub fn needs_rehash(stored: &str) -> bool {
+    let Ok(hash) = PasswordHash::new(stored) else {
+        return true;
+    };
+    let current = Params::default();
+    match (Algorithm::try_from(hash.algorithm), Params::try_from(&hash)) {
+        (Ok(Algorithm::Argon2id), Ok(params)) => {
+            params.m_cost() < current.m_cost()
+                || params.t_cost() < current.t_cost()
+                || params.p_cost() < current.p_cost()
+        }
+        _ => true,
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 pub async fn login(
     req: web::Json<LoginRequest>,
     state: web::Data<AppState>,
 ) -> Result<HttpResponse, AppError> {
-    let user = sqlx::query!(
-        "SELECT id, is_admin FROM users WHERE username = ? AND password = ?",
-        req.username,
-        req.password
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE username = ?")
+        .bind(&req.username)
+        .fetch_optional(&state.db_pool)
+        .await?;
+
+    let user = match user {
+        Some(user) if verify_password(&req.password, &user.password) => user,
+        _ => return Err(AppError::Unauthorized("Invalid credentials".to_string())),
+    };
+
+    // Upgrade legacy plaintext passwords (e.g. the seeded admin account) and
+    // hashes made with weaker parameters
+    if needs_rehash(&user.password) {
+        let hashed = hash_password(&req.password)?;
+        sqlx::query("UPDATE users SET password = ? WHERE id = ?")
+            .bind(hashed)
+            .bind(&user.id)
+            .execute(&state.db_pool)
+            .await?;
+    }
+
+    let mut conn = state.db_pool.acquire().await?;
+    let tokens = issue_tokens(&user.id, &state.config.auth, &mut conn).await?;
+
+    Ok(HttpResponse::Ok().json(tokens))
+}
+
+/// Exchanges a refresh token for a new access/refresh token pair.
+///
+/// The presented refresh token is revoked in the process. Presenting a token
+/// that was already rotated means it has leaked, so every session of that
+/// user is revoked.
+pub async fn refresh_token(
+    req: web::Json<RefreshTokenRequest>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let tokens =
+        rotate_refresh_token(&req.refresh_token, &state.config.auth, &state.db_pool).await?;
+    Ok(HttpResponse::Ok().json(tokens))
+}
+
+async fn rotate_refresh_token(
+    refresh_token: &str,
+    settings: &AuthSettings,
+    pool: &SqlitePool,
+) -> Result<AuthResponse, AppError> {
+    let mut tx = pool.begin().await?;
+
+    let row = sqlx::query_as::<_, (String, String, DateTime<Utc>, bool)>(
+        "SELECT id, user_id, expires_at, revoked FROM refresh_tokens WHERE token_hash = ?",
     )
-    .fetch_optional(&state.db_pool)
-    .await
-    .map_err(|e| AppError(e.to_string()))?;
-
-    if let Some(user) = user {
-        let claims = Claims {
-            sub: user.id.expect("User ID should not be null"),
-            exp: (Utc::now() + chrono::Duration::days(1)).timestamp() as usize,
-        };
-
-        let token = encode(
-            &Header::default(),
-            &claims,
-            &EncodingKey::from_secret(state.secret_key.as_ref()),
-        )
-        .map_err(|e| AppError(e.to_string()))?;
+    .bind(hash_refresh_token(refresh_token))
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let (session_id, user_id, expires_at, revoked) =
+        row.ok_or_else(|| AppError::Unauthorized("Invalid refresh token".to_string()))?;
 
-        Ok(HttpResponse::Ok().json(AuthResponse { token }))
-    } else {
-        Err(AppError("Invalid credentials".to_string()))
+    if revoked {
+        revoke_user_tokens(&user_id, settings.access_token_ttl(), &mut tx).await?;
+        tx.commit().await?;
+        return Err(AppError::Unauthorized("Invalid refresh token".to_string()));
+    }
+    if expires_at < Utc::now() {
+        return Err(AppError::Unauthorized("Refresh token expired".to_string()));
     }
+
+    revoke_session(&session_id, settings.access_token_ttl(), &mut tx).await?;
+    let tokens = issue_tokens(&user_id, settings, &mut tx).await?;
+    tx.commit().await?;
+
+    Ok(tokens)
 }
 
-pub async fn check_admin(user_id: &str, pool: &sqlx::SqlitePool) -> Result<bool, AppError> {
-    let is_admin = sqlx::query!("SELECT is_admin FROM users WHERE id = ?", user_id)
-        .fetch_one(pool)
+/// Revokes the caller's access token and the refresh token issued with it.
+pub async fn logout(
+    user: AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let mut tx = state.db_pool.begin().await?;
+
+    let session_id: Option<String> =
+        sqlx::query_scalar("SELECT id FROM refresh_tokens WHERE access_jti = ?")
+            .bind(&user.token_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+
+    match session_id {
+        Some(session_id) => {
+            revoke_session(&session_id, state.config.auth.access_token_ttl(), &mut tx).await?
+        }
+        None => {
+            revoke_jti(
+                &user.token_id,
+                state.config.auth.access_token_ttl(),
+                &mut tx,
+            )
+            .await?
+        }
+    }
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().body("Logged out"))
+}
+
+/// Creates a new session: a short-lived access token and a refresh token
+/// whose hash is stored alongside the access token's `jti`.
+async fn issue_tokens(
+    user_id: &str,
+    settings: &AuthSettings,
+    conn: &mut SqliteConnection,
+) -> Result<AuthResponse, AppError> {
+    let now = Utc::now();
+    let claims = Claims {
+        sub: user_id.to_string(),
+        exp: (now + settings.access_token_ttl()).timestamp() as usize,
+        jti: Uuid::new_v4().to_string(),
+    };
+
+    let token = encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(settings.secret_key.as_ref()),
+    )?;
+
+    let mut secret_bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut secret_bytes);
+    let refresh_token: String = secret_bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+    sqlx::query(
+        "INSERT INTO refresh_tokens (id, user_id, token_hash, access_jti, expires_at, created_at, revoked) VALUES (?, ?, ?, ?, ?, ?, 0)",
+    )
+    .bind(Uuid::new_v4().to_string())
+    .bind(user_id)
+    .bind(hash_refresh_token(&refresh_token))
+    .bind(&claims.jti)
+    .bind(now + settings.refresh_token_ttl())
+    .bind(now)
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(AuthResponse {
+        token,
+        refresh_token,
+        expires_in: settings.access_token_ttl().num_seconds(),
+    })
+}
+
+/// An `Authorization` header value for a new session of `user_id`, for
+/// tests.
+#[cfg(test)]
+pub async fn test_bearer(user_id: &str, state: &AppState) -> String {
+    let mut conn = state.db_pool.acquire().await.unwrap();
+    let tokens = issue_tokens(user_id, &state.config.auth, &mut conn)
         .await
-        .map_err(|e| AppError(e.to_string()))?
-        .is_admin;
+        .unwrap();
+    format!("Bearer {}", tokens.token)
+}
+
+/// Revokes every access and refresh token issued to a user.
+pub async fn revoke_user_tokens(
+    user_id: &str,
+    access_token_ttl: Duration,
+    conn: &mut SqliteConnection,
+) -> Result<(), AppError> {
+    let sessions: Vec<String> =
+        sqlx::query_scalar("SELECT id FROM refresh_tokens WHERE user_id = ? AND revoked = 0")
+            .bind(user_id)
+            .fetch_all(&mut *conn)
+            .await?;
+
+    for session_id in sessions {
+        revoke_session(&session_id, access_token_ttl, conn).await?;
+    }
+    Ok(())
+}
+
+async fn revoke_session(
+    session_id: &str,
+    access_token_ttl: Duration,
+    conn: &mut SqliteConnection,
+) -> Result<(), AppError> {
+    let access_jti: String =
+        sqlx::query_scalar("SELECT access_jti FROM refresh_tokens WHERE id = ?")
+            .bind(session_id)
+            .fetch_one(&mut *conn)
+            .await?;
+
+    sqlx::query("UPDATE refresh_tokens SET revoked = 1 WHERE id = ?")
+        .bind(session_id)
+        .execute(&mut *conn)
+        .await?;
+
+    revoke_jti(&access_jti, access_token_ttl, conn).await
+}
+
+async fn revoke_jti(
+    jti: &str,
+    access_token_ttl: Duration,
+    conn: &mut SqliteConnection,
+) -> Result<(), AppError> {
+    let now = Utc::now();
+
+    // Entries are only needed until the access token would have expired anyway
+    sqlx::query("DELETE FROM revoked_tokens WHERE expires_at < ?")
+        .bind(now)
+        .execute(&mut *conn)
+        .await?;
 
-    Ok(is_admin)
+    sqlx::query("INSERT OR IGNORE INTO revoked_tokens (jti, expires_at) VALUES (?, ?)")
+        .bind(jti)
+        .bind(now + access_token_ttl)
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(())
+}
+
+fn hash_refresh_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// The caller identified by a valid `Authorization: Bearer` token.
+///
+/// Handlers take this as an argument to require authentication; requests
+/// without a valid token are rejected with 401 before the handler runs.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub user_id: String,
+    pub is_admin: bool,
+    /// The `jti` of the access token used for this request
+    pub token_id: String,
+}
+
+impl AuthenticatedUser {
+    /// Returns true if the user is `owner_id` or an admin.
+    pub fn can_access(&self, owner_id: &str) -> bool {
+        self.user_id == owner_id || self.is_admin
+    }
+
+    /// The user a valid token was issued to.
+    async fn load(
+        user_id: String,
+        token_id: String,
+        pool: &SqlitePool,
+    ) -> Result<AuthenticatedUser, AppError> {
+        // The token may outlive the account it was issued for
+        let is_admin: bool = sqlx::query_scalar("SELECT is_admin FROM users WHERE id = ?")
+            .bind(&user_id)
+            .fetch_optional(pool)
+            .await?
+            .ok_or_else(|| AppError::Unauthorized("Invalid token".to_string()))?;
+
+        Ok(AuthenticatedUser {
+            user_id,
+            is_admin,
+            token_id,
+        })
+    }
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = AppError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let token = req
+            .headers()
+            .get("Authorization")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|s| s.strip_prefix("Bearer "))
+            .map(|s| s.to_string());
+        let state = req.app_data::<web::Data<AppState>>().cloned();
+
+        Box::pin(async move {
+            let state =
+                state.ok_or_else(|| AppError::Internal("Application state missing".to_string()))?;
+            let token = token
+                .ok_or_else(|| AppError::Unauthorized("Authentication required".to_string()))?;
+
+            let claims =
+                validate_token(&token, &state.config.auth.secret_key, &state.db_pool).await?;
+            AuthenticatedUser::load(claims.sub, claims.jti, &state.db_pool).await
+        })
+    }
+}
+
+/// The caller of a route streaming the audio file `{id}`: an
+/// [`AuthenticatedUser`], or the holder of a stream token for that file
+/// given as `?token=`.
+#[derive(Debug, Clone)]
+pub struct StreamUser(pub AuthenticatedUser);
+
+/// The caller of a route streaming the playlist `{id}` with HLS: an
+/// [`AuthenticatedUser`], or the holder of a stream token for that
+/// playlist given as `?token=`.
+#[derive(Debug, Clone)]
+pub struct PlaylistStreamUser(pub AuthenticatedUser);
+
+#[derive(serde::Deserialize)]
+struct StreamTokenQuery {
+    token: Option<String>,
+}
+
+impl FromRequest for StreamUser {
+    type Error = AppError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let user = stream_user(req, payload, StreamScope::Audio);
+        Box::pin(async move { Ok(StreamUser(user.await?)) })
+    }
+}
+
+impl FromRequest for PlaylistStreamUser {
+    type Error = AppError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let user = stream_user(req, payload, StreamScope::Playlist);
+        Box::pin(async move { Ok(PlaylistStreamUser(user.await?)) })
+    }
+}
+
+/// Authenticates by the `Authorization` header if there is one, and
+/// otherwise by a `?token=` stream token for the route's `{id}`.
+fn stream_user(
+    req: &HttpRequest,
+    payload: &mut Payload,
+    scope: fn(String) -> StreamScope,
+) -> LocalBoxFuture<'static, Result<AuthenticatedUser, AppError>> {
+    if req.headers().contains_key("Authorization") {
+        return Box::pin(AuthenticatedUser::from_request(req, payload));
+    }
+
+    let token = web::Query::<StreamTokenQuery>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.into_inner().token);
+    let id = req.match_info().get("id").map(str::to_string);
+    let state = req.app_data::<web::Data<AppState>>().cloned();
+
+    Box::pin(async move {
+        let state =
+            state.ok_or_else(|| AppError::Internal("Application state missing".to_string()))?;
+        let token =
+            token.ok_or_else(|| AppError::Unauthorized("Authentication required".to_string()))?;
+        let id = id.ok_or_else(|| AppError::Internal("Stream route without an id".to_string()))?;
+
+        let claims = validate_stream_token(&token, &scope(id), &state.config.auth.secret_key)?;
+        AuthenticatedUser::load(claims.sub, claims.jti, &state.db_pool).await
+    })
+}
+
+/// An [`AuthenticatedUser`] that is also an admin; anyone else gets 403.
+#[derive(Debug, Clone)]
+pub struct AdminUser(pub AuthenticatedUser);
+
+impl FromRequest for AdminUser {
+    type Error = AppError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let user = AuthenticatedUser::from_request(req, payload);
+
+        Box::pin(async move {
+            let user = user.await?;
+            if !user.is_admin {
+                return Err(AppError::Forbidden("Admin privileges required".to_string()));
+            }
+            Ok(AdminUser(user))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::ResponseError;
+
+    use crate::db;
+
+    const SECRET: &str = "test secret";
+
+    fn settings() -> AuthSettings {
+        AuthSettings {
+            secret_key: SECRET.to_string(),
+            ..AuthSettings::default()
+        }
+    }
+
+    async fn login_admin(pool: &SqlitePool) -> AuthResponse {
+        let mut conn = pool.acquire().await.unwrap();
+        issue_tokens("admin-user-id", &settings(), &mut conn)
+            .await
+            .unwrap()
+    }
+
+    fn is_unauthorized(result: Result<impl std::fmt::Debug, AppError>) -> bool {
+        result.unwrap_err().status_code() == StatusCode::UNAUTHORIZED
+    }
+
+    #[test]
+    fn hashed_password_verifies() {
+        let hash = hash_password("correct horse").unwrap();
+        assert_ne!(hash, "correct horse");
+        assert!(verify_password("correct horse", &hash));
+        assert!(!needs_rehash(&hash));
+    }
+
+    #[test]
+    fn wrong_password_is_rejected() {
+        let hash = hash_password("correct horse").unwrap();
+        assert!(!verify_password("battery staple", &hash));
+        assert!(!verify_password("", &hash));
+        assert!(!verify_password("admin", "admin1"));
+    }
+
+    #[test]
+    fn plaintext_password_needs_rehash() {
+        assert!(verify_password("admin", "admin"));
+        assert!(needs_rehash("admin"));
+    }
+
+    #[test]
+    fn weaker_hash_needs_rehash() {
+        let salt = SaltString::generate(&mut OsRng);
+        let weak = Argon2::new(
+            Algorithm::Argon2id,
+            argon2::Version::V0x13,
+            Params::new(8 * 1024, 1, 1, None).unwrap(),
+        )
+        .hash_password(b"correct horse", &salt)
+        .unwrap()
+        .to_string();
+        assert!(verify_password("correct horse", &weak));
+        assert!(needs_rehash(&weak));
+
+        let argon2i = Argon2::new(
+            Algorithm::Argon2i,
+            argon2::Version::V0x13,
+            Params::default(),
+        )
+        .hash_password(b"correct horse", &salt)
+        .unwrap()
+        .to_string();
+        assert!(needs_rehash(&argon2i));
+    }
+
+    #[tokio::test]
+    async fn refresh_rotates_both_tokens() {
+        let pool = db::memory_pool().await;
+        let first = login_admin(&pool).await;
+        let claims = validate_token(&first.token, SECRET, &pool).await.unwrap();
+        assert_eq!(claims.sub, "admin-user-id");
+
+        let second = rotate_refresh_token(&first.refresh_token, &settings(), &pool)
+            .await
+            .unwrap();
+        assert_ne!(second.refresh_token, first.refresh_token);
+        assert!(validate_token(&second.token, SECRET, &pool).await.is_ok());
+        assert!(is_unauthorized(
+            validate_token(&first.token, SECRET, &pool).await
+        ));
+
+        let third = rotate_refresh_token(&second.refresh_token, &settings(), &pool)
+            .await
+            .unwrap();
+        assert!(validate_token(&third.token, SECRET, &pool).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn reused_refresh_token_revokes_every_session() {
+        let pool = db::memory_pool().await;
+        let other_session = login_admin(&pool).await;
+        let first = login_admin(&pool).await;
+        let second = rotate_refresh_token(&first.refresh_token, &settings(), &pool)
+            .await
+            .unwrap();
+
+        assert!(is_unauthorized(
+            rotate_refresh_token(&first.refresh_token, &settings(), &pool).await
+        ));
+        for token in [&second.token, &other_session.token] {
+            assert!(is_unauthorized(validate_token(token, SECRET, &pool).await));
+        }
+        for refresh in [&second.refresh_token, &other_session.refresh_token] {
+            assert!(is_unauthorized(
+                rotate_refresh_token(refresh, &settings(), &pool).await
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn revoked_access_token_is_unauthorized() {
+        let pool = db::memory_pool().await;
+        let session = login_admin(&pool).await;
+        let claims = validate_token(&session.token, SECRET, &pool).await.unwrap();
+
+        let mut conn = pool.acquire().await.unwrap();
+        revoke_jti(&claims.jti, settings().access_token_ttl(), &mut conn)
+            .await
+            .unwrap();
+        drop(conn);
+
+        assert!(is_unauthorized(
+            validate_token(&session.token, SECRET, &pool).await
+        ));
+    }
 }
@@ -0,0 +1,268 @@
+//! Deduplicated storage of uploaded files.
+//!
+//! Every upload is stored as a blob named after the SHA-256 of its
+//! contents, so the same album uploaded by two people is kept once. The
+//! `blobs` table counts the `audio_files` rows referring to each blob (kept
+//! up to date by triggers); [`BlobStore::collect_garbage`] removes blobs
+//! nobody refers to any more.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use chrono::Utc;
+use sqlx::{SqliteConnection, SqlitePool};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard, RwLock, RwLockReadGuard};
+
+use crate::error::AppError;
+use crate::storage::{blob_key, StorageBackend};
+
+pub struct BlobStore {
+    storage: Arc<dyn StorageBackend>,
+    /// Held shared while a blob is being referenced and exclusively while
+    /// unreferenced blobs are removed, so a blob cannot be collected between
+    /// an upload finding it and the upload's row being committed.
+    lock: RwLock<()>,
+    /// One lock per hash being stored, so an upload of contents that are
+    /// still being written by another waits for that to succeed or fail.
+    writers: Mutex<HashMap<String, Arc<AsyncMutex<()>>>>,
+}
+
+/// The right to store contents with a given hash. While this is alive the
+/// blob cannot be collected and no other upload of the same contents gets
+/// past [`BlobStore::reserve`].
+///
+/// The blob is recorded in the database with [`BlobReservation::record`]
+/// as part of the transaction that adds the referring row; only once that
+/// is committed are new contents moved into place with
+/// [`BlobReservation::write`].
+pub struct BlobReservation<'a> {
+    store: &'a BlobStore,
+    hash: String,
+    _writer: OwnedMutexGuard<()>,
+    _pin: RwLockReadGuard<'a, ()>,
+}
+
+/// Where a blob is kept, as returned by [`BlobReservation::record`].
+pub struct RecordedBlob {
+    pub key: String,
+    /// The contents still have to be stored with [`BlobReservation::write`]
+    pub is_new: bool,
+}
+
+impl BlobStore {
+    pub fn new(storage: Arc<dyn StorageBackend>) -> Self {
+        BlobStore {
+            storage,
+            lock: RwLock::new(()),
+            writers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Reserves `hash`, the SHA-256 of contents about to be stored, waiting
+    /// for any other upload of the same contents to finish first.
+    pub async fn reserve(&self, hash: &str) -> BlobReservation<'_> {
+        let writer = self
+            .writers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(hash.to_string())
+            .or_default()
+            .clone();
+        let writer = writer.lock_owned().await;
+        let pin = self.lock.read().await;
+
+        BlobReservation {
+            store: self,
+            hash: hash.to_string(),
+            _writer: writer,
+            _pin: pin,
+        }
+    }
+
+    /// Removes every blob no longer referred to by an audio file and
+    /// returns how many were removed.
+    pub async fn collect_garbage(&self, pool: &SqlitePool) -> Result<usize, AppError> {
+        let _exclusive = self.lock.write().await;
+
+        let keys: Vec<String> =
+            sqlx::query_scalar("DELETE FROM blobs WHERE ref_count <= 0 RETURNING storage_path")
+                .fetch_all(pool)
+                .await?;
+
+        for key in &keys {
+            match self.storage.delete(key).await {
+                Ok(()) | Err(AppError::NotFound(_)) => {}
+                Err(e) => eprintln!(
+                    "Failed to remove unreferenced blob {}: {}",
+                    key,
+                    e.message()
+                ),
+            }
+        }
+        Ok(keys.len())
+    }
+}
+
+impl BlobReservation<'_> {
+    /// Finds the blob with the reserved hash, or adds a row for a new one
+    /// of `size` bytes. `conn` should be the transaction that adds the row
+    /// referring to the blob.
+    pub async fn record(
+        &self,
+        conn: &mut SqliteConnection,
+        size: u64,
+        extension: &str,
+    ) -> Result<RecordedBlob, AppError> {
+        let existing: Option<String> =
+            sqlx::query_scalar("SELECT storage_path FROM blobs WHERE hash = ?")
+                .bind(&self.hash)
+                .fetch_optional(&mut *conn)
+                .await?;
+        if let Some(key) = existing {
+            return Ok(RecordedBlob { key, is_new: false });
+        }
+
+        let key = blob_key(&self.hash, extension)?;
+        sqlx::query(
+            "INSERT INTO blobs (hash, storage_path, size, ref_count, created_at) VALUES (?, ?, ?, 0, ?)",
+        )
+        .bind(&self.hash)
+        .bind(&key)
+        .bind(size as i64)
+        .bind(Utc::now())
+        .execute(&mut *conn)
+        .await?;
+        Ok(RecordedBlob { key, is_new: true })
+    }
+
+    /// Stores the local file `src` as the new blob `key`. `src` may be
+    /// moved in the process.
+    pub async fn write(&self, key: &str, src: &Path) -> Result<(), AppError> {
+        self.store.storage.put(key, src).await
+    }
+}
+
+impl Drop for BlobReservation<'_> {
+    fn drop(&mut self) {
+        // Forget the hash's lock unless someone else is waiting for it; the
+        // map and this reservation hold one reference each
+        let mut writers = self
+            .store
+            .writers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if writers
+            .get(&self.hash)
+            .is_some_and(|writer| Arc::strong_count(writer) <= 2)
+        {
+            writers.remove(&self.hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    use crate::db;
+    use crate::storage::LocalStorage;
+
+    /// Stores `contents` as a new audio file of the seeded admin, the way
+    /// an upload does, and returns the blob it refers to.
+    async fn upload(
+        blobs: &BlobStore,
+        pool: &SqlitePool,
+        dir: &Path,
+        id: &str,
+        contents: &[u8],
+    ) -> String {
+        let src = dir.join(format!("{}.part", id));
+        std::fs::write(&src, contents).unwrap();
+        let hash = format!("{:x}", Sha256::digest(contents));
+
+        let reservation = blobs.reserve(&hash).await;
+        let mut tx = pool.begin().await.unwrap();
+        let blob = reservation
+            .record(&mut tx, contents.len() as u64, "mp3")
+            .await
+            .unwrap();
+        sqlx::query(
+            "INSERT INTO audio_files (id, filename, user_id, created_at, mime_type, size, user_folder, storage_path, blob_hash) VALUES (?, 'a.mp3', 'admin-user-id', ?, 'audio/mpeg', ?, 'admin-user-id', ?, ?)",
+        )
+        .bind(id)
+        .bind(Utc::now())
+        .bind(contents.len() as i64)
+        .bind(&blob.key)
+        .bind(&hash)
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+        if blob.is_new {
+            reservation.write(&blob.key, &src).await.unwrap();
+        }
+        hash
+    }
+
+    async fn delete(pool: &SqlitePool, id: &str) {
+        sqlx::query("DELETE FROM audio_files WHERE id = ?")
+            .bind(id)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    async fn ref_count(pool: &SqlitePool, hash: &str) -> Option<i64> {
+        sqlx::query_scalar("SELECT ref_count FROM blobs WHERE hash = ?")
+            .bind(hash)
+            .fetch_optional(pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn identical_uploads_share_a_blob_until_both_are_deleted() {
+        let pool = db::memory_pool().await;
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(LocalStorage::new(dir.path()).unwrap());
+        let blobs = BlobStore::new(storage.clone());
+
+        let first = upload(&blobs, &pool, dir.path(), "a1", b"same bytes").await;
+        let second = upload(&blobs, &pool, dir.path(), "a2", b"same bytes").await;
+        assert_eq!(first, second);
+        assert_eq!(ref_count(&pool, &first).await, Some(2));
+        let stored = storage.list("blobs").await.unwrap();
+        assert_eq!(stored.len(), 1);
+
+        delete(&pool, "a1").await;
+        assert_eq!(blobs.collect_garbage(&pool).await.unwrap(), 0);
+        assert_eq!(ref_count(&pool, &first).await, Some(1));
+        assert_eq!(storage.stat(&stored[0].key).await.unwrap().size, 10);
+
+        delete(&pool, "a2").await;
+        assert_eq!(blobs.collect_garbage(&pool).await.unwrap(), 1);
+        assert_eq!(ref_count(&pool, &first).await, None);
+        assert!(storage.list("blobs").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn different_contents_get_their_own_blobs() {
+        let pool = db::memory_pool().await;
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(LocalStorage::new(dir.path()).unwrap());
+        let blobs = BlobStore::new(storage.clone());
+
+        let first = upload(&blobs, &pool, dir.path(), "a1", b"one").await;
+        let second = upload(&blobs, &pool, dir.path(), "a2", b"two").await;
+        assert_ne!(first, second);
+        assert_eq!(ref_count(&pool, &first).await, Some(1));
+        assert_eq!(ref_count(&pool, &second).await, Some(1));
+
+        delete(&pool, "a1").await;
+        assert_eq!(blobs.collect_garbage(&pool).await.unwrap(), 1);
+        assert_eq!(ref_count(&pool, &second).await, Some(1));
+        assert_eq!(storage.list("blobs").await.unwrap().len(), 1);
+    }
+}
@@ -1,121 +1,781 @@
-use rustls::pki_types::PrivateKeyDer;
+use clap::Parser;
 use rustls::ServerConfig;
-use rustls_pemfile::{certs, pkcs8_private_keys};
+use rustls_pemfile::{certs, private_key};
+use serde::Deserialize;
 use sqlx::SqlitePool;
 use std::fs;
 use std::io::BufReader;
-use std::path::Path;
-use std::process::Command;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::blobs::BlobStore;
+use crate::handlers::tus::UploadLocks;
+use crate::storage::StorageBackend;
+use crate::transcode::{TranscodeCache, TranscodeFormat, Transcoder};
+
+/// The secret key the server used to fall back to; refusing it stops anyone
+/// from running with a publicly known JWT signing key.
+const INSECURE_SECRET_KEY: &str = "your_secret_key";
 
 pub struct AppState {
     pub db_pool: SqlitePool,
+    pub config: Config,
+    pub storage: Arc<dyn StorageBackend>,
+    pub blobs: BlobStore,
+    pub upload_locks: UploadLocks,
+    pub transcoder: Transcoder,
+    pub transcode_cache: Arc<TranscodeCache>,
+}
+
+#[cfg(test)]
+impl AppState {
+    /// State with an in-memory database and files kept under `dir`, for
+    /// tests.
+    pub async fn for_tests(dir: &Path) -> AppState {
+        let mut config = Config::default();
+        config.auth.secret_key = "test secret".to_string();
+        config.storage.upload_root = dir.join("uploads");
+        config.transcoding.cache_dir = dir.join("transcode-cache");
+        fs::create_dir_all(crate::storage::staging_dir(&config.storage.upload_root)).unwrap();
+
+        let storage: Arc<dyn StorageBackend> =
+            Arc::new(crate::storage::LocalStorage::new(&config.storage.upload_root).unwrap());
+        let transcode_cache =
+            TranscodeCache::open(&config.transcoding.cache_dir, config.transcoding.cache_size)
+                .unwrap();
+        AppState {
+            db_pool: crate::db::memory_pool().await,
+            transcoder: Transcoder::new(&config.transcoding),
+            transcode_cache: Arc::new(transcode_cache),
+            blobs: BlobStore::new(storage.clone()),
+            storage,
+            upload_locks: UploadLocks::default(),
+            config,
+        }
+    }
+}
+
+/// Server configuration, built from defaults, then `config.toml`, then
+/// environment variables, then command-line flags (later sources win).
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerSettings,
+    pub database: DatabaseSettings,
+    pub storage: StorageSettings,
+    pub transcoding: TranscodingSettings,
+    pub tls: TlsSettings,
+    pub auth: AuthSettings,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    /// Address of the HTTPS listener
+    pub bind: String,
+    /// Address of the plain HTTP listener that redirects to HTTPS; `None` disables it
+    pub http_redirect_bind: Option<String>,
+    /// URL clients reach the server at, e.g. `https://music.example.org`, for
+    /// links in exported playlists; taken from each request if not set
+    pub public_url: Option<String>,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        ServerSettings {
+            bind: "127.0.0.1:8443".to_string(),
+            http_redirect_bind: Some("127.0.0.1:8080".to_string()),
+            public_url: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseSettings {
+    pub url: String,
+    pub max_connections: u32,
+}
+
+impl Default for DatabaseSettings {
+    fn default() -> Self {
+        DatabaseSettings {
+            url: "sqlite:audio.db".to_string(),
+            max_connections: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageSettings {
+    /// Where uploaded files are kept
+    pub backend: StorageBackendKind,
+    /// Directory holding one sub-folder of uploads per user with the local
+    /// backend; with any backend, incoming uploads are staged here
+    pub upload_root: PathBuf,
+    /// Largest single file accepted by the upload endpoint, in bytes
+    pub max_upload_size: u64,
+    /// How long an unfinished resumable upload is kept after its last write
+    pub resumable_upload_ttl_hours: i64,
+    pub s3: S3Settings,
+}
+
+impl Default for StorageSettings {
+    fn default() -> Self {
+        StorageSettings {
+            backend: StorageBackendKind::Local,
+            upload_root: PathBuf::from("./uploads"),
+            max_upload_size: 2 * 1024 * 1024 * 1024,
+            resumable_upload_ttl_hours: 24,
+            s3: S3Settings::default(),
+        }
+    }
+}
+
+impl StorageSettings {
+    pub fn resumable_upload_ttl(&self) -> chrono::Duration {
+        chrono::Duration::hours(self.resumable_upload_ttl_hours)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackendKind {
+    /// Files under `upload_root`
+    Local,
+    /// An S3-compatible bucket
+    S3,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3Settings {
+    pub bucket: String,
+    pub region: String,
+    /// Endpoint of a non-AWS store such as MinIO, e.g. `http://nas:9000`
+    pub endpoint: Option<String>,
+    /// Falls back to `AWS_ACCESS_KEY_ID`
+    pub access_key_id: Option<String>,
+    /// Falls back to `AWS_SECRET_ACCESS_KEY`
+    pub secret_access_key: Option<String>,
+}
+
+impl Default for S3Settings {
+    fn default() -> Self {
+        S3Settings {
+            bucket: String::new(),
+            region: "us-east-1".to_string(),
+            endpoint: None,
+            access_key_id: None,
+            secret_access_key: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TranscodingSettings {
+    /// The ffmpeg executable; a bare name is looked up in `PATH`
+    pub ffmpeg_path: PathBuf,
+    /// Transcodes allowed to run at once; further requests are refused
+    pub max_concurrent: usize,
+    /// Directory transcoded files are cached in
+    pub cache_dir: PathBuf,
+    /// Size the cache may grow to in bytes before the least recently used
+    /// files are removed; 0 disables caching
+    pub cache_size: u64,
+    /// Length of HLS segments
+    pub hls_segment_seconds: u32,
+    /// Bitrates in kbit/s of the HLS variants players choose between
+    pub hls_bitrates: Vec<u32>,
+}
+
+impl Default for TranscodingSettings {
+    fn default() -> Self {
+        TranscodingSettings {
+            ffmpeg_path: PathBuf::from("ffmpeg"),
+            max_concurrent: 2,
+            cache_dir: PathBuf::from("./transcode-cache"),
+            cache_size: 1024 * 1024 * 1024,
+            hls_segment_seconds: 6,
+            hls_bitrates: vec![64, 128, 192],
+        }
+    }
+}
+
+impl TranscodingSettings {
+    pub fn hls_segment_ms(&self) -> u64 {
+        u64::from(self.hls_segment_seconds) * 1000
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSettings {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    /// Extra DNS names or IPs for the generated self-signed certificate
+    pub subject_alt_names: Vec<String>,
+}
+
+impl Default for TlsSettings {
+    fn default() -> Self {
+        TlsSettings {
+            cert_path: PathBuf::from("cert.pem"),
+            key_path: PathBuf::from("key.pem"),
+            subject_alt_names: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSettings {
+    /// Key used to sign access tokens; required
     pub secret_key: String,
+    pub access_token_ttl_minutes: i64,
+    pub refresh_token_ttl_days: i64,
+    /// Lifetime of the stream tokens in exported playlists' links
+    pub stream_token_ttl_hours: i64,
 }
 
-pub async fn init_db(pool: &SqlitePool) -> Result<(), sqlx::Error> {
-    sqlx::query(
-        "CREATE TABLE IF NOT EXISTS users (
-            id TEXT PRIMARY KEY,
-            username TEXT UNIQUE NOT NULL,
-            password TEXT NOT NULL,
-            is_admin BOOLEAN NOT NULL DEFAULT FALSE
-        ); CREATE TABLE IF NOT EXISTS audio_files (
-            id TEXT PRIMARY KEY,
-            filename TEXT NOT NULL,
-            user_id TEXT NOT NULL,
-            created_at DATETIME NOT NULL,
-            mime_type TEXT NOT NULL,
-            user_folder TEXT NOT NULL,
-            FOREIGN KEY (user_id) REFERENCES users(id)
-        ); CREATE TABLE IF NOT EXISTS playlists (
-            id TEXT PRIMARY KEY,
-            name TEXT NOT NULL,
-            user_id TEXT NOT NULL,
-            created_at DATETIME NOT NULL,
-            FOREIGN KEY (user_id) REFERENCES users(id)
-        ); CREATE TABLE IF NOT EXISTS playlist_items (
-            id TEXT PRIMARY KEY,
-            playlist_id TEXT NOT NULL,
-            audio_id TEXT NOT NULL,
-            position INTEGER NOT NULL,
-            FOREIGN KEY (playlist_id) REFERENCES playlists(id),
-            FOREIGN KEY (audio_id) REFERENCES audio_files(id)
-        )",
-    )
-    .execute(pool)
-    .await?;
-    Ok(())
-}
-
-// Commented out as it's not currently used, but may be needed in the future
-// for TLS support
-#[allow(dead_code)]
-pub fn load_rustls_config() -> ServerConfig {
-    let cert_file =
-        &mut BufReader::new(fs::File::open("cert.pem").expect("Cannot open certificate file"));
-    let key_file = &mut BufReader::new(fs::File::open("key.pem").expect("Cannot open key file"));
-
-    let cert_chain = certs(cert_file).collect::<Result<Vec<_>, _>>().unwrap();
-
-    let mut keys = pkcs8_private_keys(key_file)
-        .collect::<Result<Vec<_>, _>>()
-        .unwrap();
+impl Default for AuthSettings {
+    fn default() -> Self {
+        AuthSettings {
+            secret_key: String::new(),
+            access_token_ttl_minutes: 15,
+            refresh_token_ttl_days: 30,
+            stream_token_ttl_hours: 24,
+        }
+    }
+}
 
-    ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(cert_chain, PrivateKeyDer::Pkcs8(keys.remove(0)))
-        .expect("Failed to set up TLS config")
-}
-
-pub fn ensure_ssl_cert_exists() -> std::io::Result<()> {
-    let cert_path = Path::new("cert.pem");
-    let key_path = Path::new("key.pem");
-
-    // Check if certificates already exist
-    if cert_path.exists() && key_path.exists() {
-        println!("SSL certificates already exist");
-        return Ok(());
-    }
-
-    println!("Generating SSL certificates...");
-
-    // Create OpenSSL command to generate self-signed certificate
-    let output = Command::new("openssl")
-        .args([
-            "req",
-            "-x509",
-            "-newkey",
-            "rsa:4096",
-            "-keyout",
-            "key.pem",
-            "-out",
-            "cert.pem",
-            "-days",
-            "365",
-            "-nodes",
-            "-subj",
-            "/CN=localhost",
-        ])
-        .output()?;
-
-    if !output.status.success() {
-        let error = String::from_utf8_lossy(&output.stderr);
+impl AuthSettings {
+    pub fn access_token_ttl(&self) -> chrono::Duration {
+        chrono::Duration::minutes(self.access_token_ttl_minutes)
+    }
+
+    pub fn refresh_token_ttl(&self) -> chrono::Duration {
+        chrono::Duration::days(self.refresh_token_ttl_days)
+    }
+
+    pub fn stream_token_ttl(&self) -> chrono::Duration {
+        chrono::Duration::hours(self.stream_token_ttl_hours)
+    }
+}
+
+/// Command-line flags; each can also be given as an environment variable.
+#[derive(Debug, Parser)]
+#[command(
+    name = "home-audio",
+    version,
+    about = "Self-hosted audio streaming server"
+)]
+pub struct Cli {
+    /// Path to a TOML configuration file (defaults to ./config.toml if present)
+    #[arg(long, env = "HOME_AUDIO_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Address of the HTTPS listener, e.g. 0.0.0.0:8443
+    #[arg(long, env = "HOME_AUDIO_BIND")]
+    pub bind: Option<String>,
+
+    /// Address of the HTTP-to-HTTPS redirect listener
+    #[arg(long, env = "HOME_AUDIO_HTTP_REDIRECT_BIND")]
+    pub http_redirect_bind: Option<String>,
+
+    /// Do not start the HTTP-to-HTTPS redirect listener
+    #[arg(long, conflicts_with = "http_redirect_bind")]
+    pub no_http_redirect: bool,
+
+    /// URL clients reach the server at, for links in exported playlists
+    #[arg(long, env = "HOME_AUDIO_PUBLIC_URL")]
+    pub public_url: Option<String>,
+
+    #[arg(long, env = "DATABASE_URL")]
+    pub database_url: Option<String>,
+
+    #[arg(long, env = "HOME_AUDIO_MAX_CONNECTIONS")]
+    pub max_connections: Option<u32>,
+
+    /// Directory where uploaded audio is stored
+    #[arg(long, env = "HOME_AUDIO_UPLOAD_ROOT")]
+    pub upload_root: Option<PathBuf>,
+
+    /// Largest accepted upload, in bytes
+    #[arg(long, env = "HOME_AUDIO_MAX_UPLOAD_SIZE")]
+    pub max_upload_size: Option<u64>,
+
+    /// Hours an unfinished resumable upload is kept after its last write
+    #[arg(long, env = "HOME_AUDIO_RESUMABLE_UPLOAD_TTL_HOURS")]
+    pub resumable_upload_ttl_hours: Option<i64>,
+
+    /// Where uploaded files are kept
+    #[arg(long, env = "HOME_AUDIO_STORAGE_BACKEND", value_enum)]
+    pub storage_backend: Option<StorageBackendKind>,
+
+    #[arg(long, env = "HOME_AUDIO_S3_BUCKET")]
+    pub s3_bucket: Option<String>,
+
+    #[arg(long, env = "HOME_AUDIO_S3_REGION")]
+    pub s3_region: Option<String>,
+
+    /// Endpoint of an S3-compatible store such as MinIO
+    #[arg(long, env = "HOME_AUDIO_S3_ENDPOINT")]
+    pub s3_endpoint: Option<String>,
+
+    /// The ffmpeg executable used for transcoding
+    #[arg(long, env = "HOME_AUDIO_FFMPEG_PATH")]
+    pub ffmpeg_path: Option<PathBuf>,
+
+    /// Transcodes allowed to run at once
+    #[arg(long, env = "HOME_AUDIO_MAX_CONCURRENT_TRANSCODES")]
+    pub max_concurrent_transcodes: Option<usize>,
+
+    /// Directory transcoded files are cached in
+    #[arg(long, env = "HOME_AUDIO_TRANSCODE_CACHE_DIR")]
+    pub transcode_cache_dir: Option<PathBuf>,
+
+    /// Largest size of the transcode cache in bytes; 0 disables it
+    #[arg(long, env = "HOME_AUDIO_TRANSCODE_CACHE_SIZE")]
+    pub transcode_cache_size: Option<u64>,
+
+    #[arg(long, env = "HOME_AUDIO_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+
+    #[arg(long, env = "HOME_AUDIO_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Extra certificate subject alternative names (comma-separated)
+    #[arg(long, env = "HOME_AUDIO_TLS_SUBJECT_ALT_NAMES", value_delimiter = ',')]
+    pub tls_san: Vec<String>,
+
+    #[arg(long, env = "SECRET_KEY", hide_env_values = true)]
+    pub secret_key: Option<String>,
+
+    #[arg(long, env = "HOME_AUDIO_ACCESS_TOKEN_TTL_MINUTES")]
+    pub access_token_ttl_minutes: Option<i64>,
+
+    #[arg(long, env = "HOME_AUDIO_REFRESH_TOKEN_TTL_DAYS")]
+    pub refresh_token_ttl_days: Option<i64>,
+
+    /// Hours the links in exported playlists keep working
+    #[arg(long, env = "HOME_AUDIO_STREAM_TOKEN_TTL_HOURS")]
+    pub stream_token_ttl_hours: Option<i64>,
+}
+
+impl Config {
+    /// Loads the configuration file named by `cli` (or `./config.toml`),
+    /// applies the flag/environment overrides and validates the result.
+    pub fn load(cli: Cli) -> std::io::Result<Config> {
+        let mut config = match &cli.config {
+            Some(path) => Config::from_file(path)?,
+            None if Path::new("config.toml").exists() => {
+                Config::from_file(Path::new("config.toml"))?
+            }
+            None => Config::default(),
+        };
+
+        if let Some(bind) = cli.bind {
+            config.server.bind = bind;
+        }
+        if let Some(bind) = cli.http_redirect_bind {
+            config.server.http_redirect_bind = Some(bind);
+        }
+        if cli.no_http_redirect {
+            config.server.http_redirect_bind = None;
+        }
+        if let Some(url) = cli.public_url {
+            config.server.public_url = Some(url);
+        }
+        if let Some(url) = cli.database_url {
+            config.database.url = url;
+        }
+        if let Some(max) = cli.max_connections {
+            config.database.max_connections = max;
+        }
+        if let Some(root) = cli.upload_root {
+            config.storage.upload_root = root;
+        }
+        if let Some(size) = cli.max_upload_size {
+            config.storage.max_upload_size = size;
+        }
+        if let Some(hours) = cli.resumable_upload_ttl_hours {
+            config.storage.resumable_upload_ttl_hours = hours;
+        }
+        if let Some(backend) = cli.storage_backend {
+            config.storage.backend = backend;
+        }
+        if let Some(bucket) = cli.s3_bucket {
+            config.storage.s3.bucket = bucket;
+        }
+        if let Some(region) = cli.s3_region {
+            config.storage.s3.region = region;
+        }
+        if let Some(endpoint) = cli.s3_endpoint {
+            config.storage.s3.endpoint = Some(endpoint);
+        }
+        if let Some(path) = cli.ffmpeg_path {
+            config.transcoding.ffmpeg_path = path;
+        }
+        if let Some(max) = cli.max_concurrent_transcodes {
+            config.transcoding.max_concurrent = max;
+        }
+        if let Some(dir) = cli.transcode_cache_dir {
+            config.transcoding.cache_dir = dir;
+        }
+        if let Some(size) = cli.transcode_cache_size {
+            config.transcoding.cache_size = size;
+        }
+        if let Some(path) = cli.tls_cert {
+            config.tls.cert_path = path;
+        }
+        if let Some(path) = cli.tls_key {
+            config.tls.key_path = path;
+        }
+        config.tls.subject_alt_names.extend(
+            cli.tls_san
+                .into_iter()
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty()),
+        );
+        if let Some(key) = cli.secret_key {
+            config.auth.secret_key = key;
+        }
+        if let Some(minutes) = cli.access_token_ttl_minutes {
+            config.auth.access_token_ttl_minutes = minutes;
+        }
+        if let Some(days) = cli.refresh_token_ttl_days {
+            config.auth.refresh_token_ttl_days = days;
+        }
+        if let Some(hours) = cli.stream_token_ttl_hours {
+            config.auth.stream_token_ttl_hours = hours;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> std::io::Result<Config> {
+        let contents = fs::read_to_string(path).map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("Cannot read config file {}: {}", path.display(), e),
+            )
+        })?;
+        toml::from_str(&contents).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid config file {}: {}", path.display(), e),
+            )
+        })
+    }
+
+    /// Checks every setting and reports all problems at once.
+    pub fn validate(&self) -> std::io::Result<()> {
+        let mut problems = Vec::new();
+
+        if self.server.bind.parse::<SocketAddr>().is_err() {
+            problems.push(format!("invalid bind address '{}'", self.server.bind));
+        }
+        if let Some(bind) = &self.server.http_redirect_bind {
+            if bind.parse::<SocketAddr>().is_err() {
+                problems.push(format!("invalid HTTP redirect address '{}'", bind));
+            }
+        }
+        if let Some(url) = &self.server.public_url {
+            if !url.starts_with("https://") && !url.starts_with("http://") {
+                problems.push(format!(
+                    "public URL '{}' must start with http:// or https://",
+                    url
+                ));
+            }
+        }
+        if !self.database.url.starts_with("sqlite:") {
+            problems.push("database url must start with 'sqlite:'".to_string());
+        }
+        if self.database.max_connections == 0 {
+            problems.push("database max_connections must be at least 1".to_string());
+        }
+        if self.storage.max_upload_size == 0 {
+            problems.push("max_upload_size must be greater than 0".to_string());
+        }
+        if self.storage.resumable_upload_ttl_hours <= 0 {
+            problems.push("resumable upload lifetime must be positive".to_string());
+        }
+        if self.storage.backend == StorageBackendKind::S3 && self.storage.s3.bucket.is_empty() {
+            problems.push("S3 storage requires a bucket".to_string());
+        }
+        if self.transcoding.max_concurrent == 0 {
+            problems.push("max_concurrent transcodes must be at least 1".to_string());
+        }
+        if !(1..=60).contains(&self.transcoding.hls_segment_seconds) {
+            problems.push("HLS segments must be 1 to 60 seconds long".to_string());
+        }
+        let aac_bitrates = TranscodeFormat::Aac.bitrates();
+        if self.transcoding.hls_bitrates.is_empty() {
+            problems.push("at least one HLS bitrate is needed".to_string());
+        } else if let Some(bitrate) = self
+            .transcoding
+            .hls_bitrates
+            .iter()
+            .find(|bitrate| !aac_bitrates.contains(bitrate))
+        {
+            problems.push(format!(
+                "HLS bitrate {} is outside {} to {} kbit/s",
+                bitrate,
+                aac_bitrates.start(),
+                aac_bitrates.end()
+            ));
+        }
+        if self.auth.secret_key.is_empty() {
+            problems.push("secret key is not set (use SECRET_KEY or --secret-key)".to_string());
+        } else if self.auth.secret_key == INSECURE_SECRET_KEY {
+            problems.push("secret key must not be the insecure default".to_string());
+        } else if self.auth.secret_key.len() < 16 {
+            problems.push("secret key must be at least 16 characters".to_string());
+        }
+        if self.auth.access_token_ttl_minutes <= 0 {
+            problems.push("access token lifetime must be positive".to_string());
+        }
+        if self.auth.refresh_token_ttl_days <= 0 {
+            problems.push("refresh token lifetime must be positive".to_string());
+        }
+        if self.auth.stream_token_ttl_hours <= 0 {
+            problems.push("stream token lifetime must be positive".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid configuration: {}", problems.join("; ")),
+            ))
+        }
+    }
+}
+
+/// Builds the rustls server configuration from PEM-encoded certificate and
+/// key files. The key may be PKCS#8, PKCS#1 (RSA) or SEC1 (EC).
+pub fn load_rustls_config(cert_path: &Path, key_path: &Path) -> std::io::Result<ServerConfig> {
+    let cert_file = fs::File::open(cert_path).map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!(
+                "Cannot open certificate file {}: {}",
+                cert_path.display(),
+                e
+            ),
+        )
+    })?;
+    let key_file = fs::File::open(key_path).map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!("Cannot open key file {}: {}", key_path.display(), e),
+        )
+    })?;
+
+    let cert_chain = certs(&mut BufReader::new(cert_file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid certificate in {}: {}", cert_path.display(), e),
+            )
+        })?;
+    if cert_chain.is_empty() {
         return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("Failed to generate SSL certificates: {}", error),
+            std::io::ErrorKind::InvalidData,
+            format!("No certificates found in {}", cert_path.display()),
         ));
     }
 
-    println!("SSL certificates generated successfully");
+    let key = private_key(&mut BufReader::new(key_file))
+        .map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid private key in {}: {}", key_path.display(), e),
+            )
+        })?
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "No PKCS#8, PKCS#1 or SEC1 private key found in {}",
+                    key_path.display()
+                ),
+            )
+        })?;
+
+    // Both the aws-lc-rs and ring providers are compiled in (the S3 client
+    // brings ring), so the provider has to be chosen explicitly
+    ServerConfig::builder_with_provider(Arc::new(rustls::crypto::aws_lc_rs::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Failed to set up TLS config: {}", e),
+            )
+        })?
+        .with_no_client_auth()
+        .with_single_cert(cert_chain, key)
+        .map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Failed to set up TLS config: {}", e),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A configuration that passes validation.
+    fn valid() -> Config {
+        let mut config = Config::default();
+        config.auth.secret_key = "a long enough secret key".to_string();
+        config
+    }
+
+    fn problems(config: &Config) -> String {
+        config.validate().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn defaults() {
+        let config = Config::default();
+        assert_eq!(config.server.bind, "127.0.0.1:8443");
+        assert_eq!(
+            config.server.http_redirect_bind.as_deref(),
+            Some("127.0.0.1:8080")
+        );
+        assert_eq!(config.server.public_url, None);
+        assert_eq!(config.database.url, "sqlite:audio.db");
+        assert_eq!(config.database.max_connections, 5);
+        assert_eq!(config.storage.backend, StorageBackendKind::Local);
+        assert_eq!(config.storage.upload_root, PathBuf::from("./uploads"));
+        assert_eq!(config.storage.max_upload_size, 2 * 1024 * 1024 * 1024);
+        assert_eq!(config.storage.resumable_upload_ttl_hours, 24);
+        assert_eq!(config.storage.s3.region, "us-east-1");
+        assert_eq!(config.transcoding.ffmpeg_path, PathBuf::from("ffmpeg"));
+        assert_eq!(config.transcoding.max_concurrent, 2);
+        assert_eq!(config.transcoding.cache_size, 1024 * 1024 * 1024);
+        assert_eq!(config.transcoding.hls_segment_seconds, 6);
+        assert_eq!(config.transcoding.hls_bitrates, vec![64, 128, 192]);
+        assert_eq!(config.tls.cert_path, PathBuf::from("cert.pem"));
+        assert_eq!(config.tls.key_path, PathBuf::from("key.pem"));
+        assert!(config.tls.subject_alt_names.is_empty());
+        assert_eq!(config.auth.secret_key, "");
+        assert_eq!(config.auth.access_token_ttl_minutes, 15);
+        assert_eq!(config.auth.refresh_token_ttl_days, 30);
+        assert_eq!(config.auth.stream_token_ttl_hours, 24);
 
-    // Set appropriate permissions for key file
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::PermissionsExt;
-        let mut perms = fs::metadata(key_path)?.permissions();
-        perms.set_mode(0o600); // Read/write for owner only
-        fs::set_permissions(key_path, perms)?;
+        // Everything but the secret key has a usable default
+        assert!(valid().validate().is_ok());
     }
 
-    Ok(())
+    #[test]
+    fn later_sources_override_earlier_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(
+            &path,
+            r#"
+            [database]
+            max_connections = 3
+
+            [storage]
+            max_upload_size = 1000
+
+            [storage.s3]
+            bucket = "from-file"
+            region = "from-file"
+
+            [tls]
+            subject_alt_names = ["file.example"]
+
+            [auth]
+            secret_key = "a long enough secret key"
+            "#,
+        )
+        .unwrap();
+
+        // No other test reads these variables
+        std::env::set_var("HOME_AUDIO_MAX_CONNECTIONS", "7");
+        std::env::set_var("HOME_AUDIO_S3_REGION", "from-env");
+        std::env::set_var("HOME_AUDIO_TLS_SUBJECT_ALT_NAMES", "env.example");
+        let cli = Cli::try_parse_from([
+            "home-audio",
+            "--config",
+            path.to_str().unwrap(),
+            "--max-connections",
+            "9",
+        ]);
+        std::env::remove_var("HOME_AUDIO_MAX_CONNECTIONS");
+        std::env::remove_var("HOME_AUDIO_S3_REGION");
+        std::env::remove_var("HOME_AUDIO_TLS_SUBJECT_ALT_NAMES");
+        let config = Config::load(cli.unwrap()).unwrap();
+
+        // Only in the file
+        assert_eq!(config.storage.max_upload_size, 1000);
+        assert_eq!(config.storage.s3.bucket, "from-file");
+        // The file, then the environment
+        assert_eq!(config.storage.s3.region, "from-env");
+        assert_eq!(
+            config.tls.subject_alt_names,
+            vec!["file.example", "env.example"]
+        );
+        // The file, the environment, then the flag
+        assert_eq!(config.database.max_connections, 9);
+        // In none of them
+        assert_eq!(config.server.bind, "127.0.0.1:8443");
+    }
+
+    #[test]
+    fn invalid_addresses_are_refused() {
+        let mut config = valid();
+        config.server.bind = "localhost".to_string();
+        assert!(problems(&config).contains("invalid bind address 'localhost'"));
+
+        let mut config = valid();
+        config.server.http_redirect_bind = Some("127.0.0.1:http".to_string());
+        assert!(problems(&config).contains("invalid HTTP redirect address '127.0.0.1:http'"));
+
+        let mut config = valid();
+        config.server.http_redirect_bind = None;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn public_url_needs_a_scheme() {
+        let mut config = valid();
+        config.server.public_url = Some("music.example.org".to_string());
+        assert!(problems(&config).contains("must start with http:// or https://"));
+
+        for url in ["http://nas.local:8443", "https://music.example.org"] {
+            config.server.public_url = Some(url.to_string());
+            assert!(config.validate().is_ok(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn only_sqlite_databases_are_supported() {
+        let mut config = valid();
+        config.database.url = "postgres://localhost/audio".to_string();
+        assert!(problems(&config).contains("database url must start with 'sqlite:'"));
+    }
+
+    #[test]
+    fn all_problems_are_reported_together() {
+        let mut config = valid();
+        config.server.bind = "nowhere".to_string();
+        config.database.url = "mysql://db".to_string();
+        let problems = problems(&config);
+        assert!(problems.contains("invalid bind address"));
+        assert!(problems.contains("database url"));
+    }
 }
@@ -0,0 +1,319 @@
+use chrono::Utc;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use sqlx::{Connection, SqliteConnection, SqlitePool};
+use std::str::FromStr;
+
+use crate::config::DatabaseSettings;
+use crate::error::AppError;
+
+/// A schema change embedded in the binary. Versions must be strictly
+/// increasing; applied migrations are recorded in `schema_version`.
+struct Migration {
+    version: i64,
+    description: &'static str,
+    sql: &'static str,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial schema",
+        sql: include_str!("../migrations/0001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        description: "cascade deletes and users.created_at",
+        sql: include_str!("../migrations/0002_cascade_deletes.sql"),
+    },
+    Migration {
+        version: 3,
+        description: "audio metadata columns",
+        sql: include_str!("../migrations/0003_audio_metadata.sql"),
+    },
+    Migration {
+        version: 4,
+        description: "server-generated storage paths",
+        sql: include_str!("../migrations/0004_storage_path.sql"),
+    },
+    Migration {
+        version: 5,
+        description: "content-addressed blobs",
+        sql: include_str!("../migrations/0005_blobs.sql"),
+    },
+    Migration {
+        version: 6,
+        description: "storage quotas and file sizes",
+        sql: include_str!("../migrations/0006_quotas.sql"),
+    },
+    Migration {
+        version: 7,
+        description: "resumable uploads",
+        sql: include_str!("../migrations/0007_resumable_uploads.sql"),
+    },
+    Migration {
+        version: 8,
+        description: "transcoding preferences",
+        sql: include_str!("../migrations/0008_transcoding_preferences.sql"),
+    },
+    Migration {
+        version: 9,
+        description: "dense playlist positions and versions",
+        sql: include_str!("../migrations/0009_playlist_positions.sql"),
+    },
+    Migration {
+        version: 10,
+        description: "playlist details and update times",
+        sql: include_str!("../migrations/0010_playlist_details.sql"),
+    },
+    Migration {
+        version: 11,
+        description: "playlist sharing and groups",
+        sql: include_str!("../migrations/0011_playlist_sharing.sql"),
+    },
+];
+
+/// Opens the connection pool with foreign key enforcement on every connection.
+pub async fn connect(settings: &DatabaseSettings) -> Result<SqlitePool, sqlx::Error> {
+    let options = SqliteConnectOptions::from_str(&settings.url)?
+        .create_if_missing(true)
+        .foreign_keys(true);
+
+    SqlitePoolOptions::new()
+        .max_connections(settings.max_connections)
+        .connect_with(options)
+        .await
+}
+
+/// A private in-memory database at the latest schema version, for tests.
+#[cfg(test)]
+pub async fn memory_pool() -> SqlitePool {
+    let pool = unmigrated_memory_pool().await;
+    init_db(&pool).await.unwrap();
+    pool
+}
+
+/// An empty in-memory database. It lives as long as its single connection,
+/// so that one is never closed.
+#[cfg(test)]
+async fn unmigrated_memory_pool() -> SqlitePool {
+    let options = SqliteConnectOptions::from_str("sqlite::memory:")
+        .unwrap()
+        .foreign_keys(true);
+    SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect_with(options)
+        .await
+        .unwrap()
+}
+
+/// Brings the database up to the latest schema version.
+pub async fn init_db(pool: &SqlitePool) -> Result<(), AppError> {
+    let mut conn = pool.acquire().await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at TIMESTAMP NOT NULL
+        )",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    let current: i64 = sqlx::query_scalar("SELECT COALESCE(MAX(version), 0) FROM schema_version")
+        .fetch_one(&mut *conn)
+        .await?;
+
+    // Table rebuilds need enforcement off; the pragma is a no-op inside a
+    // transaction, so it is toggled around the whole run.
+    sqlx::query("PRAGMA foreign_keys = OFF")
+        .execute(&mut *conn)
+        .await?;
+    let result = apply_pending(&mut conn, current).await;
+    sqlx::query("PRAGMA foreign_keys = ON")
+        .execute(&mut *conn)
+        .await?;
+
+    result
+}
+
+/// Applies every pending migration in a single transaction so a failure
+/// leaves the database exactly as it was. Foreign keys are checked once at
+/// the end because older databases may hold orphaned rows that a later
+/// migration cleans up.
+async fn apply_pending(conn: &mut SqliteConnection, current: i64) -> Result<(), AppError> {
+    let pending: Vec<&Migration> = MIGRATIONS.iter().filter(|m| m.version > current).collect();
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    let mut tx = conn.begin().await?;
+
+    for migration in pending {
+        println!(
+            "Applying database migration {}: {}",
+            migration.version, migration.description
+        );
+
+        sqlx::raw_sql(migration.sql).execute(&mut *tx).await?;
+
+        sqlx::query(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES (?, ?, ?)",
+        )
+        .bind(migration.version)
+        .bind(migration.description)
+        .bind(Utc::now())
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    let violations = sqlx::query("PRAGMA foreign_key_check")
+        .fetch_all(&mut *tx)
+        .await?;
+    if !violations.is_empty() {
+        return Err(AppError::Database(format!(
+            "Migrations left {} foreign key violations",
+            violations.len()
+        )));
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every table, index and trigger definition, to compare schemas.
+    async fn schema(pool: &SqlitePool) -> Vec<(String, Option<String>)> {
+        sqlx::query_as("SELECT name, sql FROM sqlite_master ORDER BY type, name")
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    async fn versions(pool: &SqlitePool) -> Vec<i64> {
+        sqlx::query_scalar("SELECT version FROM schema_version ORDER BY version")
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn baseline_database_is_upgraded() {
+        let pool = unmigrated_memory_pool().await;
+        sqlx::raw_sql(MIGRATIONS[0].sql)
+            .execute(&pool)
+            .await
+            .unwrap();
+        // Data as an unversioned release could leave it: an audio file
+        // whose owner was deleted and playlist positions with repeats and gaps
+        sqlx::raw_sql(
+            "PRAGMA foreign_keys = OFF;
+             INSERT INTO users (id, username, password) VALUES ('bob', 'bob', 'secret');
+             INSERT INTO audio_files (id, filename, user_id, created_at, mime_type, user_folder)
+             VALUES ('a1', 'one.mp3', 'bob', '2024-01-01T00:00:00Z', 'audio/mpeg', 'bob'),
+                    ('a2', 'two.mp3', 'bob', '2024-01-01T00:00:00Z', 'audio/mpeg', 'bob'),
+                    ('a3', 'gone.mp3', 'ghost', '2024-01-01T00:00:00Z', 'audio/mpeg', 'ghost');
+             INSERT INTO playlists (id, name, user_id, created_at)
+             VALUES ('p1', 'Mix', 'bob', '2024-01-01T00:00:00Z');
+             INSERT INTO playlist_items (id, playlist_id, audio_id, position)
+             VALUES ('i1', 'p1', 'a1', 5), ('i2', 'p1', 'a2', 5), ('i3', 'p1', 'a1', 9);
+             PRAGMA foreign_keys = ON;",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        init_db(&pool).await.unwrap();
+
+        let expected: Vec<i64> = MIGRATIONS.iter().map(|m| m.version).collect();
+        assert_eq!(versions(&pool).await, expected);
+        assert_eq!(expected.last(), Some(&11));
+
+        let audio: Vec<String> = sqlx::query_scalar("SELECT id FROM audio_files ORDER BY id")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(audio, ["a1", "a2"]);
+
+        let items: Vec<(String, i64, String)> =
+            sqlx::query_as("SELECT id, position, added_by FROM playlist_items ORDER BY position")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        let bob = "bob".to_string();
+        assert_eq!(
+            items,
+            [
+                ("i1".to_string(), 1, bob.clone()),
+                ("i2".to_string(), 2, bob.clone()),
+                ("i3".to_string(), 3, bob),
+            ]
+        );
+
+        let (version, visibility): (i64, String) =
+            sqlx::query_as("SELECT version, visibility FROM playlists WHERE id = 'p1'")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!((version, visibility.as_str()), (1, "private"));
+    }
+
+    #[tokio::test]
+    async fn new_database_gets_the_default_admin() {
+        let pool = memory_pool().await;
+        let admins: Vec<String> = sqlx::query_scalar("SELECT id FROM users WHERE is_admin = 1")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(admins, ["admin-user-id"]);
+    }
+
+    #[tokio::test]
+    async fn adopted_database_keeps_its_users() {
+        let pool = unmigrated_memory_pool().await;
+        sqlx::raw_sql(MIGRATIONS[0].sql)
+            .execute(&pool)
+            .await
+            .unwrap();
+        // An unversioned release's database whose admin was deleted
+        sqlx::raw_sql(
+            "DELETE FROM users;
+             INSERT INTO users (id, username, password) VALUES ('bob', 'bob', 'secret');",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        init_db(&pool).await.unwrap();
+
+        let users: Vec<String> = sqlx::query_scalar("SELECT id FROM users")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(users, ["bob"]);
+    }
+
+    #[tokio::test]
+    async fn init_db_twice_changes_nothing() {
+        let pool = memory_pool().await;
+        let before = schema(&pool).await;
+        let applied: Vec<String> = sqlx::query_scalar("SELECT applied_at FROM schema_version")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+
+        init_db(&pool).await.unwrap();
+
+        assert_eq!(schema(&pool).await, before);
+        let reapplied: Vec<String> = sqlx::query_scalar("SELECT applied_at FROM schema_version")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(reapplied, applied);
+    }
+}
@@ -1,21 +1,204 @@
 use actix_web::{http::StatusCode, HttpResponse};
+use serde::Serialize;
 use std::fmt;
+use uuid::Uuid;
 
+/// Errors returned by handlers, each mapped to an HTTP status and a stable
+/// machine-readable code.
 #[derive(Debug)]
-pub struct AppError(pub String);
+pub enum AppError {
+    /// Missing, invalid or expired credentials (401)
+    Unauthorized(String),
+    /// Authenticated but not allowed to perform the action (403)
+    Forbidden(String),
+    /// The requested resource does not exist (404)
+    NotFound(String),
+    /// The request conflicts with existing state, e.g. a duplicate username (409)
+    Conflict(String),
+    /// A required request header is missing or has an unsupported value (412)
+    PreconditionFailed(String),
+    /// The request body exceeds a configured size limit (413)
+    TooLarge(String),
+    /// The upload would take the user over their storage quota (413)
+    QuotaExceeded(String),
+    /// The request body has the wrong content type (415)
+    UnsupportedMediaType(String),
+    /// The request was well-formed but its content is not acceptable (422)
+    Validation(String),
+    /// The server is too busy to handle the request right now (503)
+    Unavailable(String),
+    /// Reading or writing files failed (500)
+    Storage(String),
+    /// A database query failed (500)
+    Database(String),
+    /// Any other server-side failure (500)
+    Internal(String),
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody<'a> {
+    code: &'a str,
+    message: &'a str,
+    request_id: String,
+}
+
+impl AppError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::NotFound(_) => "not_found",
+            AppError::Conflict(_) => "conflict",
+            AppError::PreconditionFailed(_) => "precondition_failed",
+            AppError::TooLarge(_) => "payload_too_large",
+            AppError::QuotaExceeded(_) => "quota_exceeded",
+            AppError::UnsupportedMediaType(_) => "unsupported_media_type",
+            AppError::Validation(_) => "validation_error",
+            AppError::Unavailable(_) => "service_unavailable",
+            AppError::Storage(_) => "storage_error",
+            AppError::Database(_) => "database_error",
+            AppError::Internal(_) => "internal_error",
+        }
+    }
+
+    /// Whether the error is a failure inside the server, whose details are
+    /// logged rather than shown to the client.
+    pub fn is_internal(&self) -> bool {
+        matches!(
+            self,
+            AppError::Storage(_) | AppError::Database(_) | AppError::Internal(_)
+        )
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            AppError::Unauthorized(msg)
+            | AppError::Forbidden(msg)
+            | AppError::NotFound(msg)
+            | AppError::Conflict(msg)
+            | AppError::PreconditionFailed(msg)
+            | AppError::TooLarge(msg)
+            | AppError::QuotaExceeded(msg)
+            | AppError::UnsupportedMediaType(msg)
+            | AppError::Validation(msg)
+            | AppError::Unavailable(msg)
+            | AppError::Storage(msg)
+            | AppError::Database(msg)
+            | AppError::Internal(msg) => msg,
+        }
+    }
+}
 
 impl fmt::Display for AppError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.0)
+        write!(f, "{}", self.message())
     }
 }
 
+impl std::error::Error for AppError {}
+
 impl actix_web::error::ResponseError for AppError {
     fn status_code(&self) -> StatusCode {
-        StatusCode::BAD_REQUEST
+        match self {
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            AppError::TooLarge(_) | AppError::QuotaExceeded(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Storage(_) | AppError::Database(_) | AppError::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
     }
 
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code()).body(self.0.clone())
+        let status = self.status_code();
+        let request_id = Uuid::new_v4().to_string();
+
+        // Server-side details stay in the log; clients only get the request id
+        let message = if self.is_internal() {
+            eprintln!("[{}] {}: {}", request_id, self.code(), self.message());
+            "Internal server error"
+        } else {
+            self.message()
+        };
+
+        HttpResponse::build(status)
+            .insert_header(("X-Request-Id", request_id.clone()))
+            .json(ErrorBody {
+                code: self.code(),
+                message,
+                request_id,
+            })
+    }
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(err: sqlx::Error) -> Self {
+        match &err {
+            sqlx::Error::RowNotFound => AppError::NotFound("Record not found".to_string()),
+            sqlx::Error::Database(db_err) if db_err.is_unique_violation() => {
+                AppError::Conflict("Record already exists".to_string())
+            }
+            _ => AppError::Database(err.to_string()),
+        }
+    }
+}
+
+impl From<std::io::Error> for AppError {
+    fn from(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::NotFound => AppError::NotFound("File not found".to_string()),
+            _ => AppError::Storage(err.to_string()),
+        }
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for AppError {
+    fn from(err: jsonwebtoken::errors::Error) -> Self {
+        use jsonwebtoken::errors::ErrorKind;
+
+        match err.kind() {
+            ErrorKind::ExpiredSignature => AppError::Unauthorized("Token expired".to_string()),
+            ErrorKind::InvalidToken
+            | ErrorKind::InvalidSignature
+            | ErrorKind::ImmatureSignature
+            | ErrorKind::InvalidAudience
+            | ErrorKind::InvalidIssuer
+            | ErrorKind::InvalidSubject
+            | ErrorKind::InvalidAlgorithm
+            | ErrorKind::MissingRequiredClaim(_)
+            | ErrorKind::Base64(_)
+            | ErrorKind::Json(_)
+            | ErrorKind::Utf8(_) => AppError::Unauthorized("Invalid token".to_string()),
+            _ => AppError::Internal(err.to_string()),
+        }
+    }
+}
+
+impl From<actix_multipart::MultipartError> for AppError {
+    fn from(err: actix_multipart::MultipartError) -> Self {
+        AppError::Validation(err.to_string())
+    }
+}
+
+impl From<actix_web::error::BlockingError> for AppError {
+    fn from(err: actix_web::error::BlockingError) -> Self {
+        AppError::Internal(err.to_string())
+    }
+}
+
+impl From<object_store::Error> for AppError {
+    fn from(err: object_store::Error) -> Self {
+        match err {
+            object_store::Error::NotFound { .. } => {
+                AppError::NotFound("File not found".to_string())
+            }
+            other => AppError::Storage(other.to_string()),
+        }
     }
 }
@@ -1,221 +1,486 @@
-use actix_files::NamedFile;
-use actix_multipart::Multipart;
-use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_multipart::{Field, Multipart};
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
 use chrono::Utc;
 use futures::StreamExt;
 use mime::Mime;
-use std::fs;
-use std::io::Write;
+use sha2::{Digest, Sha256};
+use sqlx::SqliteConnection;
+use std::path::PathBuf;
+use tokio::io::AsyncWriteExt;
 use uuid::Uuid;
 
-use crate::auth::{check_admin, validate_token};
+use crate::auth::{AdminUser, AuthenticatedUser, StreamUser};
 use crate::config::AppState;
 use crate::error::AppError;
-use crate::models::AudioFile;
+use crate::handlers::playlist::append_to_named_playlist;
+use crate::handlers::user::{transcoding_preferences, user_usage};
+use crate::metadata::inspect_audio;
+use crate::models::{
+    AudioFile, StorageStats, StreamQuery, UploadOptions, UploadResponse, UploadResult,
+};
+use crate::sharing;
+use crate::storage::{self, audio_key, sanitize_filename, StorageBackend};
+use crate::transcode::{self, TranscodeCache};
+
+/// A file being received by an upload, in the staging directory. It is
+/// deleted when dropped, so any early return or a dropped client connection
+/// leaves nothing behind; a completed upload has been handed to the storage
+/// backend by then.
+pub(crate) struct PartialFile {
+    pub(crate) path: PathBuf,
+}
 
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        // Removed in the background so the worker thread doesn't wait on the
+        // disk; the file is usually gone already after a successful upload
+        let path = std::mem::take(&mut self.path);
+        tokio::task::spawn_blocking(move || {
+            let _ = std::fs::remove_file(path);
+        });
+    }
+}
+
+/// Uploads one or more audio files, e.g. a whole album folder. Every part
+/// of the multipart body is stored on its own, streamed to disk without
+/// being held in memory, and the response reports the outcome of each in
+/// the order they were sent. With `?playlist=<name>`, the stored files are
+/// also appended to the caller's playlist of that name, which is created if
+/// it doesn't exist.
 pub async fn upload_audio(
     mut payload: Multipart,
+    options: web::Query<UploadOptions>,
     state: web::Data<AppState>,
-    req: HttpRequest,
-) -> Result<HttpResponse, Error> {
-    let token = req
-        .headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .and_then(|s| s.strip_prefix("Bearer "))
-        .ok_or_else(|| AppError("Authentication required".to_string()))?;
-
-    let user_id = validate_token(token, &state.secret_key)
-        .await
-        .ok_or_else(|| AppError("Invalid token".to_string()))?;
-
-    // Ensure user folder exists
-    let user_folder = format!("./uploads/{}", user_id);
-    fs::create_dir_all(&user_folder)?;
-
-    // Process the first field that contains an audio file
-    if let Some(Ok(mut field)) = payload.next().await {
-        // Get content type and validate it first
-        let mime_type = field
-            .content_type()
-            .ok_or_else(|| AppError("No content type specified".to_string()))?;
-
-        let valid_types = vec![
-            "audio/mpeg".parse::<Mime>().unwrap(), // MP3
-            "audio/wav".parse::<Mime>().unwrap(),  // WAV
-            "audio/flac".parse::<Mime>().unwrap(), // FLAC
-            "audio/aac".parse::<Mime>().unwrap(),  // AAC
-            "audio/ogg".parse::<Mime>().unwrap(),  // OGG
-        ];
-
-        if !valid_types.contains(mime_type) {
-            return Err(
-                AppError("Invalid audio format (only MP3/WAV/FLAC/AAC/OGG)".to_string()).into(),
-            );
-        }
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let playlist_name = options.playlist.as_deref().map(str::trim);
+    if playlist_name == Some("") {
+        return Err(AppError::Validation(
+            "Playlist name must not be empty".to_string(),
+        ));
+    }
 
-        // Store the mime type as string before processing the field
-        let mime_type_str = mime_type.to_string();
+    let mut results = Vec::new();
+    while let Some(field) = payload.next().await {
+        // A malformed body cannot be read past this point
+        let mut field = field?;
 
-        let filename = field
+        // Folder uploads send the path relative to the chosen folder; it is
+        // echoed back so clients can match results to files
+        let client_name = field
             .content_disposition()
-            .expect("Content disposition missing")
-            .get_filename()
-            .map(|s| s.to_string())
-            .unwrap_or_else(|| format!("unknown_{}.mp3", Uuid::new_v4()));
-        let audio_id = Uuid::new_v4().to_string();
-        let filepath = format!("{}/{}_{}", user_folder, audio_id, filename);
-
-        let mut f = fs::File::create(&filepath)?;
-        while let Some(chunk) = field.next().await {
-            let data = chunk?;
-            f.write_all(&data)?;
+            .and_then(|cd| cd.get_filename())
+            .unwrap_or("")
+            .to_string();
+        let path = storage::sanitize_relative_path(&client_name);
+
+        match receive_file(&mut field, &client_name, &state, &user).await {
+            Ok(file) => results.push(UploadResult::Stored {
+                path,
+                file: Box::new(file),
+            }),
+            Err(e) => results.push(upload_failure(path, e)),
         }
+    }
 
-        let audio_file = AudioFile {
-            id: audio_id.clone(),
-            filename,
-            user_id: user_id.clone(),
-            created_at: Utc::now(),
-            mime_type: mime_type_str,
-            user_folder,
-        };
-
-        sqlx::query(
-            "INSERT INTO audio_files (id, filename, user_id, created_at, mime_type, user_folder) VALUES (?, ?, ?, ?, ?, ?)"
-        )
-        .bind(&audio_file.id)
-        .bind(&audio_file.filename)
-        .bind(&audio_file.user_id)
-        .bind(audio_file.created_at)
-        .bind(&audio_file.mime_type)
-        .bind(&audio_file.user_folder)
-        .execute(&state.db_pool)
-        .await
-        .map_err(|e| AppError(e.to_string()))?;
-
-        return Ok(HttpResponse::Ok().json(audio_file));
+    if results.is_empty() {
+        return Err(AppError::Validation("No file uploaded".to_string()));
     }
 
-    Err(AppError("No file uploaded".to_string()).into())
+    let playlist = match playlist_name {
+        Some(name) => {
+            let audio_ids: Vec<&str> = results
+                .iter()
+                .filter_map(|result| match result {
+                    UploadResult::Stored { file, .. } => Some(file.id.as_str()),
+                    UploadResult::Failed { .. } => None,
+                })
+                .collect();
+            if audio_ids.is_empty() {
+                None
+            } else {
+                let mut tx = state.db_pool.begin().await?;
+                let playlist =
+                    append_to_named_playlist(&user.user_id, name, &audio_ids, &mut tx).await?;
+                tx.commit().await?;
+                Some(playlist)
+            }
+        }
+        None => None,
+    };
+
+    Ok(HttpResponse::Ok().json(UploadResponse { results, playlist }))
 }
 
-pub async fn stream_audio(
-    path: web::Path<String>,
-    state: web::Data<AppState>,
-    req: HttpRequest,
-) -> Result<NamedFile, Error> {
-    let token = req
-        .headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .and_then(|s| s.strip_prefix("Bearer "))
-        .ok_or_else(|| AppError("Authentication required".to_string()))?;
+/// Receives one file of an upload into the staging directory, checks it and
+/// stores it.
+async fn receive_file(
+    field: &mut Field,
+    client_name: &str,
+    state: &AppState,
+    user: &AuthenticatedUser,
+) -> Result<AudioFile, AppError> {
+    let partial = PartialFile {
+        path: storage::staging_dir(&state.config.storage.upload_root)
+            .join(format!("{}.part", Uuid::new_v4())),
+    };
+
+    // Both limits are enforced while the file is received, so an oversized
+    // upload is cut off instead of filling the disk first
+    let max_size = state.config.storage.max_upload_size;
+    let mut conn = state.db_pool.acquire().await?;
+    let quota_left = user_usage(&user.user_id, &mut conn)
+        .await?
+        .remaining_bytes
+        .map(|bytes| bytes as u64);
+    drop(conn);
+
+    // After a limit is hit the rest of the file is read and discarded, so
+    // the next file in the request can still be received
+    let mut written: u64 = 0;
+    let mut rejected = None;
+    let mut hasher = Sha256::new();
+    let mut f = tokio::fs::File::create(&partial.path).await?;
+    while let Some(chunk) = field.next().await {
+        let data = chunk?;
+        if rejected.is_some() {
+            continue;
+        }
+        written += data.len() as u64;
+        if written > max_size {
+            rejected = Some(AppError::TooLarge(format!(
+                "File exceeds the maximum upload size of {} bytes",
+                max_size
+            )));
+        } else if let Some(left) = quota_left.filter(|&left| written > left) {
+            rejected = Some(quota_exceeded(left));
+        } else {
+            hasher.update(&data);
+            f.write_all(&data).await?;
+        }
+    }
+    if let Some(e) = rejected {
+        return Err(e);
+    }
+    f.flush().await?;
+    drop(f);
+    let hash = format!("{:x}", hasher.finalize());
+
+    ingest_file(state, &user.user_id, partial, client_name, &hash, written).await
+}
+
+/// Checks a completely received file and stores it as a new audio file of
+/// `user_id`. `hash` is the SHA-256 of the file and `size` its length.
+/// Every upload endpoint ends here.
+pub(crate) async fn ingest_file(
+    state: &AppState,
+    user_id: &str,
+    partial: PartialFile,
+    client_name: &str,
+    hash: &str,
+    size: u64,
+) -> Result<AudioFile, AppError> {
+    let user_folder = storage::user_prefix(user_id)?.to_string();
+
+    // The client's filename is only kept for display; the stored file is
+    // named after its contents.
+    let filename = sanitize_filename(client_name);
+
+    // The client's Content-Type is ignored; the stored type is whatever the
+    // file turns out to contain. Probing reads the file, so it runs off the
+    // worker thread.
+    let probe_path = partial.path.clone();
+    let inspected = web::block(move || inspect_audio(&probe_path)).await??;
+
+    let mut audio_file = AudioFile {
+        id: Uuid::new_v4().to_string(),
+        filename,
+        user_id: user_id.to_string(),
+        created_at: Utc::now(),
+        mime_type: inspected.format.mime_type().to_string(),
+        size: Some(size as i64),
+        user_folder,
+        storage_path: None,
+        blob_hash: Some(hash.to_string()),
+        metadata: inspected.metadata,
+    };
+
+    // Identical files share one blob; the row takes a reference to it
+    let reservation = state.blobs.reserve(hash).await;
+    let mut tx = state.db_pool.begin().await?;
+    let blob = reservation
+        .record(&mut tx, size, inspected.format.extension())
+        .await?;
+    audio_file.storage_path = Some(blob.key.clone());
+    let before = user_usage(user_id, &mut tx).await?;
+    insert_audio_file(&audio_file, &mut tx).await?;
+
+    // Checked again with the row in place, as concurrent uploads may have
+    // used up the quota in the meantime, and contents the user already has
+    // don't count again
+    let usage = user_usage(user_id, &mut tx).await?;
+    if let Some(quota) = usage.quota_bytes.filter(|&quota| usage.used_bytes > quota) {
+        let left = before.remaining_bytes.unwrap_or(quota);
+        return Err(quota_exceeded(left as u64));
+    }
+    tx.commit().await?;
+
+    // New contents are moved into place only once the row is committed, so
+    // a failed upload never leaves a stored file nobody refers to
+    if blob.is_new {
+        if let Err(e) = reservation.write(&blob.key, &partial.path).await {
+            let _ = sqlx::query("DELETE FROM audio_files WHERE id = ?")
+                .bind(&audio_file.id)
+                .execute(&state.db_pool)
+                .await;
+            drop(reservation);
+            let _ = state.blobs.collect_garbage(&state.db_pool).await;
+            return Err(e);
+        }
+    }
+    Ok(audio_file)
+}
 
-    let user_id = validate_token(token, &state.secret_key)
-        .await
-        .ok_or_else(|| AppError("Invalid token".to_string()))?;
+/// Reports a file that could not be stored. Server-side details stay in the
+/// log, as they would for a failed request.
+fn upload_failure(path: String, err: AppError) -> UploadResult {
+    let message = if err.is_internal() {
+        eprintln!(
+            "Upload of {} failed: {}: {}",
+            path,
+            err.code(),
+            err.message()
+        );
+        "Internal server error".to_string()
+    } else {
+        err.message().to_string()
+    };
+    UploadResult::Failed {
+        path,
+        code: err.code().to_string(),
+        message,
+    }
+}
 
-    // Check if user is admin
-    let is_admin = check_admin(&user_id, &state.db_pool).await?;
+pub(crate) fn quota_exceeded(bytes_left: u64) -> AppError {
+    AppError::QuotaExceeded(format!(
+        "Upload exceeds your storage quota ({} bytes remaining)",
+        bytes_left
+    ))
+}
 
+async fn insert_audio_file(
+    audio_file: &AudioFile,
+    conn: &mut SqliteConnection,
+) -> Result<(), AppError> {
+    let meta = &audio_file.metadata;
+    sqlx::query(
+        "INSERT INTO audio_files (id, filename, user_id, created_at, mime_type, size, user_folder, storage_path, blob_hash, title, artist, album, album_artist, track_number, disc_number, year, genre, duration_ms, sample_rate, channels, bitrate_kbps) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+    )
+    .bind(&audio_file.id)
+    .bind(&audio_file.filename)
+    .bind(&audio_file.user_id)
+    .bind(audio_file.created_at)
+    .bind(&audio_file.mime_type)
+    .bind(audio_file.size)
+    .bind(&audio_file.user_folder)
+    .bind(&audio_file.storage_path)
+    .bind(&audio_file.blob_hash)
+    .bind(&meta.title)
+    .bind(&meta.artist)
+    .bind(&meta.album)
+    .bind(&meta.album_artist)
+    .bind(meta.track_number)
+    .bind(meta.disc_number)
+    .bind(meta.year)
+    .bind(&meta.genre)
+    .bind(meta.duration_ms)
+    .bind(meta.sample_rate)
+    .bind(meta.channels)
+    .bind(meta.bitrate_kbps)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Streams an audio file, either as stored, with range support, or
+/// transcoded as asked for by `?format=` and `?bitrate=` or the user's
+/// preferences.
+pub async fn stream_audio(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<StreamQuery>,
+    state: web::Data<AppState>,
+    StreamUser(user): StreamUser,
+) -> Result<HttpResponse, AppError> {
     let audio_id = path.into_inner();
     let audio = sqlx::query_as::<_, AudioFile>("SELECT * FROM audio_files WHERE id = ?")
         .bind(&audio_id)
         .fetch_optional(&state.db_pool)
-        .await
-        .map_err(|e| AppError(e.to_string()))?;
+        .await?;
 
     if let Some(audio) = audio {
-        // Check if user has access to this audio file
-        if audio.user_id != user_id && !is_admin {
-            return Err(AppError("Not authorized to access this audio file".to_string()).into());
+        // Check if user has access to this audio file, as its owner or
+        // through a playlist shared with them
+        if !sharing::can_stream(&user, &audio, &state.db_pool).await? {
+            return Err(AppError::Forbidden(
+                "Not authorized to access this audio file".to_string(),
+            ));
+        }
+
+        let key = audio_key(&audio)?;
+
+        let mut conn = state.db_pool.acquire().await?;
+        let preferences = transcoding_preferences(&user.user_id, &mut conn).await?;
+        drop(conn);
+        if let Some(options) = transcode::resolve(&query, &preferences, &audio)? {
+            let mime_type = options.format.mime_type().parse::<Mime>().unwrap();
+            let cache = &state.transcode_cache;
+            let cache_key = TranscodeCache::key(&audio, options);
+            if cache.lookup(&cache_key) {
+                match serve_object(&req, cache.files(), &cache_key, mime_type.clone()).await {
+                    // Evicted in the meantime
+                    Err(AppError::NotFound(_)) => cache.forget(&cache_key),
+                    served => return served,
+                }
+            }
+
+            // The output's length isn't known until it is complete, so it
+            // can't be served in ranges until it has been cached
+            let stream = state
+                .transcoder
+                .transcode(state.storage.clone(), &key, options)
+                .await?;
+            return Ok(HttpResponse::Ok()
+                .content_type(mime_type)
+                .insert_header((header::ACCEPT_RANGES, "none"))
+                .streaming(cache.record(cache_key, stream)));
         }
 
-        let filepath = format!("{}/{}_{}", audio.user_folder, audio.id, audio.filename);
         let mime_type = audio
             .mime_type
             .parse::<Mime>()
             .unwrap_or("audio/mpeg".parse::<Mime>().unwrap());
-        let file = NamedFile::open(filepath)?.set_content_type(mime_type);
-        Ok(file)
+        serve_object(&req, state.storage.as_ref(), &key, mime_type).await
     } else {
-        Err(AppError("Audio not found".to_string()).into())
+        Err(AppError::NotFound("Audio not found".to_string()))
     }
 }
 
+/// Sends the object `key` from `storage`, or the single byte range asked
+/// for in the request's `Range` header.
+pub(crate) async fn serve_object(
+    req: &HttpRequest,
+    storage: &dyn StorageBackend,
+    key: &str,
+    mime_type: Mime,
+) -> Result<HttpResponse, AppError> {
+    let requested = req
+        .headers()
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<header::Range>().ok());
+
+    let range = match requested {
+        Some(header::Range::Bytes(specs)) if specs.len() == 1 => {
+            let size = storage.stat(key).await?.size;
+            match specs[0].to_satisfiable_range(size) {
+                Some((start, end)) => Some(start..end + 1),
+                None => {
+                    return Ok(HttpResponse::RangeNotSatisfiable()
+                        .insert_header((header::CONTENT_RANGE, format!("bytes */{}", size)))
+                        .finish())
+                }
+            }
+        }
+        // Multiple or non-byte ranges get the whole file
+        _ => None,
+    };
+    let partial = range.is_some();
+
+    let data = storage.get_range(key, range).await?;
+    let length = data.range.end - data.range.start;
+
+    let mut response = if partial {
+        let mut response = HttpResponse::PartialContent();
+        response.insert_header((
+            header::CONTENT_RANGE,
+            format!(
+                "bytes {}-{}/{}",
+                data.range.start,
+                data.range.end.saturating_sub(1),
+                data.size
+            ),
+        ));
+        response
+    } else {
+        HttpResponse::Ok()
+    };
+    Ok(response
+        .content_type(mime_type)
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .no_chunking(length)
+        .streaming(data.stream))
+}
+
 pub async fn delete_audio(
     path: web::Path<String>,
     state: web::Data<AppState>,
-    req: HttpRequest,
-) -> Result<HttpResponse, Error> {
-    let token = req
-        .headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .and_then(|s| s.strip_prefix("Bearer "))
-        .ok_or_else(|| AppError("Authentication required".to_string()))?;
-
-    let user_id = validate_token(token, &state.secret_key)
-        .await
-        .ok_or_else(|| AppError("Invalid token".to_string()))?;
-
-    // Check if user is admin
-    let is_admin = check_admin(&user_id, &state.db_pool).await?;
-
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
     let audio_id = path.into_inner();
     let audio = sqlx::query_as::<_, AudioFile>("SELECT * FROM audio_files WHERE id = ?")
         .bind(&audio_id)
         .fetch_optional(&state.db_pool)
-        .await
-        .map_err(|e| AppError(e.to_string()))?;
+        .await?;
 
     if let Some(audio) = audio {
         // Check if user has access to delete this audio file
-        if audio.user_id != user_id && !is_admin {
-            return Err(AppError("Not authorized to delete this audio file".to_string()).into());
+        if !user.can_access(&audio.user_id) {
+            return Err(AppError::Forbidden(
+                "Not authorized to delete this audio file".to_string(),
+            ));
         }
 
-        let filepath = format!("{}/{}_{}", audio.user_folder, audio.id, audio.filename);
-        fs::remove_file(filepath)?;
-
+        let key = audio_key(&audio)?;
         sqlx::query("DELETE FROM audio_files WHERE id = ?")
             .bind(audio_id)
             .execute(&state.db_pool)
-            .await
-            .map_err(|e| AppError(e.to_string()))?;
+            .await?;
+
+        state.transcode_cache.invalidate(&audio.id).await;
+
+        // The file goes once the row is gone, so a failure in between leaves
+        // at worst an unused file rather than a row without one. Shared blobs
+        // are only removed once no row refers to them.
+        if audio.blob_hash.is_some() {
+            state.blobs.collect_garbage(&state.db_pool).await?;
+        } else {
+            match state.storage.delete(&key).await {
+                Ok(()) | Err(AppError::NotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
 
         Ok(HttpResponse::Ok().body("Audio deleted"))
     } else {
-        Err(AppError("Audio not found".to_string()).into())
+        Err(AppError::NotFound("Audio not found".to_string()))
     }
 }
 
 pub async fn get_user_audio(
     path: web::Path<String>,
     state: web::Data<AppState>,
-    req: HttpRequest,
-) -> Result<HttpResponse, Error> {
-    let token = req
-        .headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .and_then(|s| s.strip_prefix("Bearer "))
-        .ok_or_else(|| AppError("Authentication required".to_string()))?;
-
-    let current_user_id = validate_token(token, &state.secret_key)
-        .await
-        .ok_or_else(|| AppError("Invalid token".to_string()))?;
-
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
     let target_user_id = path.into_inner();
 
     // Check if current user is admin or is accessing their own files
-    if current_user_id != target_user_id {
-        let is_admin = check_admin(&current_user_id, &state.db_pool).await?;
-
-        if !is_admin {
-            return Err(AppError("Not authorized to access this user's files".to_string()).into());
-        }
+    if !user.can_access(&target_user_id) {
+        return Err(AppError::Forbidden(
+            "Not authorized to access this user's files".to_string(),
+        ));
     }
 
     // Get user's audio files
@@ -224,8 +489,76 @@ pub async fn get_user_audio(
     )
     .bind(target_user_id)
     .fetch_all(&state.db_pool)
-    .await
-    .map_err(|e| AppError(e.to_string()))?;
+    .await?;
 
     Ok(HttpResponse::Ok().json(audio_files))
 }
+
+/// Reports how much space is saved by storing identical uploads once.
+pub async fn storage_stats(
+    state: web::Data<AppState>,
+    _admin: AdminUser,
+) -> Result<HttpResponse, AppError> {
+    let (files, logical_bytes) = sqlx::query_as::<_, (i64, i64)>(
+        "SELECT COUNT(*), COALESCE(SUM(b.size), 0) FROM audio_files af JOIN blobs b ON af.blob_hash = b.hash",
+    )
+    .fetch_one(&state.db_pool)
+    .await?;
+
+    let (blobs, stored_bytes) =
+        sqlx::query_as::<_, (i64, i64)>("SELECT COUNT(*), COALESCE(SUM(size), 0) FROM blobs")
+            .fetch_one(&state.db_pool)
+            .await?;
+
+    Ok(HttpResponse::Ok().json(StorageStats {
+        files,
+        blobs,
+        logical_bytes,
+        stored_bytes,
+        saved_bytes: logical_bytes - stored_bytes,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+
+    use crate::auth::test_bearer;
+
+    #[actix_web::test]
+    async fn upload_over_quota_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = web::Data::new(AppState::for_tests(dir.path()).await);
+        sqlx::query("UPDATE users SET quota_bytes = 5 WHERE id = 'admin-user-id'")
+            .execute(&state.db_pool)
+            .await
+            .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .route("/audio", web::post().to(upload_audio)),
+        )
+        .await;
+
+        let body = "--x\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.mp3\"\r\n\r\n0123456789\r\n--x--\r\n";
+        let req = test::TestRequest::post()
+            .uri("/audio")
+            .insert_header((
+                header::AUTHORIZATION,
+                test_bearer("admin-user-id", &state).await,
+            ))
+            .insert_header((header::CONTENT_TYPE, "multipart/form-data; boundary=x"))
+            .set_payload(body)
+            .to_request();
+        let response: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        assert_eq!(response["results"][0]["status"], "failed");
+        assert_eq!(response["results"][0]["code"], "quota_exceeded");
+        let stored: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM audio_files")
+            .fetch_one(&state.db_pool)
+            .await
+            .unwrap();
+        assert_eq!(stored, 0);
+    }
+}
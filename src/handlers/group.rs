@@ -0,0 +1,156 @@
+use actix_web::{web, HttpResponse};
+use chrono::Utc;
+use sqlx::SqlitePool;
+use uuid::Uuid;
+
+use crate::auth::{AdminUser, AuthenticatedUser};
+use crate::config::AppState;
+use crate::error::AppError;
+use crate::models::{CreateGroupRequest, Group, GroupMember, GroupWithMembers};
+
+/// Creates a group of users that playlists can be shared with.
+pub async fn create_group(
+    req: web::Json<CreateGroupRequest>,
+    state: web::Data<AppState>,
+    _admin: AdminUser,
+) -> Result<HttpResponse, AppError> {
+    let name = req.name.trim();
+    if name.is_empty() {
+        return Err(AppError::Validation(
+            "Group name must not be empty".to_string(),
+        ));
+    }
+
+    let group = Group {
+        id: Uuid::new_v4().to_string(),
+        name: name.to_string(),
+        created_at: Utc::now(),
+    };
+    // A duplicate name fails the unique index and is reported as a conflict
+    sqlx::query("INSERT INTO user_groups (id, name, created_at) VALUES (?, ?, ?)")
+        .bind(&group.id)
+        .bind(&group.name)
+        .bind(group.created_at)
+        .execute(&state.db_pool)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(GroupWithMembers {
+        group,
+        members: Vec::new(),
+    }))
+}
+
+/// Lists every group for admins, and the groups they belong to for
+/// everyone else.
+pub async fn list_groups(
+    state: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let groups = if user.is_admin {
+        sqlx::query_as::<_, Group>("SELECT * FROM user_groups ORDER BY name")
+            .fetch_all(&state.db_pool)
+            .await
+    } else {
+        sqlx::query_as::<_, Group>(
+            "SELECT g.* FROM user_groups g
+             JOIN user_group_members m ON m.group_id = g.id
+             WHERE m.user_id = ?
+             ORDER BY g.name",
+        )
+        .bind(&user.user_id)
+        .fetch_all(&state.db_pool)
+        .await
+    }?;
+
+    let mut response = Vec::with_capacity(groups.len());
+    for group in groups {
+        let members = group_members(&group.id, &state.db_pool).await?;
+        response.push(GroupWithMembers { group, members });
+    }
+
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// Deletes a group; playlists shared with it are no longer shared with its
+/// members.
+pub async fn delete_group(
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+    _admin: AdminUser,
+) -> Result<HttpResponse, AppError> {
+    // Memberships and shares are removed by ON DELETE CASCADE
+    let deleted = sqlx::query("DELETE FROM user_groups WHERE id = ?")
+        .bind(path.into_inner())
+        .execute(&state.db_pool)
+        .await?;
+    if deleted.rows_affected() == 0 {
+        return Err(AppError::NotFound("Group not found".to_string()));
+    }
+
+    Ok(HttpResponse::Ok().body("Group deleted"))
+}
+
+pub async fn add_group_member(
+    path: web::Path<(String, String)>,
+    state: web::Data<AppState>,
+    _admin: AdminUser,
+) -> Result<HttpResponse, AppError> {
+    let (group_id, user_id) = path.into_inner();
+
+    let group = sqlx::query_as::<_, Group>("SELECT * FROM user_groups WHERE id = ?")
+        .bind(&group_id)
+        .fetch_optional(&state.db_pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Group not found".to_string()))?;
+    let user = sqlx::query("SELECT id FROM users WHERE id = ?")
+        .bind(&user_id)
+        .fetch_optional(&state.db_pool)
+        .await?;
+    if user.is_none() {
+        return Err(AppError::NotFound("User not found".to_string()));
+    }
+
+    // Adding a member twice leaves them in the group once
+    sqlx::query("INSERT OR IGNORE INTO user_group_members (group_id, user_id) VALUES (?, ?)")
+        .bind(&group_id)
+        .bind(&user_id)
+        .execute(&state.db_pool)
+        .await?;
+
+    let members = group_members(&group_id, &state.db_pool).await?;
+    Ok(HttpResponse::Ok().json(GroupWithMembers { group, members }))
+}
+
+pub async fn remove_group_member(
+    path: web::Path<(String, String)>,
+    state: web::Data<AppState>,
+    _admin: AdminUser,
+) -> Result<HttpResponse, AppError> {
+    let (group_id, user_id) = path.into_inner();
+
+    let deleted = sqlx::query("DELETE FROM user_group_members WHERE group_id = ? AND user_id = ?")
+        .bind(&group_id)
+        .bind(&user_id)
+        .execute(&state.db_pool)
+        .await?;
+    if deleted.rows_affected() == 0 {
+        return Err(AppError::NotFound(
+            "User is not a member of this group".to_string(),
+        ));
+    }
+
+    Ok(HttpResponse::Ok().body("Member removed"))
+}
+
+async fn group_members(group_id: &str, pool: &SqlitePool) -> Result<Vec<GroupMember>, AppError> {
+    let members = sqlx::query_as::<_, GroupMember>(
+        "SELECT u.id AS user_id, u.username FROM user_group_members m
+         JOIN users u ON m.user_id = u.id
+         WHERE m.group_id = ?
+         ORDER BY u.username",
+    )
+    .bind(group_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(members)
+}
@@ -0,0 +1,385 @@
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use mime::Mime;
+
+use crate::auth::{
+    issue_playlist_stream_token, issue_stream_token, AuthenticatedUser, PlaylistStreamUser,
+    StreamUser,
+};
+use crate::config::AppState;
+use crate::error::AppError;
+use crate::handlers::audio::serve_object;
+use crate::handlers::playlist::public_url;
+use crate::models::{AudioFile, HlsLink, Playlist, ShareAccess};
+use crate::sharing;
+use crate::storage::audio_key;
+use crate::transcode::hls::{self, MediaTrack};
+use crate::transcode::TranscodeCache;
+
+/// A link to the master playlist of an audio file for media players, which
+/// can't send an `Authorization` header.
+pub async fn audio_hls_link(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let audio = find_audio(&path.into_inner(), &state, &user).await?;
+    track_duration(&audio)?;
+
+    let token = issue_stream_token(&user.user_id, &audio.id, &state.config.auth)?;
+    Ok(HttpResponse::Ok().json(HlsLink {
+        url: format!(
+            "{}/audio/{}/hls/index.m3u8?token={}",
+            public_url(&req, &state),
+            audio.id,
+            token
+        ),
+        expires_in: state.config.auth.stream_token_ttl().num_seconds(),
+    }))
+}
+
+/// The master playlist of an audio file, offering each configured bitrate.
+/// Every URI in it and in the playlists it leads to carries a new stream
+/// token, so players can fetch them however the caller authenticated.
+pub async fn audio_hls_master(
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+    StreamUser(user): StreamUser,
+) -> Result<HttpResponse, AppError> {
+    let audio = find_audio(&path.into_inner(), &state, &user).await?;
+    track_duration(&audio)?;
+
+    let token = issue_stream_token(&user.user_id, &audio.id, &state.config.auth)?;
+    Ok(playlist_response(hls::master_playlist(
+        &state.config.transcoding.hls_bitrates,
+        Some(&token),
+    )))
+}
+
+/// The media playlist of an audio file at one bitrate.
+pub async fn audio_hls_variant(
+    path: web::Path<(String, u32)>,
+    state: web::Data<AppState>,
+    StreamUser(user): StreamUser,
+) -> Result<HttpResponse, AppError> {
+    let (audio_id, bitrate) = path.into_inner();
+    variant_bitrate(&state, bitrate)?;
+    let audio = find_audio(&audio_id, &state, &user).await?;
+
+    let track = MediaTrack {
+        duration_ms: track_duration(&audio)?,
+        segment_prefix: String::new(),
+        token: Some(issue_stream_token(
+            &user.user_id,
+            &audio.id,
+            &state.config.auth,
+        )?),
+    };
+    Ok(playlist_response(hls::media_playlist(
+        &[track],
+        state.config.transcoding.hls_segment_ms(),
+    )))
+}
+
+/// One segment of an audio file, transcoded when first asked for.
+pub async fn audio_hls_segment(
+    req: HttpRequest,
+    path: web::Path<(String, u32, u64)>,
+    state: web::Data<AppState>,
+    StreamUser(user): StreamUser,
+) -> Result<HttpResponse, AppError> {
+    let (audio_id, bitrate, index) = path.into_inner();
+    variant_bitrate(&state, bitrate)?;
+    let audio = find_audio(&audio_id, &state, &user).await?;
+
+    let segment_ms = state.config.transcoding.hls_segment_ms();
+    let span = hls::segment(track_duration(&audio)?, segment_ms, index)
+        .ok_or_else(|| AppError::NotFound("Segment not found".to_string()))?;
+
+    let mime_type = hls::SEGMENT_MIME_TYPE.parse::<Mime>().unwrap();
+    let cache = &state.transcode_cache;
+    let cache_key = TranscodeCache::segment_key(&audio, bitrate, segment_ms, index);
+    if cache.lookup(&cache_key) {
+        match serve_object(&req, cache.files(), &cache_key, mime_type.clone()).await {
+            // Evicted in the meantime
+            Err(AppError::NotFound(_)) => cache.forget(&cache_key),
+            served => return served,
+        }
+    }
+
+    let stream = state
+        .transcoder
+        .transcode_segment(state.storage.clone(), &audio_key(&audio)?, bitrate, span)
+        .await?;
+    Ok(HttpResponse::Ok()
+        .content_type(mime_type)
+        .insert_header((header::ACCEPT_RANGES, "none"))
+        .streaming(cache.record(cache_key, stream)))
+}
+
+/// A link to the master playlist of a playlist for media players, which
+/// can't send an `Authorization` header.
+pub async fn playlist_hls_link(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let playlist_id = path.into_inner();
+    playlist_tracks(&playlist_id, &state, &user).await?;
+
+    let token = issue_playlist_stream_token(&user.user_id, &playlist_id, &state.config.auth)?;
+    Ok(HttpResponse::Ok().json(HlsLink {
+        url: format!(
+            "{}/playlists/{}/hls/index.m3u8?token={}",
+            public_url(&req, &state),
+            playlist_id,
+            token
+        ),
+        expires_in: state.config.auth.stream_token_ttl().num_seconds(),
+    }))
+}
+
+/// The master playlist of a playlist, offering each configured bitrate.
+pub async fn playlist_hls_master(
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+    PlaylistStreamUser(user): PlaylistStreamUser,
+) -> Result<HttpResponse, AppError> {
+    let playlist_id = path.into_inner();
+    playlist_tracks(&playlist_id, &state, &user).await?;
+
+    let token = issue_playlist_stream_token(&user.user_id, &playlist_id, &state.config.auth)?;
+    Ok(playlist_response(hls::master_playlist(
+        &state.config.transcoding.hls_bitrates,
+        Some(&token),
+    )))
+}
+
+/// The media playlist of a playlist at one bitrate: every track's segments
+/// in playlist order, each with a stream token for its track. Tracks of
+/// unknown duration can't be segmented and are left out.
+pub async fn playlist_hls_variant(
+    path: web::Path<(String, u32)>,
+    state: web::Data<AppState>,
+    PlaylistStreamUser(user): PlaylistStreamUser,
+) -> Result<HttpResponse, AppError> {
+    let (playlist_id, bitrate) = path.into_inner();
+    variant_bitrate(&state, bitrate)?;
+
+    let mut tracks = Vec::new();
+    for audio in playlist_tracks(&playlist_id, &state, &user).await? {
+        let Ok(duration_ms) = track_duration(&audio) else {
+            continue;
+        };
+        tracks.push(MediaTrack {
+            duration_ms,
+            segment_prefix: format!("/audio/{}/hls/{}/", audio.id, bitrate),
+            token: Some(issue_stream_token(
+                &user.user_id,
+                &audio.id,
+                &state.config.auth,
+            )?),
+        });
+    }
+    Ok(playlist_response(hls::media_playlist(
+        &tracks,
+        state.config.transcoding.hls_segment_ms(),
+    )))
+}
+
+async fn find_audio(
+    audio_id: &str,
+    state: &AppState,
+    user: &AuthenticatedUser,
+) -> Result<AudioFile, AppError> {
+    let audio = sqlx::query_as::<_, AudioFile>("SELECT * FROM audio_files WHERE id = ?")
+        .bind(audio_id)
+        .fetch_optional(&state.db_pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Audio not found".to_string()))?;
+
+    if !sharing::can_stream(user, &audio, &state.db_pool).await? {
+        return Err(AppError::Forbidden(
+            "Not authorized to access this audio file".to_string(),
+        ));
+    }
+    Ok(audio)
+}
+
+/// The files of a playlist the user may stream, in order, once they may
+/// access it.
+async fn playlist_tracks(
+    playlist_id: &str,
+    state: &AppState,
+    user: &AuthenticatedUser,
+) -> Result<Vec<AudioFile>, AppError> {
+    let playlist = sqlx::query_as::<_, Playlist>("SELECT * FROM playlists WHERE id = ?")
+        .bind(playlist_id)
+        .fetch_optional(&state.db_pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Playlist not found".to_string()))?;
+
+    sharing::require_access(user, &playlist, ShareAccess::Read, &state.db_pool).await?;
+
+    let items = sharing::streamable_tracks(user, playlist_id, &state.db_pool).await?;
+
+    if items.is_empty() {
+        return Err(AppError::Validation(
+            "Playlist has no tracks you can stream".to_string(),
+        ));
+    }
+    Ok(items)
+}
+
+/// Segments are cut by time, so a file's duration must be known.
+fn track_duration(audio: &AudioFile) -> Result<u64, AppError> {
+    audio
+        .metadata
+        .duration_ms
+        .filter(|&ms| ms > 0)
+        .map(|ms| ms as u64)
+        .ok_or_else(|| {
+            AppError::Validation(
+                "The duration of this file is unknown, so it can't be streamed with HLS"
+                    .to_string(),
+            )
+        })
+}
+
+/// Only the configured bitrates are offered, which keeps the cache small.
+fn variant_bitrate(state: &AppState, bitrate: u32) -> Result<(), AppError> {
+    if state.config.transcoding.hls_bitrates.contains(&bitrate) {
+        Ok(())
+    } else {
+        Err(AppError::NotFound("No variant at this bitrate".to_string()))
+    }
+}
+
+fn playlist_response(playlist: String) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(hls::PLAYLIST_MIME_TYPE)
+        .body(playlist)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use chrono::Utc;
+
+    use crate::auth::test_bearer;
+
+    /// A playlist of two 10-second tracks, both owned by the seeded admin.
+    async fn seed(state: &AppState) {
+        sqlx::raw_sql(
+            "INSERT INTO audio_files (id, filename, user_id, created_at, mime_type, user_folder, duration_ms)
+             VALUES ('a1', 'one.flac', 'admin-user-id', '2024-01-01T00:00:00Z', 'audio/flac', 'admin-user-id', 10000),
+                    ('a2', 'two.flac', 'admin-user-id', '2024-01-01T00:00:00Z', 'audio/flac', 'admin-user-id', 10000);",
+        )
+        .execute(&state.db_pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO playlists (id, name, user_id, created_at, updated_at) VALUES ('p1', 'Mix', 'admin-user-id', ?, ?)",
+        )
+        .bind(Utc::now())
+        .bind(Utc::now())
+        .execute(&state.db_pool)
+        .await
+        .unwrap();
+        sqlx::raw_sql(
+            "INSERT INTO playlist_items (id, playlist_id, audio_id, position, added_by)
+             VALUES ('i1', 'p1', 'a1', 1, 'admin-user-id'), ('i2', 'p1', 'a2', 2, 'admin-user-id');",
+        )
+        .execute(&state.db_pool)
+        .await
+        .unwrap();
+    }
+
+    fn uris(playlist: &str) -> Vec<String> {
+        playlist
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn playlists_carry_stream_tokens_to_every_uri() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = web::Data::new(AppState::for_tests(dir.path()).await);
+        seed(&state).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .route("/playlists/{id}/hls/link", web::get().to(playlist_hls_link))
+                .route(
+                    "/playlists/{id}/hls/index.m3u8",
+                    web::get().to(playlist_hls_master),
+                )
+                .route(
+                    "/playlists/{id}/hls/{bitrate}/index.m3u8",
+                    web::get().to(playlist_hls_variant),
+                )
+                .route(
+                    "/audio/{id}/hls/{bitrate}/index.m3u8",
+                    web::get().to(audio_hls_variant),
+                ),
+        )
+        .await;
+        let get = |uri: &str| test::TestRequest::get().uri(uri).to_request();
+
+        let link = test::TestRequest::get()
+            .uri("/playlists/p1/hls/link")
+            .insert_header((
+                header::AUTHORIZATION,
+                test_bearer("admin-user-id", &state).await,
+            ))
+            .to_request();
+        let link: serde_json::Value = test::call_and_read_body_json(&app, link).await;
+        let master_uri = link["url"].as_str().unwrap();
+        let master_uri = &master_uri[master_uri.find("/playlists/").unwrap()..];
+        assert!(master_uri.contains("?token="));
+
+        // Without a token, or with the playlist's token on a track, nothing
+        // is served
+        let response = test::call_service(&app, get("/playlists/p1/hls/index.m3u8")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let playlist_token = &master_uri[master_uri.find("token=").unwrap() + 6..];
+        let response = test::call_service(
+            &app,
+            get(&format!(
+                "/audio/a1/hls/64/index.m3u8?token={}",
+                playlist_token
+            )),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let master = test::call_and_read_body(&app, get(master_uri)).await;
+        let variants = uris(std::str::from_utf8(&master).unwrap());
+        assert_eq!(variants.len(), state.config.transcoding.hls_bitrates.len());
+        assert!(variants
+            .iter()
+            .all(|uri| uri.contains("/index.m3u8?token=")));
+
+        let variant = format!("/playlists/p1/hls/{}", variants[0]);
+        let media = test::call_and_read_body(&app, get(&variant)).await;
+        let segments = uris(std::str::from_utf8(&media).unwrap());
+        assert_eq!(segments.len(), 4);
+        for segment in &segments {
+            let (path, token) = segment.split_once("?token=").unwrap();
+            assert!(path.starts_with("/audio/a1/hls/64/") || path.starts_with("/audio/a2/hls/64/"));
+
+            // Each segment's token is good for its own track
+            let track = path.rsplit_once('/').unwrap().0;
+            let response =
+                test::call_service(&app, get(&format!("{}/index.m3u8?token={}", track, token)))
+                    .await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+    }
+}
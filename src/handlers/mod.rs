@@ -1,7 +1,13 @@
 pub mod audio;
+pub mod group;
+pub mod hls;
 pub mod playlist;
+pub mod tus;
 pub mod user;
 
 pub use audio::*;
+pub use group::*;
+pub use hls::*;
 pub use playlist::*;
+pub use tus::*;
 pub use user::*;
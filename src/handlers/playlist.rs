@@ -1,74 +1,88 @@
-use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_multipart::Multipart;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
 use chrono::Utc;
+use futures::StreamExt;
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+use sqlx::{SqliteConnection, SqlitePool};
+use std::collections::HashSet;
 use uuid::Uuid;
 
-use crate::auth::{check_admin, validate_token};
+use crate::auth::{issue_stream_token, AuthenticatedUser};
 use crate::config::AppState;
 use crate::error::AppError;
 use crate::models::{
-    AddToPlaylistRequest, CreatePlaylistRequest, Playlist, PlaylistAudioItem, PlaylistItem,
-    PlaylistWithItems, StreamPlaylistOptions,
+    AddToPlaylistRequest, AudioFile, CreatePlaylistRequest, DuplicatePlaylistOptions,
+    EditPlaylistItemsRequest, GetPlaylistsOptions, ImportPlaylistOptions, ImportedPlaylist,
+    ItemPlacement, MatchedEntry, Playlist, PlaylistAudioItem, PlaylistImportResponse, PlaylistItem,
+    PlaylistItemEdit, PlaylistShare, PlaylistWithItems, ShareAccess, SharePlaylistRequest,
+    SharedPlaylist, StreamPlaylistOptions, UnmatchedEntry, UpdatePlaylistRequest,
 };
+use crate::playlist_edit::{self, EditedItem};
+use crate::playlist_formats::{self, PlaylistEntry};
+use crate::sharing;
+use crate::track_matcher::TrackMatcher;
 
 pub async fn create_playlist(
     req: web::Json<CreatePlaylistRequest>,
     state: web::Data<AppState>,
-    http_req: HttpRequest,
-) -> Result<HttpResponse, Error> {
-    let token = http_req
-        .headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .and_then(|s| s.strip_prefix("Bearer "))
-        .ok_or_else(|| AppError("Authentication required".to_string()))?;
-
-    let user_id = validate_token(token, &state.secret_key)
-        .await
-        .ok_or_else(|| AppError("Invalid token".to_string()))?;
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let playlist = Playlist::new(&user.user_id, &req.name);
+    let mut conn = state.db_pool.acquire().await?;
+    insert_playlist(&playlist, &mut conn).await?;
 
-    let playlist_id = Uuid::new_v4().to_string();
-    let created_at = Utc::now();
+    Ok(HttpResponse::Ok().json(playlist))
+}
 
-    sqlx::query!(
-        "INSERT INTO playlists (id, name, user_id, created_at) VALUES (?, ?, ?, ?)",
-        playlist_id,
-        req.name,
-        user_id,
-        created_at
+async fn insert_playlist(playlist: &Playlist, conn: &mut SqliteConnection) -> Result<(), AppError> {
+    sqlx::query(
+        "INSERT INTO playlists (id, name, user_id, created_at, version, description,
+                                cover_image_url, visibility, updated_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
     )
-    .execute(&state.db_pool)
-    .await
-    .map_err(|e| AppError(e.to_string()))?;
-
-    let playlist = Playlist {
-        id: playlist_id,
-        name: req.name.clone(),
-        user_id,
-        created_at,
-    };
-
-    Ok(HttpResponse::Ok().json(playlist))
+    .bind(&playlist.id)
+    .bind(&playlist.name)
+    .bind(&playlist.user_id)
+    .bind(playlist.created_at)
+    .bind(playlist.version)
+    .bind(&playlist.description)
+    .bind(&playlist.cover_image_url)
+    .bind(playlist.visibility)
+    .bind(playlist.updated_at)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
 }
 
 pub async fn get_playlists(
+    options: web::Query<GetPlaylistsOptions>,
     state: web::Data<AppState>,
-    req: HttpRequest,
-) -> Result<HttpResponse, Error> {
-    let token = req
-        .headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .and_then(|s| s.strip_prefix("Bearer "))
-        .ok_or_else(|| AppError("Authentication required".to_string()))?;
-
-    let user_id = validate_token(token, &state.secret_key)
-        .await
-        .ok_or_else(|| AppError("Invalid token".to_string()))?;
-
-    // Check if user is admin
-    let is_admin = check_admin(&user_id, &state.db_pool).await?;
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    if options.shared {
+        // Other users' playlists shared with this user or their groups
+        let shared = sqlx::query_as::<_, SharedPlaylist>(&format!(
+            "SELECT p.*, u.username AS owner_username,
+                    CASE WHEN MAX(s.access = 'edit') THEN 'edit' ELSE 'read' END AS access
+             FROM ({}) s
+             JOIN playlists p ON s.playlist_id = p.id
+             JOIN users u ON p.user_id = u.id
+             WHERE p.user_id != ?
+             GROUP BY p.id
+             ORDER BY p.updated_at DESC",
+            sharing::SHARES_FOR_USER
+        ))
+        .bind(&user.user_id)
+        .bind(&user.user_id)
+        .bind(&user.user_id)
+        .fetch_all(&state.db_pool)
+        .await?;
+        return Ok(HttpResponse::Ok().json(shared));
+    }
 
-    let playlists = if is_admin {
+    let playlists = if user.is_admin {
         // Admins can see all playlists
         sqlx::query_as::<_, Playlist>("SELECT * FROM playlists ORDER BY created_at DESC")
             .fetch_all(&state.db_pool)
@@ -78,11 +92,10 @@ pub async fn get_playlists(
         sqlx::query_as::<_, Playlist>(
             "SELECT * FROM playlists WHERE user_id = ? ORDER BY created_at DESC",
         )
-        .bind(user_id)
+        .bind(&user.user_id)
         .fetch_all(&state.db_pool)
         .await
-    }
-    .map_err(|e| AppError(e.to_string()))?;
+    }?;
 
     Ok(HttpResponse::Ok().json(playlists))
 }
@@ -90,123 +103,330 @@ pub async fn get_playlists(
 pub async fn get_playlist(
     path: web::Path<String>,
     state: web::Data<AppState>,
-    req: HttpRequest,
-) -> Result<HttpResponse, Error> {
-    let token = req
-        .headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .and_then(|s| s.strip_prefix("Bearer "))
-        .ok_or_else(|| AppError("Authentication required".to_string()))?;
-
-    let user_id = validate_token(token, &state.secret_key)
-        .await
-        .ok_or_else(|| AppError("Invalid token".to_string()))?;
-
-    // Check if user is admin
-    let is_admin = check_admin(&user_id, &state.db_pool).await?;
-
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
     let playlist_id = path.into_inner();
     let playlist = sqlx::query_as::<_, Playlist>("SELECT * FROM playlists WHERE id = ?")
         .bind(&playlist_id)
         .fetch_optional(&state.db_pool)
-        .await
-        .map_err(|e| AppError(e.to_string()))?;
+        .await?;
 
     if let Some(playlist) = playlist {
         // Check if user has access to this playlist
-        if playlist.user_id != user_id && !is_admin {
-            return Err(AppError("Not authorized to access this playlist".to_string()).into());
+        sharing::require_access(&user, &playlist, ShareAccess::Read, &state.db_pool).await?;
+
+        Ok(HttpResponse::Ok().json(with_items(playlist, &state.db_pool).await?))
+    } else {
+        Err(AppError::NotFound("Playlist not found".to_string()))
+    }
+}
+
+/// Longest playlist description accepted.
+const MAX_DESCRIPTION_LENGTH: usize = 4000;
+
+/// Renames a playlist or changes its description, cover image or
+/// visibility.
+pub async fn update_playlist(
+    path: web::Path<String>,
+    req: web::Json<UpdatePlaylistRequest>,
+    state: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let playlist_id = path.into_inner();
+    let mut playlist = find_playlist(&playlist_id, &state.db_pool).await?;
+
+    // Sharing with edit access covers the items only; the name, description
+    // and visibility stay with the owner, or an admin
+    if !user.can_access(&playlist.user_id) {
+        return Err(AppError::Forbidden(
+            "Not authorized to modify this playlist".to_string(),
+        ));
+    }
+
+    let req = req.into_inner();
+    if let Some(name) = req.name {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(AppError::Validation(
+                "Playlist name must not be empty".to_string(),
+            ));
         }
+        playlist.name = name.to_string();
+    }
+    if let Some(description) = req.description {
+        if description.chars().count() > MAX_DESCRIPTION_LENGTH {
+            return Err(AppError::Validation(format!(
+                "Descriptions may be at most {} characters",
+                MAX_DESCRIPTION_LENGTH
+            )));
+        }
+        playlist.description = Some(description).filter(|text| !text.trim().is_empty());
+    }
+    if let Some(url) = req.cover_image_url {
+        let url = url.trim();
+        if !url.is_empty() && !url.starts_with("https://") && !url.starts_with("http://") {
+            return Err(AppError::Validation(
+                "Cover image URL must start with http:// or https://".to_string(),
+            ));
+        }
+        playlist.cover_image_url = Some(url.to_string()).filter(|url| !url.is_empty());
+    }
+    if let Some(visibility) = req.visibility {
+        playlist.visibility = visibility;
+    }
+    playlist.updated_at = Utc::now();
 
-        // Get playlist items with audio details
-        let items = sqlx::query!(
-            "SELECT pi.id, pi.audio_id, pi.position, af.filename, af.mime_type 
-             FROM playlist_items pi
-             JOIN audio_files af ON pi.audio_id = af.id
-             WHERE pi.playlist_id = ?
-             ORDER BY pi.position",
-            playlist_id
+    sqlx::query(
+        "UPDATE playlists SET name = ?, description = ?, cover_image_url = ?, visibility = ?,
+                              updated_at = ?
+         WHERE id = ?",
+    )
+    .bind(&playlist.name)
+    .bind(&playlist.description)
+    .bind(&playlist.cover_image_url)
+    .bind(playlist.visibility)
+    .bind(playlist.updated_at)
+    .bind(&playlist.id)
+    .execute(&state.db_pool)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(playlist))
+}
+
+/// Copies a playlist the user can see, with its items, into a new private
+/// playlist of theirs.
+pub async fn duplicate_playlist(
+    path: web::Path<String>,
+    options: web::Query<DuplicatePlaylistOptions>,
+    state: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let source = find_playlist(&path.into_inner(), &state.db_pool).await?;
+    sharing::require_access(&user, &source, ShareAccess::Read, &state.db_pool).await?;
+
+    let name = match options.name.as_deref().map(str::trim) {
+        Some("") => {
+            return Err(AppError::Validation(
+                "Playlist name must not be empty".to_string(),
+            ))
+        }
+        Some(name) => name.to_string(),
+        None if source.user_id == user.user_id => format!("{} (copy)", source.name),
+        None => source.name.clone(),
+    };
+    let playlist = Playlist {
+        description: source.description.clone(),
+        cover_image_url: source.cover_image_url.clone(),
+        ..Playlist::new(&user.user_id, &name)
+    };
+
+    let mut tx = state.db_pool.begin().await?;
+    insert_playlist(&playlist, &mut tx).await?;
+    let items: Vec<(String, i64)> = sqlx::query_as(
+        "SELECT audio_id, position FROM playlist_items WHERE playlist_id = ? ORDER BY position",
+    )
+    .bind(&source.id)
+    .fetch_all(&mut *tx)
+    .await?;
+    // The copy's items count as added by the user making it, so other
+    // users' files in it are only streamable through the original
+    for (audio_id, position) in items {
+        sqlx::query(
+            "INSERT INTO playlist_items (id, playlist_id, audio_id, position, added_by)
+             VALUES (?, ?, ?, ?, ?)",
         )
-        .fetch_all(&state.db_pool)
-        .await
-        .map_err(|e| AppError(e.to_string()))?;
+        .bind(Uuid::new_v4().to_string())
+        .bind(&playlist.id)
+        .bind(audio_id)
+        .bind(position)
+        .bind(&user.user_id)
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
 
-        let playlist_items: Vec<PlaylistAudioItem> = items
-            .into_iter()
-            .map(|item| PlaylistAudioItem {
-                id: item.id.expect("Item ID should not be null"),
-                audio_id: item.audio_id,
-                position: item.position as i32,
-                filename: item.filename,
-                mime_type: item.mime_type,
-            })
-            .collect();
-
-        let playlist_with_items = PlaylistWithItems {
-            id: playlist.id,
-            name: playlist.name,
-            user_id: playlist.user_id,
-            created_at: playlist.created_at,
-            items: playlist_items,
-        };
+    Ok(HttpResponse::Ok().json(with_items(playlist, &state.db_pool).await?))
+}
 
-        Ok(HttpResponse::Ok().json(playlist_with_items))
+/// Lists the users and groups a playlist is shared with.
+pub async fn get_playlist_shares(
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let playlist = find_playlist(&path.into_inner(), &state.db_pool).await?;
+    require_sharer(&user, &playlist)?;
+
+    let shares = sqlx::query_as::<_, PlaylistShare>(&format!(
+        "{} WHERE s.playlist_id = ? ORDER BY s.created_at",
+        SHARE_QUERY
+    ))
+    .bind(&playlist.id)
+    .fetch_all(&state.db_pool)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(shares))
+}
+
+/// Shares a playlist with a user or group, or changes what an existing
+/// share allows.
+pub async fn share_playlist(
+    path: web::Path<String>,
+    req: web::Json<SharePlaylistRequest>,
+    state: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let playlist = find_playlist(&path.into_inner(), &state.db_pool).await?;
+    require_sharer(&user, &playlist)?;
+
+    // Each share is with exactly one user or group, and can be updated in
+    // place through the unique index on the playlist and that user or group
+    let (column, target_id) = match (&req.username, &req.group) {
+        (Some(username), None) => {
+            let user_id: String = sqlx::query_scalar("SELECT id FROM users WHERE username = ?")
+                .bind(username)
+                .fetch_optional(&state.db_pool)
+                .await?
+                .ok_or_else(|| AppError::NotFound("User not found".to_string()))?;
+            if user_id == playlist.user_id {
+                return Err(AppError::Validation(
+                    "Playlists can't be shared with their owner".to_string(),
+                ));
+            }
+            ("user_id", user_id)
+        }
+        (None, Some(group)) => {
+            let group_id: String = sqlx::query_scalar("SELECT id FROM user_groups WHERE name = ?")
+                .bind(group)
+                .fetch_optional(&state.db_pool)
+                .await?
+                .ok_or_else(|| AppError::NotFound("Group not found".to_string()))?;
+            ("group_id", group_id)
+        }
+        _ => {
+            return Err(AppError::Validation(
+                "Give either a username or a group".to_string(),
+            ))
+        }
+    };
+
+    let share_id: String = sqlx::query_scalar(&format!(
+        "INSERT INTO playlist_shares (id, playlist_id, {column}, access, created_at)
+         VALUES (?, ?, ?, ?, ?)
+         ON CONFLICT (playlist_id, {column}) DO UPDATE SET access = excluded.access
+         RETURNING id"
+    ))
+    .bind(Uuid::new_v4().to_string())
+    .bind(&playlist.id)
+    .bind(&target_id)
+    .bind(req.access)
+    .bind(Utc::now())
+    .fetch_one(&state.db_pool)
+    .await?;
+
+    let share = sqlx::query_as::<_, PlaylistShare>(&format!("{} WHERE s.id = ?", SHARE_QUERY))
+        .bind(&share_id)
+        .fetch_one(&state.db_pool)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(share))
+}
+
+pub async fn unshare_playlist(
+    path: web::Path<(String, String)>,
+    state: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let (playlist_id, share_id) = path.into_inner();
+    let playlist = find_playlist(&playlist_id, &state.db_pool).await?;
+    require_sharer(&user, &playlist)?;
+
+    let deleted = sqlx::query("DELETE FROM playlist_shares WHERE id = ? AND playlist_id = ?")
+        .bind(&share_id)
+        .bind(&playlist_id)
+        .execute(&state.db_pool)
+        .await?;
+    if deleted.rows_affected() == 0 {
+        return Err(AppError::NotFound("Share not found".to_string()));
+    }
+
+    Ok(HttpResponse::Ok().body("Share removed"))
+}
+
+/// Shares with the names of the user or group they are with.
+const SHARE_QUERY: &str = "SELECT s.id, s.playlist_id, s.user_id, u.username, s.group_id,
+            g.name AS group_name, s.access, s.created_at
+     FROM playlist_shares s
+     LEFT JOIN users u ON s.user_id = u.id
+     LEFT JOIN user_groups g ON s.group_id = g.id";
+
+/// Only a playlist's owner, or an admin, decides who it is shared with.
+fn require_sharer(user: &AuthenticatedUser, playlist: &Playlist) -> Result<(), AppError> {
+    if user.can_access(&playlist.user_id) {
+        Ok(())
     } else {
-        Err(AppError("Playlist not found".to_string()).into())
+        Err(AppError::Forbidden(
+            "Not authorized to share this playlist".to_string(),
+        ))
     }
 }
 
+async fn find_playlist(playlist_id: &str, pool: &SqlitePool) -> Result<Playlist, AppError> {
+    sqlx::query_as::<_, Playlist>("SELECT * FROM playlists WHERE id = ?")
+        .bind(playlist_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Playlist not found".to_string()))
+}
+
+/// A playlist with its items and their audio details, in order.
+async fn with_items(playlist: Playlist, pool: &SqlitePool) -> Result<PlaylistWithItems, AppError> {
+    let items = sqlx::query_as::<_, PlaylistAudioItem>(
+        "SELECT pi.id, pi.audio_id, pi.position, af.filename, af.mime_type,
+                pi.added_by, u.username AS added_by_username, af.title, af.artist, af.album, af.album_artist, af.track_number,
+                af.disc_number, af.year, af.genre, af.duration_ms, af.sample_rate,
+                af.channels, af.bitrate_kbps
+         FROM playlist_items pi
+         JOIN audio_files af ON pi.audio_id = af.id
+         LEFT JOIN users u ON pi.added_by = u.id
+         WHERE pi.playlist_id = ?
+         ORDER BY pi.position",
+    )
+    .bind(&playlist.id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(PlaylistWithItems { playlist, items })
+}
+
 pub async fn delete_playlist(
     path: web::Path<String>,
     state: web::Data<AppState>,
-    req: HttpRequest,
-) -> Result<HttpResponse, Error> {
-    let token = req
-        .headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .and_then(|s| s.strip_prefix("Bearer "))
-        .ok_or_else(|| AppError("Authentication required".to_string()))?;
-
-    let user_id = validate_token(token, &state.secret_key)
-        .await
-        .ok_or_else(|| AppError("Invalid token".to_string()))?;
-
-    // Check if user is admin
-    let is_admin = check_admin(&user_id, &state.db_pool).await?;
-
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
     let playlist_id = path.into_inner();
     let playlist = sqlx::query_as::<_, Playlist>("SELECT * FROM playlists WHERE id = ?")
         .bind(&playlist_id)
         .fetch_optional(&state.db_pool)
-        .await
-        .map_err(|e| AppError(e.to_string()))?;
+        .await?;
 
     if let Some(playlist) = playlist {
         // Check if user has access to delete this playlist
-        if playlist.user_id != user_id && !is_admin {
-            return Err(AppError("Not authorized to delete this playlist".to_string()).into());
+        if !user.can_access(&playlist.user_id) {
+            return Err(AppError::Forbidden(
+                "Not authorized to delete this playlist".to_string(),
+            ));
         }
 
-        // First delete all playlist items
-        sqlx::query!(
-            "DELETE FROM playlist_items WHERE playlist_id = ?",
-            playlist_id
-        )
-        .execute(&state.db_pool)
-        .await
-        .map_err(|e| AppError(e.to_string()))?;
-
-        // Then delete the playlist
-        sqlx::query!("DELETE FROM playlists WHERE id = ?", playlist_id)
+        // Delete the playlist; its items are removed by ON DELETE CASCADE
+        sqlx::query("DELETE FROM playlists WHERE id = ?")
+            .bind(&playlist_id)
             .execute(&state.db_pool)
-            .await
-            .map_err(|e| AppError(e.to_string()))?;
+            .await?;
 
         Ok(HttpResponse::Ok().body("Playlist deleted"))
     } else {
-        Err(AppError("Playlist not found".to_string()).into())
+        Err(AppError::NotFound("Playlist not found".to_string()))
     }
 }
 
@@ -214,235 +434,553 @@ pub async fn add_to_playlist(
     path: web::Path<String>,
     req: web::Json<AddToPlaylistRequest>,
     state: web::Data<AppState>,
-    http_req: HttpRequest,
-) -> Result<HttpResponse, Error> {
-    let token = http_req
-        .headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .and_then(|s| s.strip_prefix("Bearer "))
-        .ok_or_else(|| AppError("Authentication required".to_string()))?;
-
-    let user_id = validate_token(token, &state.secret_key)
-        .await
-        .ok_or_else(|| AppError("Invalid token".to_string()))?;
-
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
     let playlist_id = path.into_inner();
 
     // Check if playlist exists and user has access
     let playlist = sqlx::query_as::<_, Playlist>("SELECT * FROM playlists WHERE id = ?")
         .bind(&playlist_id)
         .fetch_optional(&state.db_pool)
-        .await
-        .map_err(|e| AppError(e.to_string()))?;
+        .await?;
 
     if let Some(playlist) = playlist {
-        // Check if user owns this playlist
-        if playlist.user_id != user_id {
-            return Err(AppError("Not authorized to modify this playlist".to_string()).into());
-        }
+        // Owners and users it is shared with for editing may change items
+        sharing::require_access(&user, &playlist, ShareAccess::Edit, &state.db_pool).await?;
+
+        let insert = PlaylistItemEdit::Insert {
+            audio_ids: vec![req.audio_id.clone()],
+            placement: ItemPlacement {
+                position: req.position,
+                ..ItemPlacement::default()
+            },
+        };
+        let (_, items) = edit_items(&playlist_id, &user, None, &[insert], &state.db_pool).await?;
 
-        // Check if audio file exists
-        let audio = sqlx::query!("SELECT id FROM audio_files WHERE id = ?", req.audio_id)
-            .fetch_optional(&state.db_pool)
-            .await
-            .map_err(|e| AppError(e.to_string()))?;
+        let (index, added) = items
+            .into_iter()
+            .enumerate()
+            .find(|(_, item)| item.added)
+            .ok_or_else(|| AppError::Internal("Inserted item is missing".to_string()))?;
+        let item = PlaylistItem {
+            id: added.id,
+            playlist_id,
+            audio_id: added.audio_id,
+            position: index as i32 + 1,
+        };
+
+        Ok(HttpResponse::Ok().json(item))
+    } else {
+        Err(AppError::NotFound("Playlist not found".to_string()))
+    }
+}
+
+/// Moves, inserts and removes items in one go. Positions stay numbered
+/// from 1 without gaps, and the edits are refused with a conflict if the
+/// request names a version the playlist has since moved past.
+pub async fn update_playlist_items(
+    path: web::Path<String>,
+    req: web::Json<EditPlaylistItemsRequest>,
+    state: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let playlist_id = path.into_inner();
+    let playlist = find_playlist(&playlist_id, &state.db_pool).await?;
+
+    sharing::require_access(&user, &playlist, ShareAccess::Edit, &state.db_pool).await?;
+    if req.operations.is_empty() {
+        return Err(AppError::Validation("No operations given".to_string()));
+    }
+
+    edit_items(
+        &playlist_id,
+        &user,
+        req.version,
+        &req.operations,
+        &state.db_pool,
+    )
+    .await?;
 
+    let playlist = find_playlist(&playlist_id, &state.db_pool).await?;
+    Ok(HttpResponse::Ok().json(with_items(playlist, &state.db_pool).await?))
+}
+
+/// Applies edits to a playlist's items in a single transaction, returning
+/// the playlist's new version and its items in order. Added items are
+/// recorded as added by `user`. With `expected_version`, nothing is changed
+/// unless the playlist is still at that version.
+async fn edit_items(
+    playlist_id: &str,
+    user: &AuthenticatedUser,
+    expected_version: Option<i64>,
+    edits: &[PlaylistItemEdit],
+    pool: &SqlitePool,
+) -> Result<(i64, Vec<EditedItem>), AppError> {
+    let mut tx = pool.begin().await?;
+
+    // Writing first takes the database lock, so no other edit can slip in
+    // between reading the items and writing them back
+    let version: i64 = sqlx::query_scalar(
+        "UPDATE playlists SET version = version + 1 WHERE id = ? RETURNING version",
+    )
+    .bind(playlist_id)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| AppError::NotFound("Playlist not found".to_string()))?;
+    if let Some(expected) = expected_version {
+        if expected != version - 1 {
+            return Err(AppError::Conflict(format!(
+                "Playlist has changed: it is at version {}, not {}",
+                version - 1,
+                expected
+            )));
+        }
+    }
+
+    let stored: Vec<(String, String)> = sqlx::query_as(
+        "SELECT id, audio_id FROM playlist_items WHERE playlist_id = ? ORDER BY position",
+    )
+    .bind(playlist_id)
+    .fetch_all(&mut *tx)
+    .await?;
+    let mut items: Vec<EditedItem> = stored
+        .into_iter()
+        .map(|(id, audio_id)| EditedItem::stored(id, audio_id))
+        .collect();
+    let before: Vec<String> = items.iter().map(|item| item.id.clone()).collect();
+    playlist_edit::apply(&mut items, edits)?;
+
+    for item in items.iter().filter(|item| item.added) {
+        let audio: Option<String> = sqlx::query_scalar("SELECT id FROM audio_files WHERE id = ?")
+            .bind(&item.audio_id)
+            .fetch_optional(&mut *tx)
+            .await?;
         if audio.is_none() {
-            return Err(AppError("Audio file not found".to_string()).into());
+            return Err(AppError::NotFound(format!(
+                "Audio file {} not found",
+                item.audio_id
+            )));
         }
+    }
 
-        // Determine position
-        let position = if let Some(pos) = req.position {
-            pos
-        } else {
-            // Get the highest position and add 1
-            let max_position = sqlx::query!(
-                "SELECT MAX(position) as max_pos FROM playlist_items WHERE playlist_id = ?",
-                playlist_id
+    // Removing an item closes its gap (see the playlist_items_close_gap
+    // trigger). The rest are then moved through negative positions so the
+    // unique (playlist_id, position) index holds after every statement.
+    let kept: HashSet<&str> = items.iter().map(|item| item.id.as_str()).collect();
+    for id in before.iter().filter(|id| !kept.contains(id.as_str())) {
+        sqlx::query("DELETE FROM playlist_items WHERE id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    sqlx::query("UPDATE playlist_items SET position = -position WHERE playlist_id = ?")
+        .bind(playlist_id)
+        .execute(&mut *tx)
+        .await?;
+    for (index, item) in items.iter().enumerate() {
+        let position = index as i64 + 1;
+        if item.added {
+            sqlx::query(
+                "INSERT INTO playlist_items (id, playlist_id, audio_id, position, added_by)
+                 VALUES (?, ?, ?, ?, ?)",
             )
-            .fetch_one(&state.db_pool)
-            .await
-            .map_err(|e| AppError(e.to_string()))?
-            .max_pos
-            .unwrap_or(0);
+            .bind(&item.id)
+            .bind(playlist_id)
+            .bind(&item.audio_id)
+            .bind(position)
+            .bind(&user.user_id)
+            .execute(&mut *tx)
+            .await?;
+        } else {
+            sqlx::query("UPDATE playlist_items SET position = ? WHERE id = ?")
+                .bind(position)
+                .bind(&item.id)
+                .execute(&mut *tx)
+                .await?;
+        }
+    }
 
-            (max_position + 1) as i32
-        };
+    // Removals bumped the version too; the whole edit counts once
+    sqlx::query("UPDATE playlists SET version = ?, updated_at = ? WHERE id = ?")
+        .bind(version)
+        .bind(Utc::now())
+        .bind(playlist_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
 
-        let item_id = Uuid::new_v4().to_string();
+    Ok((version, items))
+}
 
-        sqlx::query!(
-            "INSERT INTO playlist_items (id, playlist_id, audio_id, position) VALUES (?, ?, ?, ?)",
-            item_id,
-            playlist_id,
-            req.audio_id,
-            position
+/// Appends audio files, in order, to the user's playlist called `name`,
+/// creating the playlist if the user has none by that name.
+pub async fn append_to_named_playlist(
+    user_id: &str,
+    name: &str,
+    audio_ids: &[&str],
+    conn: &mut SqliteConnection,
+) -> Result<Playlist, AppError> {
+    let existing = sqlx::query_as::<_, Playlist>(
+        "SELECT * FROM playlists WHERE user_id = ? AND name = ? ORDER BY created_at LIMIT 1",
+    )
+    .bind(user_id)
+    .bind(name)
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    let created = existing.is_none();
+    let mut playlist = match existing {
+        Some(playlist) => playlist,
+        None => {
+            let playlist = Playlist::new(user_id, name);
+            insert_playlist(&playlist, conn).await?;
+            playlist
+        }
+    };
+
+    let max_position: Option<i64> =
+        sqlx::query_scalar("SELECT MAX(position) FROM playlist_items WHERE playlist_id = ?")
+            .bind(&playlist.id)
+            .fetch_one(&mut *conn)
+            .await?;
+    let mut position = max_position.unwrap_or(0);
+
+    for audio_id in audio_ids {
+        position += 1;
+        sqlx::query(
+            "INSERT INTO playlist_items (id, playlist_id, audio_id, position, added_by)
+             VALUES (?, ?, ?, ?, ?)",
         )
-        .execute(&state.db_pool)
-        .await
-        .map_err(|e| AppError(e.to_string()))?;
+        .bind(Uuid::new_v4().to_string())
+        .bind(&playlist.id)
+        .bind(audio_id)
+        .bind(position)
+        .bind(user_id)
+        .execute(&mut *conn)
+        .await?;
+    }
 
-        let item = PlaylistItem {
-            id: item_id,
-            playlist_id,
-            audio_id: req.audio_id.clone(),
-            position,
-        };
+    if !created && !audio_ids.is_empty() {
+        playlist.updated_at = Utc::now();
+        playlist.version = sqlx::query_scalar(
+            "UPDATE playlists SET version = version + 1, updated_at = ? WHERE id = ?
+             RETURNING version",
+        )
+        .bind(playlist.updated_at)
+        .bind(&playlist.id)
+        .fetch_one(&mut *conn)
+        .await?;
+    }
 
-        Ok(HttpResponse::Ok().json(item))
-    } else {
-        Err(AppError("Playlist not found".to_string()).into())
+    Ok(playlist)
+}
+
+/// Largest playlist file accepted for import; iTunes libraries run to tens
+/// of megabytes.
+const MAX_IMPORT_SIZE: usize = 64 * 1024 * 1024;
+
+/// Creates playlists from an uploaded M3U, PLS, XSPF, JSPF or iTunes
+/// library file, matching its entries to the user's audio files. Entries
+/// that match nothing are left out and reported.
+pub async fn import_playlist(
+    mut payload: Multipart,
+    options: web::Query<ImportPlaylistOptions>,
+    state: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let name = options.name.as_deref().map(str::trim);
+    if name == Some("") {
+        return Err(AppError::Validation(
+            "Playlist name must not be empty".to_string(),
+        ));
     }
+
+    let (filename, contents) = read_playlist_file(&mut payload).await?;
+    let mut playlists = playlist_formats::parse(&contents)?;
+    if let Some(wanted) = &options.playlist {
+        playlists.retain(|playlist| playlist.title.as_deref() == Some(wanted.as_str()));
+        if playlists.is_empty() {
+            return Err(AppError::NotFound(format!(
+                "No playlist named '{}' in the file",
+                wanted
+            )));
+        }
+    }
+    if playlists.is_empty() {
+        return Err(AppError::Validation(
+            "The file contains no playlists".to_string(),
+        ));
+    }
+    if name.is_some() && playlists.len() > 1 {
+        return Err(AppError::Validation(
+            "The file contains several playlists; choose one with `playlist` to name it"
+                .to_string(),
+        ));
+    }
+
+    let files = sqlx::query_as::<_, AudioFile>("SELECT * FROM audio_files WHERE user_id = ?")
+        .bind(&user.user_id)
+        .fetch_all(&state.db_pool)
+        .await?;
+    let matcher = TrackMatcher::new(&files);
+
+    // Files without a title of their own are named after the file
+    let file_stem = filename
+        .rsplit(['/', '\\'])
+        .next()
+        .and_then(|name| {
+            name.rsplit_once('.')
+                .map_or(Some(name), |(stem, _)| Some(stem))
+        })
+        .map(str::trim)
+        .filter(|stem| !stem.is_empty())
+        .unwrap_or("Imported playlist");
+
+    let mut tx = state.db_pool.begin().await?;
+    let mut imported = Vec::new();
+    for parsed in playlists {
+        let title = name.map(str::to_string).or(parsed.title);
+        let playlist = Playlist::new(&user.user_id, title.as_deref().unwrap_or(file_stem));
+        insert_playlist(&playlist, &mut tx).await?;
+
+        let mut matched = Vec::new();
+        let mut unmatched = Vec::new();
+        for (index, entry) in parsed.entries.into_iter().enumerate() {
+            match matcher.find(&entry) {
+                Some((audio, matched_by)) => {
+                    sqlx::query(
+                        "INSERT INTO playlist_items (id, playlist_id, audio_id, position, added_by)
+                         VALUES (?, ?, ?, ?, ?)",
+                    )
+                    .bind(Uuid::new_v4().to_string())
+                    .bind(&playlist.id)
+                    .bind(&audio.id)
+                    .bind(matched.len() as i64 + 1)
+                    .bind(&user.user_id)
+                    .execute(&mut *tx)
+                    .await?;
+                    matched.push(MatchedEntry {
+                        entry: index + 1,
+                        audio_id: audio.id.clone(),
+                        matched_by,
+                    });
+                }
+                None => unmatched.push(UnmatchedEntry {
+                    entry: index + 1,
+                    location: entry.location,
+                    title: entry.title,
+                    artist: entry.artist,
+                }),
+            }
+        }
+        imported.push(ImportedPlaylist {
+            playlist,
+            matched,
+            unmatched,
+        });
+    }
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(PlaylistImportResponse {
+        playlists: imported,
+    }))
+}
+
+/// Reads the first file of a multipart body, returning its name and text.
+/// Files that aren't UTF-8 are read as Latin-1, which older M3U files use.
+async fn read_playlist_file(payload: &mut Multipart) -> Result<(String, String), AppError> {
+    let mut field = payload
+        .next()
+        .await
+        .ok_or_else(|| AppError::Validation("No file uploaded".to_string()))??;
+    let filename = field
+        .content_disposition()
+        .and_then(|cd| cd.get_filename())
+        .unwrap_or("")
+        .to_string();
+
+    let mut data = Vec::new();
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk?;
+        if data.len() + chunk.len() > MAX_IMPORT_SIZE {
+            return Err(AppError::TooLarge(format!(
+                "Playlist files may be at most {} bytes",
+                MAX_IMPORT_SIZE
+            )));
+        }
+        data.extend_from_slice(&chunk);
+    }
+
+    let contents = match String::from_utf8(data) {
+        Ok(text) => text,
+        Err(e) => e.into_bytes().iter().map(|&byte| byte as char).collect(),
+    };
+    Ok((filename, contents))
 }
 
 pub async fn remove_from_playlist(
     path: web::Path<(String, String)>,
     state: web::Data<AppState>,
-    req: HttpRequest,
-) -> Result<HttpResponse, Error> {
-    let token = req
-        .headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .and_then(|s| s.strip_prefix("Bearer "))
-        .ok_or_else(|| AppError("Authentication required".to_string()))?;
-
-    let user_id = validate_token(token, &state.secret_key)
-        .await
-        .ok_or_else(|| AppError("Invalid token".to_string()))?;
-
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
     let (playlist_id, item_id) = path.into_inner();
 
     // Check if playlist exists and user has access
     let playlist = sqlx::query_as::<_, Playlist>("SELECT * FROM playlists WHERE id = ?")
         .bind(&playlist_id)
         .fetch_optional(&state.db_pool)
-        .await
-        .map_err(|e| AppError(e.to_string()))?;
+        .await?;
 
     if let Some(playlist) = playlist {
-        // Check if user owns this playlist
-        if playlist.user_id != user_id {
-            return Err(AppError("Not authorized to modify this playlist".to_string()).into());
-        }
-
-        // Check if item exists in the playlist
-        let item = sqlx::query!(
-            "SELECT id FROM playlist_items WHERE id = ? AND playlist_id = ?",
-            item_id,
-            playlist_id
-        )
-        .fetch_optional(&state.db_pool)
-        .await
-        .map_err(|e| AppError(e.to_string()))?;
-
-        if item.is_none() {
-            return Err(AppError("Item not found in playlist".to_string()).into());
-        }
+        // Owners and users it is shared with for editing may change items
+        sharing::require_access(&user, &playlist, ShareAccess::Edit, &state.db_pool).await?;
 
-        // Delete the item
-        sqlx::query!("DELETE FROM playlist_items WHERE id = ?", item_id)
-            .execute(&state.db_pool)
-            .await
-            .map_err(|e| AppError(e.to_string()))?;
+        let remove = PlaylistItemEdit::Remove {
+            item_ids: vec![item_id],
+        };
+        edit_items(&playlist_id, &user, None, &[remove], &state.db_pool).await?;
 
         Ok(HttpResponse::Ok().body("Item removed from playlist"))
     } else {
-        Err(AppError("Playlist not found".to_string()).into())
+        Err(AppError::NotFound("Playlist not found".to_string()))
     }
 }
 
-use actix_web::http::header;
-use rand::seq::SliceRandom;
-use rand::thread_rng;
-use std::io::Write;
-use tempfile::NamedTempFile;
-
 pub async fn stream_playlist(
+    req: HttpRequest,
     path: web::Path<String>,
     options: web::Query<StreamPlaylistOptions>,
     state: web::Data<AppState>,
-    req: HttpRequest,
-) -> Result<HttpResponse, Error> {
-    let token = req
-        .headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .and_then(|s| s.strip_prefix("Bearer "))
-        .ok_or_else(|| AppError("Authentication required".to_string()))?;
-
-    let user_id = validate_token(token, &state.secret_key)
-        .await
-        .ok_or_else(|| AppError("Invalid token".to_string()))?;
-
-    // Check if user is admin
-    let is_admin = check_admin(&user_id, &state.db_pool).await?;
-
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
     let playlist_id = path.into_inner();
     let playlist = sqlx::query_as::<_, Playlist>("SELECT * FROM playlists WHERE id = ?")
         .bind(&playlist_id)
         .fetch_optional(&state.db_pool)
-        .await
-        .map_err(|e| AppError(e.to_string()))?;
+        .await?;
 
     if let Some(playlist) = playlist {
         // Check if user has access to this playlist
-        if playlist.user_id != user_id && !is_admin {
-            return Err(AppError("Not authorized to access this playlist".to_string()).into());
-        }
+        sharing::require_access(&user, &playlist, ShareAccess::Read, &state.db_pool).await?;
 
-        // Get playlist items with audio details
-        let items = sqlx::query!(
-            "SELECT pi.id, pi.audio_id, pi.position, af.filename, af.mime_type, af.user_folder 
-             FROM playlist_items pi
-             JOIN audio_files af ON pi.audio_id = af.id
-             WHERE pi.playlist_id = ?
-             ORDER BY pi.position",
-            playlist_id
-        )
-        .fetch_all(&state.db_pool)
-        .await
-        .map_err(|e| AppError(e.to_string()))?;
+        // Get the playlist's files, leaving out any the user can't stream
+        let mut items = sharing::streamable_tracks(&user, &playlist_id, &state.db_pool).await?;
 
         if items.is_empty() {
-            return Err(AppError("Playlist is empty".to_string()).into());
+            return Err(AppError::Validation(
+                "Playlist has no tracks you can stream".to_string(),
+            ));
         }
 
-        // Create a list of audio files with their paths
-        let mut audio_files = items
-            .into_iter()
-            .map(|item| {
-                let filepath = format!("{}/{}_{}", item.user_folder, item.audio_id, item.filename);
-                (filepath, item.mime_type)
-            })
-            .collect::<Vec<_>>();
-
         // Shuffle the playlist if requested
         if options.shuffle {
             let mut rng = thread_rng();
-            audio_files.shuffle(&mut rng);
+            items.shuffle(&mut rng);
         }
 
-        // Create a playlist file with audio file paths
-        let mut playlist_file = NamedTempFile::new()?;
-        for (filepath, _) in &audio_files {
-            writeln!(playlist_file, "{}", filepath)?;
+        // Players fetch the tracks themselves, so each link carries a token
+        // good for that track only
+        let base_url = public_url(&req, &state);
+        let entries = items
+            .iter()
+            .map(|audio| {
+                let token = issue_stream_token(&user.user_id, &audio.id, &state.config.auth)?;
+                let location = format!("{}/audio/{}?token={}", base_url, audio.id, token);
+                Ok(PlaylistEntry::for_audio(audio, location))
+            })
+            .collect::<Result<Vec<_>, AppError>>()?;
+
+        let format = options.format;
+        Ok(HttpResponse::Ok()
+            .content_type(format.mime_type())
+            .insert_header((
+                header::CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"playlist-{}.{}\"",
+                    playlist_id,
+                    format.extension()
+                ),
+            ))
+            .body(playlist_formats::write(format, &playlist.name, &entries)))
+    } else {
+        Err(AppError::NotFound("Playlist not found".to_string()))
+    }
+}
+
+/// The URL clients reach the server at: the configured one, or else the
+/// one this request was made to.
+pub(crate) fn public_url(req: &HttpRequest, state: &AppState) -> String {
+    match &state.config.server.public_url {
+        Some(url) => url.trim_end_matches('/').to_string(),
+        None => {
+            let info = req.connection_info();
+            format!("{}://{}", info.scheme(), info.host())
         }
+    }
+}
 
-        // Create a response with the playlist file
-        let mut response = HttpResponse::Ok();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
 
-        // Set appropriate headers
-        response.append_header((header::CONTENT_TYPE, "audio/mpegurl"));
-        response.append_header((
-            header::CONTENT_DISPOSITION,
-            format!("attachment; filename=\"playlist-{}.m3u\"", playlist_id),
-        ));
+    use crate::auth::test_bearer;
 
-        // Return the playlist file
-        Ok(response.body(std::fs::read_to_string(playlist_file.path())?))
-    } else {
-        Err(AppError("Playlist not found".to_string()).into())
+    /// Bob's playlist, shared with edit access with sam.
+    async fn seed(state: &AppState) {
+        sqlx::raw_sql(
+            "INSERT INTO users (id, username, password) VALUES ('bob', 'bob', 'x'), ('sam', 'sam', 'x'), ('eve', 'eve', 'x');",
+        )
+        .execute(&state.db_pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO playlists (id, name, user_id, created_at, updated_at) VALUES ('p1', 'Mix', 'bob', ?, ?)",
+        )
+        .bind(Utc::now())
+        .bind(Utc::now())
+        .execute(&state.db_pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO playlist_shares (id, playlist_id, user_id, access, created_at) VALUES ('s1', 'p1', 'sam', 'edit', ?)",
+        )
+        .bind(Utc::now())
+        .execute(&state.db_pool)
+        .await
+        .unwrap();
+    }
+
+    #[actix_web::test]
+    async fn owner_and_admins_update_playlist_details() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = web::Data::new(AppState::for_tests(dir.path()).await);
+        seed(&state).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .route("/playlists/{id}", web::patch().to(update_playlist)),
+        )
+        .await;
+
+        for (user_id, name, expected) in [
+            ("bob", "Bob's mix", StatusCode::OK),
+            ("admin-user-id", "Tidied up", StatusCode::OK),
+            ("sam", "Sam's now", StatusCode::FORBIDDEN),
+            ("eve", "Eve's now", StatusCode::FORBIDDEN),
+        ] {
+            let req = test::TestRequest::patch()
+                .uri("/playlists/p1")
+                .insert_header((header::AUTHORIZATION, test_bearer(user_id, &state).await))
+                .set_json(serde_json::json!({ "name": name, "visibility": "public" }))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), expected, "{}", user_id);
+        }
+
+        let playlist = find_playlist("p1", &state.db_pool).await.unwrap();
+        assert_eq!(playlist.name, "Tidied up");
+        assert_eq!(playlist.user_id, "bob");
     }
 }
@@ -0,0 +1,670 @@
+//! Resumable uploads following the tus 1.0 protocol (<https://tus.io>), for
+//! large files on unreliable connections.
+//!
+//! A client creates an upload with `POST /uploads`, declaring its length,
+//! then sends the data with one or more `PATCH` requests. After a dropped
+//! connection it asks for the received offset with `HEAD` and continues
+//! from there. The data is kept in the staging directory until the last
+//! byte arrives; the file is then stored like any other upload. Unfinished
+//! uploads expire a while after their last write.
+
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use uuid::Uuid;
+
+use crate::auth::AuthenticatedUser;
+use crate::config::AppState;
+use crate::error::AppError;
+use crate::handlers::audio::{ingest_file, quota_exceeded, PartialFile};
+use crate::handlers::playlist::append_to_named_playlist;
+use crate::handlers::user::user_usage;
+use crate::models::{AudioFile, ResumableUpload};
+use crate::storage;
+
+/// The only protocol version spoken.
+pub const TUS_VERSION: &str = "1.0.0";
+
+/// Optional parts of the protocol that are supported.
+const TUS_EXTENSIONS: &str = "creation,termination,expiration";
+
+/// How often, in bytes, the progress of a `PATCH` is recorded while its body
+/// is received.
+const PROGRESS_INTERVAL: u64 = 1024 * 1024;
+
+/// Uploads currently being written to. A second `PATCH` to the same upload
+/// is refused while one is in progress rather than interleaving the data.
+#[derive(Default)]
+pub struct UploadLocks(Mutex<HashSet<String>>);
+
+struct UploadLock<'a> {
+    locks: &'a UploadLocks,
+    id: String,
+}
+
+impl UploadLocks {
+    fn acquire(&self, id: &str) -> Result<UploadLock<'_>, AppError> {
+        let mut held = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if !held.insert(id.to_string()) {
+            return Err(AppError::Conflict(
+                "Upload is in use by another request".to_string(),
+            ));
+        }
+        Ok(UploadLock {
+            locks: self,
+            id: id.to_string(),
+        })
+    }
+}
+
+impl Drop for UploadLock<'_> {
+    fn drop(&mut self) {
+        self.locks
+            .0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.id);
+    }
+}
+
+/// Where the data of upload `id` is kept while it is received.
+pub(crate) fn upload_path(upload_root: &Path, id: &str) -> PathBuf {
+    storage::staging_dir(upload_root).join(format!("{}.tus", id))
+}
+
+/// Announces the protocol version, extensions and size limit.
+pub async fn tus_options(state: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::NoContent()
+        .insert_header(("Tus-Extension", TUS_EXTENSIONS))
+        .insert_header(("Tus-Max-Size", state.config.storage.max_upload_size))
+        .finish()
+}
+
+/// Creates an upload of `Upload-Length` bytes. The optional
+/// `Upload-Metadata` may carry a `filename` (or `name`) and a `playlist` to
+/// add the finished file to.
+pub async fn create_upload(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    check_tus_version(&req)?;
+
+    if req.headers().contains_key("Upload-Defer-Length") {
+        return Err(AppError::Validation(
+            "Uploads of unknown length are not supported".to_string(),
+        ));
+    }
+    let length = numeric_header(&req, "Upload-Length")?;
+
+    // Both limits are checked up front so the client doesn't send gigabytes
+    // only to have them refused; the quota is checked again at the end.
+    // Space promised to the user's other unfinished uploads is not free.
+    let max_size = state.config.storage.max_upload_size;
+    if length > max_size {
+        return Err(AppError::TooLarge(format!(
+            "File exceeds the maximum upload size of {} bytes",
+            max_size
+        )));
+    }
+    let mut conn = state.db_pool.acquire().await?;
+    let usage = user_usage(&user.user_id, &mut conn).await?;
+    drop(conn);
+    let pending = pending_bytes(&state, &user.user_id).await?;
+    if let Some(left) = usage
+        .remaining_bytes
+        .map(|bytes| (bytes as u64).saturating_sub(pending))
+        .filter(|&left| length > left)
+    {
+        return Err(quota_exceeded(left));
+    }
+
+    let raw_metadata = match req.headers().get("Upload-Metadata") {
+        Some(value) => Some(
+            value
+                .to_str()
+                .map_err(|_| AppError::Validation("Invalid Upload-Metadata".to_string()))?
+                .to_string(),
+        ),
+        None => None,
+    };
+    let metadata = parse_metadata(raw_metadata.as_deref().unwrap_or(""))?;
+    let filename = metadata
+        .get("filename")
+        .or_else(|| metadata.get("name"))
+        .cloned()
+        .unwrap_or_default();
+    let playlist = match metadata.get("playlist").map(|name| name.trim()) {
+        Some("") => {
+            return Err(AppError::Validation(
+                "Playlist name must not be empty".to_string(),
+            ))
+        }
+        name => name.map(str::to_string),
+    };
+
+    purge_expired(&state).await?;
+
+    let now = Utc::now();
+    let upload = ResumableUpload {
+        id: Uuid::new_v4().to_string(),
+        user_id: user.user_id.clone(),
+        length: length as i64,
+        received: 0,
+        filename,
+        playlist,
+        upload_metadata: raw_metadata,
+        created_at: now,
+        expires_at: now + state.config.storage.resumable_upload_ttl(),
+    };
+
+    tokio::fs::File::create(upload_path(&state.config.storage.upload_root, &upload.id)).await?;
+    sqlx::query(
+        "INSERT INTO resumable_uploads (id, user_id, length, received, filename, playlist, upload_metadata, created_at, expires_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(&upload.id)
+    .bind(&upload.user_id)
+    .bind(upload.length)
+    .bind(upload.received)
+    .bind(&upload.filename)
+    .bind(&upload.playlist)
+    .bind(&upload.upload_metadata)
+    .bind(upload.created_at)
+    .bind(upload.expires_at)
+    .execute(&state.db_pool)
+    .await?;
+
+    Ok(HttpResponse::Created()
+        .insert_header((header::LOCATION, format!("/uploads/{}", upload.id)))
+        .insert_header(("Upload-Expires", http_date(upload.expires_at)))
+        .finish())
+}
+
+/// Reports how much of an upload has been received.
+pub async fn upload_status(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    check_tus_version(&req)?;
+    let upload = find_upload(&state, &path, &user).await?;
+
+    let mut response = HttpResponse::Ok();
+    response
+        .insert_header(("Upload-Offset", upload.received))
+        .insert_header(("Upload-Length", upload.length))
+        .insert_header(("Upload-Expires", http_date(upload.expires_at)))
+        .insert_header((header::CACHE_CONTROL, "no-store"));
+    if let Some(metadata) = &upload.upload_metadata {
+        response.insert_header(("Upload-Metadata", metadata.as_str()));
+    }
+    Ok(response.finish())
+}
+
+/// Appends the request body to an upload at `Upload-Offset`, which must be
+/// the number of bytes received so far. Once the last byte has arrived the
+/// file is stored and its id returned in `X-Audio-Id`.
+pub async fn append_upload(
+    req: HttpRequest,
+    path: web::Path<String>,
+    mut payload: web::Payload,
+    state: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    check_tus_version(&req)?;
+    let content_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    if content_type != Some("application/offset+octet-stream") {
+        return Err(AppError::UnsupportedMediaType(
+            "Content-Type must be application/offset+octet-stream".to_string(),
+        ));
+    }
+    let offset = numeric_header(&req, "Upload-Offset")?;
+
+    let (upload, _lock) = lock_upload(&state, &path, &user).await?;
+    if offset != upload.received as u64 {
+        return Err(AppError::Conflict(format!(
+            "Upload-Offset must be {}",
+            upload.received
+        )));
+    }
+
+    // Anything after the recorded offset is left over from a request that
+    // broke off before recording it, and is overwritten
+    let data_path = upload_path(&state.config.storage.upload_root, &upload.id);
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(&data_path)
+        .await?;
+    file.set_len(offset).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    // Whatever arrives before an error is kept, so the client can resume
+    // right after it. A dropped connection cancels this handler, so
+    // progress is also recorded as it goes.
+    let length = upload.length as u64;
+    let mut received = offset;
+    let mut recorded = offset;
+    let mut failure = None;
+    while let Some(chunk) = payload.next().await {
+        let data = match chunk {
+            Ok(data) => data,
+            Err(e) => {
+                failure = Some(AppError::Validation(e.to_string()));
+                break;
+            }
+        };
+        if received + data.len() as u64 > length {
+            failure = Some(AppError::TooLarge(
+                "Data exceeds the declared Upload-Length".to_string(),
+            ));
+            break;
+        }
+        if let Err(e) = file.write_all(&data).await {
+            failure = Some(e.into());
+            break;
+        }
+        received += data.len() as u64;
+
+        if received - recorded >= PROGRESS_INTERVAL {
+            file.flush().await?;
+            record_progress(&state, &upload.id, received).await?;
+            recorded = received;
+        }
+    }
+    file.flush().await?;
+    drop(file);
+
+    let expires_at = record_progress(&state, &upload.id, received).await?;
+    if let Some(e) = failure {
+        return Err(e);
+    }
+
+    let mut response = HttpResponse::NoContent();
+    response.insert_header(("Upload-Offset", received));
+    if received == length {
+        let audio_file = finish_upload(&state, &upload, data_path).await?;
+        response.insert_header(("X-Audio-Id", audio_file.id));
+    } else {
+        response.insert_header(("Upload-Expires", http_date(expires_at)));
+    }
+    Ok(response.finish())
+}
+
+/// Abandons an upload and discards the data received.
+pub async fn terminate_upload(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    check_tus_version(&req)?;
+    let (upload, _lock) = lock_upload(&state, &path, &user).await?;
+
+    sqlx::query("DELETE FROM resumable_uploads WHERE id = ?")
+        .bind(&upload.id)
+        .execute(&state.db_pool)
+        .await?;
+    let _ =
+        tokio::fs::remove_file(upload_path(&state.config.storage.upload_root, &upload.id)).await;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Stores a completely received upload. The upload is gone afterwards
+/// whether or not that succeeds: a file that fails the checks would fail
+/// them again.
+async fn finish_upload(
+    state: &AppState,
+    upload: &ResumableUpload,
+    data_path: PathBuf,
+) -> Result<AudioFile, AppError> {
+    sqlx::query("DELETE FROM resumable_uploads WHERE id = ?")
+        .bind(&upload.id)
+        .execute(&state.db_pool)
+        .await?;
+    let partial = PartialFile { path: data_path };
+
+    // The data arrived over several requests, so it is hashed in one go here
+    let hash_path = partial.path.clone();
+    let hash = web::block(move || hash_file(&hash_path)).await??;
+
+    let audio_file = ingest_file(
+        state,
+        &upload.user_id,
+        partial,
+        &upload.filename,
+        &hash,
+        upload.length as u64,
+    )
+    .await?;
+
+    if let Some(name) = &upload.playlist {
+        let mut tx = state.db_pool.begin().await?;
+        append_to_named_playlist(&upload.user_id, name, &[audio_file.id.as_str()], &mut tx).await?;
+        tx.commit().await?;
+    }
+    Ok(audio_file)
+}
+
+/// Records that `received` bytes of an upload are on disk, extending its
+/// expiry, and returns the new expiry time.
+async fn record_progress(
+    state: &AppState,
+    id: &str,
+    received: u64,
+) -> Result<DateTime<Utc>, AppError> {
+    let expires_at = Utc::now() + state.config.storage.resumable_upload_ttl();
+    sqlx::query("UPDATE resumable_uploads SET received = ?, expires_at = ? WHERE id = ?")
+        .bind(received as i64)
+        .bind(expires_at)
+        .bind(id)
+        .execute(&state.db_pool)
+        .await?;
+    Ok(expires_at)
+}
+
+/// Looks up an unexpired upload of the current user.
+async fn find_upload(
+    state: &AppState,
+    id: &str,
+    user: &AuthenticatedUser,
+) -> Result<ResumableUpload, AppError> {
+    sqlx::query_as::<_, ResumableUpload>(
+        "SELECT * FROM resumable_uploads WHERE id = ? AND user_id = ? AND expires_at > ?",
+    )
+    .bind(id)
+    .bind(&user.user_id)
+    .bind(Utc::now())
+    .fetch_optional(&state.db_pool)
+    .await?
+    .ok_or_else(|| AppError::NotFound("Upload not found".to_string()))
+}
+
+/// Locks an upload of the current user and then looks it up, so the row
+/// can't be changed by another request between reading and writing it.
+async fn lock_upload<'a>(
+    state: &'a AppState,
+    id: &str,
+    user: &AuthenticatedUser,
+) -> Result<(ResumableUpload, UploadLock<'a>), AppError> {
+    let lock = state.upload_locks.acquire(id)?;
+    let upload = find_upload(state, id, user).await?;
+    Ok((upload, lock))
+}
+
+/// Bytes reserved by a user's unfinished uploads, which count against
+/// their quota until they finish or expire.
+async fn pending_bytes(state: &AppState, user_id: &str) -> Result<u64, AppError> {
+    let pending: i64 = sqlx::query_scalar(
+        "SELECT COALESCE(SUM(length), 0) FROM resumable_uploads WHERE user_id = ? AND expires_at > ?",
+    )
+    .bind(user_id)
+    .bind(Utc::now())
+    .fetch_one(&state.db_pool)
+    .await?;
+    Ok(pending as u64)
+}
+
+/// Removes expired uploads and their data.
+async fn purge_expired(state: &AppState) -> Result<(), AppError> {
+    let expired: Vec<String> =
+        sqlx::query_scalar("DELETE FROM resumable_uploads WHERE expires_at <= ? RETURNING id")
+            .bind(Utc::now())
+            .fetch_all(&state.db_pool)
+            .await?;
+    for id in expired {
+        let _ = tokio::fs::remove_file(upload_path(&state.config.storage.upload_root, &id)).await;
+    }
+    Ok(())
+}
+
+fn check_tus_version(req: &HttpRequest) -> Result<(), AppError> {
+    match req
+        .headers()
+        .get("Tus-Resumable")
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(TUS_VERSION) => Ok(()),
+        _ => Err(AppError::PreconditionFailed(format!(
+            "Tus-Resumable must be {}",
+            TUS_VERSION
+        ))),
+    }
+}
+
+fn numeric_header(req: &HttpRequest, name: &str) -> Result<u64, AppError> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| AppError::Validation(format!("A numeric {} header is required", name)))
+}
+
+/// Parses `Upload-Metadata`: comma-separated pairs of a key and a base64
+/// value, the value being optional.
+fn parse_metadata(header: &str) -> Result<HashMap<String, String>, AppError> {
+    let invalid = || AppError::Validation("Invalid Upload-Metadata".to_string());
+
+    let mut metadata = HashMap::new();
+    for pair in header
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+    {
+        let (key, value) = match pair.split_once(' ') {
+            Some((key, encoded)) => {
+                let decoded = BASE64.decode(encoded.trim()).map_err(|_| invalid())?;
+                (key, String::from_utf8(decoded).map_err(|_| invalid())?)
+            }
+            None => (pair, String::new()),
+        };
+        if metadata.insert(key.to_string(), value).is_some() {
+            return Err(invalid());
+        }
+    }
+    Ok(metadata)
+}
+
+fn hash_file(path: &Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Formats a time as an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::App;
+
+    use crate::auth::test_bearer;
+
+    #[test]
+    fn metadata_values_are_base64() {
+        let metadata =
+            parse_metadata("filename MDEgSW50cm8uZmxhYw==, playlist Um9hZCBUcmlw,is_confidential")
+                .unwrap();
+        assert_eq!(metadata["filename"], "01 Intro.flac");
+        assert_eq!(metadata["playlist"], "Road Trip");
+        assert_eq!(metadata["is_confidential"], "");
+        assert!(parse_metadata("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_metadata_is_rejected() {
+        assert!(parse_metadata("filename not*base64").is_err());
+        assert!(parse_metadata("name YQ==,name Yg==").is_err());
+    }
+
+    #[test]
+    fn http_dates() {
+        let time = DateTime::parse_from_rfc3339("1994-11-06T08:49:37Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+    }
+
+    /// Creates an upload of `length` bytes and returns its id, or the
+    /// status it was refused with.
+    async fn create_id(state: &web::Data<AppState>, length: u64) -> Result<String, StatusCode> {
+        let app = init_service(
+            App::new()
+                .app_data(state.clone())
+                .route("/uploads", web::post().to(create_upload)),
+        )
+        .await;
+        let req = TestRequest::post()
+            .uri("/uploads")
+            .insert_header((
+                header::AUTHORIZATION,
+                test_bearer("admin-user-id", state).await,
+            ))
+            .insert_header(("Tus-Resumable", TUS_VERSION))
+            .insert_header(("Upload-Length", length.to_string()))
+            .to_request();
+        let response = call_service(&app, req).await;
+        match response.status() {
+            StatusCode::CREATED => {
+                let location = response
+                    .headers()
+                    .get(header::LOCATION)
+                    .unwrap()
+                    .to_str()
+                    .unwrap();
+                Ok(location.trim_start_matches("/uploads/").to_string())
+            }
+            status => Err(status),
+        }
+    }
+
+    async fn append(
+        state: &web::Data<AppState>,
+        id: &str,
+        offset: u64,
+        data: &'static [u8],
+    ) -> StatusCode {
+        let app = init_service(
+            App::new()
+                .app_data(state.clone())
+                .route("/uploads/{id}", web::patch().to(append_upload)),
+        )
+        .await;
+        let req = TestRequest::patch()
+            .uri(&format!("/uploads/{}", id))
+            .insert_header((
+                header::AUTHORIZATION,
+                test_bearer("admin-user-id", state).await,
+            ))
+            .insert_header(("Tus-Resumable", TUS_VERSION))
+            .insert_header((header::CONTENT_TYPE, "application/offset+octet-stream"))
+            .insert_header(("Upload-Offset", offset.to_string()))
+            .set_payload(data)
+            .to_request();
+        call_service(&app, req).await.status()
+    }
+
+    #[actix_web::test]
+    async fn unfinished_uploads_count_against_the_quota() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = web::Data::new(AppState::for_tests(dir.path()).await);
+        sqlx::query("UPDATE users SET quota_bytes = 10 WHERE id = 'admin-user-id'")
+            .execute(&state.db_pool)
+            .await
+            .unwrap();
+
+        let first = create_id(&state, 6).await.unwrap();
+        assert_eq!(
+            create_id(&state, 6).await,
+            Err(StatusCode::PAYLOAD_TOO_LARGE)
+        );
+        assert!(create_id(&state, 4).await.is_ok());
+        assert_eq!(
+            create_id(&state, 1).await,
+            Err(StatusCode::PAYLOAD_TOO_LARGE)
+        );
+
+        // Abandoning an upload frees its space again
+        sqlx::query("DELETE FROM resumable_uploads WHERE id = ?")
+            .bind(&first)
+            .execute(&state.db_pool)
+            .await
+            .unwrap();
+        assert!(create_id(&state, 6).await.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn stale_or_concurrent_appends_keep_received_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = web::Data::new(AppState::for_tests(dir.path()).await);
+        let id = create_id(&state, 10).await.unwrap();
+        let data_path = upload_path(&state.config.storage.upload_root, &id);
+
+        assert_eq!(
+            append(&state, &id, 0, b"0123").await,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(append(&state, &id, 0, b"xx").await, StatusCode::CONFLICT);
+        assert_eq!(std::fs::read(&data_path).unwrap(), b"0123");
+
+        {
+            let _busy = state.upload_locks.acquire(&id).unwrap();
+            assert_eq!(append(&state, &id, 4, b"45").await, StatusCode::CONFLICT);
+        }
+        assert_eq!(std::fs::read(&data_path).unwrap(), b"0123");
+        assert_eq!(append(&state, &id, 4, b"45").await, StatusCode::NO_CONTENT);
+        assert_eq!(std::fs::read(&data_path).unwrap(), b"012345");
+    }
+
+    async fn create(state: &web::Data<AppState>, length: u64) -> StatusCode {
+        match create_id(state, length).await {
+            Ok(_) => StatusCode::CREATED,
+            Err(status) => status,
+        }
+    }
+
+    #[actix_web::test]
+    async fn upload_over_quota_is_too_large() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = web::Data::new(AppState::for_tests(dir.path()).await);
+        sqlx::query("UPDATE users SET quota_bytes = 5 WHERE id = 'admin-user-id'")
+            .execute(&state.db_pool)
+            .await
+            .unwrap();
+
+        assert_eq!(create(&state, 10).await, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(create(&state, 5).await, StatusCode::CREATED);
+    }
+
+    #[actix_web::test]
+    async fn no_quota_allows_up_to_the_maximum_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = web::Data::new(AppState::for_tests(dir.path()).await);
+        let max_size = state.config.storage.max_upload_size;
+
+        assert_eq!(create(&state, max_size).await, StatusCode::CREATED);
+        assert_eq!(
+            create(&state, max_size + 1).await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+}
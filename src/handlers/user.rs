@@ -1,69 +1,54 @@
-use actix_web::{web, Error, HttpRequest, HttpResponse};
-use sqlx::Row;
-use std::fs;
+use actix_web::{web, HttpResponse};
+use sqlx::{Row, SqliteConnection};
 use uuid::Uuid;
 
-use crate::auth::{check_admin, validate_token};
+use crate::auth::{hash_password, revoke_user_tokens, AdminUser, AuthenticatedUser};
 use crate::config::AppState;
 use crate::error::AppError;
-use crate::models::{CreateUserRequest, UserResponse};
+use crate::handlers::tus::upload_path;
+use crate::models::{
+    CreateUserRequest, SetQuotaRequest, TranscodingPreferences, UsageResponse, UserResponse,
+};
+use crate::storage;
+use crate::transcode::{TranscodeFormat, TranscodeOptions};
 
 pub async fn create_user(
     req: web::Json<CreateUserRequest>,
     state: web::Data<AppState>,
-    http_req: HttpRequest,
-) -> Result<HttpResponse, Error> {
-    let token = http_req
-        .headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .and_then(|s| s.strip_prefix("Bearer "))
-        .ok_or_else(|| AppError("Authentication required".to_string()))?;
-
-    let user_id = validate_token(token, &state.secret_key)
-        .await
-        .ok_or_else(|| AppError("Invalid token".to_string()))?;
-
-    // Check if user is admin
-    let is_admin = check_admin(&user_id, &state.db_pool).await?;
-    if !is_admin {
-        return Err(AppError("Only admin users can create new users".to_string()).into());
-    }
-
+    _admin: AdminUser,
+) -> Result<HttpResponse, AppError> {
     // Check if username already exists
     let existing_user = sqlx::query("SELECT id FROM users WHERE username = ?")
         .bind(&req.username)
         .fetch_optional(&state.db_pool)
-        .await
-        .map_err(|e| AppError(e.to_string()))?;
+        .await?;
 
     if existing_user.is_some() {
-        return Err(AppError("Username already exists".to_string()).into());
+        return Err(AppError::Conflict("Username already exists".to_string()));
     }
 
+    validate_quota(req.quota_bytes)?;
     let new_user_id = Uuid::new_v4().to_string();
-
-    // Create user folder
-    let user_folder = format!("./uploads/{}", new_user_id);
-    fs::create_dir_all(&user_folder)?;
+    let password_hash = hash_password(&req.password)?;
 
     // Create user in database
     sqlx::query(
-        "INSERT INTO users (id, username, password, is_admin, created_at) VALUES (?, ?, ?, ?, ?)",
+        "INSERT INTO users (id, username, password, is_admin, created_at, quota_bytes) VALUES (?, ?, ?, ?, ?, ?)",
     )
     .bind(&new_user_id)
     .bind(&req.username)
-    .bind(&req.password)
+    .bind(&password_hash)
     .bind(req.is_admin)
     .bind(chrono::Utc::now())
+    .bind(req.quota_bytes)
     .execute(&state.db_pool)
-    .await
-    .map_err(|e| AppError(e.to_string()))?;
+    .await?;
 
     let user_response = UserResponse {
         id: new_user_id,
         username: req.username.clone(),
         is_admin: req.is_admin,
+        quota_bytes: req.quota_bytes,
     };
 
     Ok(HttpResponse::Ok().json(user_response))
@@ -72,150 +57,81 @@ pub async fn create_user(
 pub async fn delete_user(
     path: web::Path<String>,
     state: web::Data<AppState>,
-    req: HttpRequest,
-) -> Result<HttpResponse, Error> {
-    let token = req
-        .headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .and_then(|s| s.strip_prefix("Bearer "))
-        .ok_or_else(|| AppError("Authentication required".to_string()))?;
-
-    let admin_id = validate_token(token, &state.secret_key)
-        .await
-        .ok_or_else(|| AppError("Invalid token".to_string()))?;
-
-    // Check if user is admin
-    let is_admin = check_admin(&admin_id, &state.db_pool).await?;
-    if !is_admin {
-        return Err(AppError("Only admin users can delete users".to_string()).into());
-    }
-
+    AdminUser(admin): AdminUser,
+) -> Result<HttpResponse, AppError> {
     let user_id = path.into_inner();
 
     // Prevent admin from deleting their own account
-    if user_id == admin_id {
-        return Err(AppError("Cannot delete your own account".to_string()).into());
+    if user_id == admin.user_id {
+        return Err(AppError::Validation(
+            "Cannot delete your own account".to_string(),
+        ));
     }
 
     // Check if user exists
     let user = sqlx::query("SELECT id FROM users WHERE id = ?")
         .bind(&user_id)
         .fetch_optional(&state.db_pool)
-        .await
-        .map_err(|e| AppError(e.to_string()))?;
+        .await?;
 
     if user.is_none() {
-        return Err(AppError("User not found".to_string()).into());
+        return Err(AppError::NotFound("User not found".to_string()));
     }
 
     // Start transaction
-    let mut tx = state
-        .db_pool
-        .begin()
-        .await
-        .map_err(|e| AppError(e.to_string()))?;
+    let mut tx = state.db_pool.begin().await?;
 
-    // Get all audio files for this user
-    let audio_files =
-        sqlx::query("SELECT id, user_folder, filename FROM audio_files WHERE user_id = ?")
-            .bind(&user_id)
-            .fetch_all(&mut *tx)
-            .await
-            .map_err(|e| AppError(e.to_string()))?;
-
-    // Delete playlist items that reference this user's audio files
-    for audio in &audio_files {
-        sqlx::query("DELETE FROM playlist_items WHERE audio_id = ?")
-            .bind(audio.get::<String, _>("id"))
-            .execute(&mut *tx)
-            .await
-            .map_err(|e| AppError(e.to_string()))?;
-    }
-
-    // Delete all audio files from the database
-    sqlx::query("DELETE FROM audio_files WHERE user_id = ?")
-        .bind(&user_id)
-        .execute(&mut *tx)
-        .await
-        .map_err(|e| AppError(e.to_string()))?;
-
-    // Delete playlist items in this user's playlists
-    let playlists = sqlx::query("SELECT id FROM playlists WHERE user_id = ?")
+    let audio_ids: Vec<String> = sqlx::query_scalar("SELECT id FROM audio_files WHERE user_id = ?")
         .bind(&user_id)
         .fetch_all(&mut *tx)
-        .await
-        .map_err(|e| AppError(e.to_string()))?;
+        .await?;
 
-    for playlist in &playlists {
-        sqlx::query("DELETE FROM playlist_items WHERE playlist_id = ?")
-            .bind(playlist.get::<String, _>("id"))
-            .execute(&mut *tx)
-            .await
-            .map_err(|e| AppError(e.to_string()))?;
-    }
+    // Revoke outstanding access tokens before the sessions are removed
+    revoke_user_tokens(&user_id, state.config.auth.access_token_ttl(), &mut tx).await?;
 
-    // Delete all playlists
-    sqlx::query("DELETE FROM playlists WHERE user_id = ?")
-        .bind(&user_id)
-        .execute(&mut *tx)
-        .await
-        .map_err(|e| AppError(e.to_string()))?;
+    let unfinished_uploads: Vec<String> =
+        sqlx::query_scalar("DELETE FROM resumable_uploads WHERE user_id = ? RETURNING id")
+            .bind(&user_id)
+            .fetch_all(&mut *tx)
+            .await?;
 
-    // Delete the user
+    // Delete the user; audio files, playlists, playlist items and refresh
+    // tokens are removed by ON DELETE CASCADE
     sqlx::query("DELETE FROM users WHERE id = ?")
         .bind(&user_id)
         .execute(&mut *tx)
-        .await
-        .map_err(|e| AppError(e.to_string()))?;
+        .await?;
 
     // Commit transaction
-    tx.commit().await.map_err(|e| AppError(e.to_string()))?;
+    tx.commit().await?;
 
-    // Delete audio files from filesystem
-    for audio in audio_files {
-        let filepath = format!(
-            "{}/{}_{}",
-            audio.get::<String, _>("user_folder"),
-            audio.get::<String, _>("id"),
-            audio.get::<String, _>("filename")
-        );
-        let _ = fs::remove_file(filepath); // Ignore errors if file doesn't exist
+    for id in audio_ids {
+        state.transcode_cache.invalidate(&id).await;
+    }
+    for id in unfinished_uploads {
+        let _ = tokio::fs::remove_file(upload_path(&state.config.storage.upload_root, &id)).await;
     }
 
-    // Delete user folder
-    let user_folder = format!("./uploads/{}", user_id);
-    let _ = fs::remove_dir_all(user_folder); // Ignore errors if directory doesn't exist
+    // Delete files stored per user before deduplication, then any blobs
+    // only this user referred to
+    if let Ok(prefix) = storage::user_prefix(&user_id) {
+        for object in state.storage.list(prefix).await? {
+            let _ = state.storage.delete(&object.key).await; // Ignore files already gone
+        }
+    }
+    state.blobs.collect_garbage(&state.db_pool).await?;
 
     Ok(HttpResponse::Ok().body("User deleted"))
 }
 
 pub async fn list_users(
     state: web::Data<AppState>,
-    req: HttpRequest,
-) -> Result<HttpResponse, Error> {
-    let token = req
-        .headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .and_then(|s| s.strip_prefix("Bearer "))
-        .ok_or_else(|| AppError("Authentication required".to_string()))?;
-
-    let user_id = validate_token(token, &state.secret_key)
-        .await
-        .ok_or_else(|| AppError("Invalid token".to_string()))?;
-
-    // Check if user is admin
-    let is_admin = check_admin(&user_id, &state.db_pool).await?;
-    if !is_admin {
-        return Err(AppError("Only admin users can list all users".to_string()).into());
-    }
-
+    _admin: AdminUser,
+) -> Result<HttpResponse, AppError> {
     // Get all users
-    let users = sqlx::query("SELECT id, username, is_admin FROM users")
+    let users = sqlx::query("SELECT id, username, is_admin, quota_bytes FROM users")
         .fetch_all(&state.db_pool)
-        .await
-        .map_err(|e| AppError(e.to_string()))?;
+        .await?;
 
     let user_responses: Vec<UserResponse> = users
         .into_iter()
@@ -225,9 +141,268 @@ pub async fn list_users(
                 id: row.get("id"),
                 username: row.get("username"),
                 is_admin: row.get("is_admin"),
+                quota_bytes: row.get("quota_bytes"),
             }
         })
         .collect();
 
     Ok(HttpResponse::Ok().json(user_responses))
 }
+
+/// Sets or clears (with `null`) a user's storage quota. Lowering a quota
+/// below current usage keeps existing files but blocks further uploads.
+pub async fn set_user_quota(
+    path: web::Path<String>,
+    req: web::Json<SetQuotaRequest>,
+    state: web::Data<AppState>,
+    _admin: AdminUser,
+) -> Result<HttpResponse, AppError> {
+    let user_id = path.into_inner();
+    validate_quota(req.quota_bytes)?;
+
+    let updated = sqlx::query("UPDATE users SET quota_bytes = ? WHERE id = ?")
+        .bind(req.quota_bytes)
+        .bind(&user_id)
+        .execute(&state.db_pool)
+        .await?;
+    if updated.rows_affected() == 0 {
+        return Err(AppError::NotFound("User not found".to_string()));
+    }
+
+    let mut conn = state.db_pool.acquire().await?;
+    Ok(HttpResponse::Ok().json(user_usage(&user_id, &mut conn).await?))
+}
+
+pub async fn get_user_usage(
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let user_id = path.into_inner();
+    if !user.can_access(&user_id) {
+        return Err(AppError::Forbidden(
+            "Not authorized to view this user's usage".to_string(),
+        ));
+    }
+
+    let mut conn = state.db_pool.acquire().await?;
+    Ok(HttpResponse::Ok().json(user_usage(&user_id, &mut conn).await?))
+}
+
+pub async fn get_transcoding_preferences(
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let user_id = path.into_inner();
+    if !user.can_access(&user_id) {
+        return Err(AppError::Forbidden(
+            "Not authorized to view this user's preferences".to_string(),
+        ));
+    }
+
+    let mut conn = state.db_pool.acquire().await?;
+    Ok(HttpResponse::Ok().json(transcoding_preferences(&user_id, &mut conn).await?))
+}
+
+/// Sets the format and bitrate a user's streams are transcoded to unless a
+/// request asks otherwise; a `null` format streams original files.
+pub async fn set_transcoding_preferences(
+    path: web::Path<String>,
+    req: web::Json<TranscodingPreferences>,
+    state: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let user_id = path.into_inner();
+    if !user.can_access(&user_id) {
+        return Err(AppError::Forbidden(
+            "Not authorized to change this user's preferences".to_string(),
+        ));
+    }
+
+    match req.format {
+        Some(format) => {
+            TranscodeOptions::new(format, req.bitrate_kbps)?;
+        }
+        None if req.bitrate_kbps.is_some() => {
+            return Err(AppError::Validation("A bitrate needs a format".to_string()))
+        }
+        None => {}
+    }
+
+    let updated = sqlx::query(
+        "UPDATE users SET transcode_format = ?, transcode_bitrate_kbps = ? WHERE id = ?",
+    )
+    .bind(req.format.map(TranscodeFormat::name))
+    .bind(req.bitrate_kbps)
+    .bind(&user_id)
+    .execute(&state.db_pool)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Err(AppError::NotFound("User not found".to_string()));
+    }
+
+    Ok(HttpResponse::Ok().json(req.into_inner()))
+}
+
+pub async fn transcoding_preferences(
+    user_id: &str,
+    conn: &mut SqliteConnection,
+) -> Result<TranscodingPreferences, AppError> {
+    let (format, bitrate_kbps) = sqlx::query_as::<_, (Option<String>, Option<u32>)>(
+        "SELECT transcode_format, transcode_bitrate_kbps FROM users WHERE id = ?",
+    )
+    .bind(user_id)
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or_else(|| AppError::NotFound("User not found".to_string()))?;
+
+    Ok(TranscodingPreferences {
+        format: format.as_deref().and_then(TranscodeFormat::parse),
+        bitrate_kbps,
+    })
+}
+
+/// Bytes and files a user has stored, measured against their quota. Each
+/// distinct contents a user's files refer to counts once at its full size,
+/// whether or not other users' files share it, so users can't avoid their
+/// quotas by uploading the same files.
+pub async fn user_usage(
+    user_id: &str,
+    conn: &mut SqliteConnection,
+) -> Result<UsageResponse, AppError> {
+    let quota_bytes: Option<i64> = sqlx::query_scalar("SELECT quota_bytes FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or_else(|| AppError::NotFound("User not found".to_string()))?;
+
+    let used_bytes: i64 = sqlx::query_scalar(
+        "SELECT COALESCE(SUM(size), 0) FROM (
+             SELECT size FROM audio_files WHERE user_id = ? AND blob_hash IS NULL
+             UNION ALL
+             SELECT size FROM blobs
+             WHERE hash IN (SELECT blob_hash FROM audio_files WHERE user_id = ?)
+         )",
+    )
+    .bind(user_id)
+    .bind(user_id)
+    .fetch_one(&mut *conn)
+    .await?;
+    let file_count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM audio_files WHERE user_id = ?")
+        .bind(user_id)
+        .fetch_one(&mut *conn)
+        .await?;
+
+    Ok(UsageResponse {
+        user_id: user_id.to_string(),
+        used_bytes,
+        file_count,
+        quota_bytes,
+        remaining_bytes: quota_bytes.map(|quota| (quota - used_bytes).max(0)),
+    })
+}
+
+fn validate_quota(quota_bytes: Option<i64>) -> Result<(), AppError> {
+    if quota_bytes.is_some_and(|quota| quota < 0) {
+        return Err(AppError::Validation(
+            "Quota must not be negative".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use sqlx::SqlitePool;
+
+    use crate::db;
+
+    async fn add_user(pool: &SqlitePool, id: &str, quota_bytes: Option<i64>) {
+        sqlx::query(
+            "INSERT INTO users (id, username, password, quota_bytes) VALUES (?, ?, 'x', ?)",
+        )
+        .bind(id)
+        .bind(id)
+        .bind(quota_bytes)
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    /// Adds a file of `size` bytes, stored in the blob `hash` or, without
+    /// one, as a file from before deduplication.
+    async fn add_file(pool: &SqlitePool, id: &str, user_id: &str, size: i64, hash: Option<&str>) {
+        if let Some(hash) = hash {
+            sqlx::query(
+                "INSERT OR IGNORE INTO blobs (hash, storage_path, size, created_at) VALUES (?, ?, ?, ?)",
+            )
+            .bind(hash)
+            .bind(format!("blobs/{}.mp3", hash))
+            .bind(size)
+            .bind(Utc::now())
+            .execute(pool)
+            .await
+            .unwrap();
+        }
+        sqlx::query(
+            "INSERT INTO audio_files (id, filename, user_id, created_at, mime_type, size, user_folder, blob_hash) VALUES (?, 'a.mp3', ?, ?, 'audio/mpeg', ?, ?, ?)",
+        )
+        .bind(id)
+        .bind(user_id)
+        .bind(Utc::now())
+        .bind(size)
+        .bind(user_id)
+        .bind(hash)
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    async fn usage(pool: &SqlitePool, user_id: &str) -> UsageResponse {
+        let mut conn = pool.acquire().await.unwrap();
+        user_usage(user_id, &mut conn).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn shared_contents_count_for_every_user() {
+        let pool = db::memory_pool().await;
+        add_user(&pool, "bob", Some(1000)).await;
+        add_user(&pool, "alice", None).await;
+
+        add_file(&pool, "b1", "bob", 100, Some("h1")).await;
+        add_file(&pool, "b2", "bob", 100, Some("h1")).await;
+        add_file(&pool, "b3", "bob", 200, Some("h2")).await;
+        add_file(&pool, "b4", "bob", 7, None).await;
+        add_file(&pool, "a1", "alice", 200, Some("h2")).await;
+
+        let bob = usage(&pool, "bob").await;
+        // h1 once, h2 and the legacy file
+        assert_eq!(bob.used_bytes, 307);
+        assert_eq!(bob.file_count, 4);
+        assert_eq!(bob.remaining_bytes, Some(693));
+        assert_eq!(usage(&pool, "alice").await.used_bytes, 200);
+
+        // Nor does bob's charge depend on whether alice keeps her copy
+        sqlx::query("DELETE FROM audio_files WHERE id = 'a1'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(usage(&pool, "bob").await.used_bytes, 307);
+        assert_eq!(usage(&pool, "alice").await.used_bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn no_quota_means_no_limit() {
+        let pool = db::memory_pool().await;
+        add_user(&pool, "bob", None).await;
+        add_file(&pool, "b1", "bob", 1 << 40, None).await;
+
+        let bob = usage(&pool, "bob").await;
+        assert_eq!(bob.used_bytes, 1 << 40);
+        assert_eq!(bob.quota_bytes, None);
+        assert_eq!(bob.remaining_bytes, None);
+    }
+}
@@ -1,8 +1,17 @@
 pub mod auth;
+pub mod blobs;
 pub mod config;
+pub mod db;
 pub mod error;
 pub mod handlers;
+pub mod metadata;
 pub mod models;
+pub mod playlist_edit;
+pub mod playlist_formats;
+pub mod sharing;
+pub mod storage;
+pub mod track_matcher;
+pub mod transcode;
 pub mod utils;
 
 // Re-export commonly used items
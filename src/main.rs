@@ -1,82 +1,227 @@
+use actix_web::http::{header, Method};
 use actix_web::web::ServiceConfig;
-use actix_web::{middleware, web, App, HttpServer};
+use actix_web::{middleware, web, App, HttpRequest, HttpResponse, HttpServer};
+use clap::Parser;
 use dotenv::dotenv;
-use sqlx::sqlite::SqlitePoolOptions;
-use std::env;
 use std::fs;
+use std::sync::Arc;
 
 mod auth;
+mod blobs;
 mod config;
+mod db;
 mod error;
 mod handlers;
+mod metadata;
 mod models;
+mod playlist_edit;
+mod playlist_formats;
+mod sharing;
+mod storage;
+mod track_matcher;
+mod transcode;
+mod utils;
 
-use crate::auth::login;
-use crate::config::{ensure_ssl_cert_exists, init_db, AppState};
+use crate::auth::{login, logout, refresh_token};
+use crate::blobs::BlobStore;
+use crate::config::{load_rustls_config, AppState, Cli, Config};
+use crate::db::init_db;
+use crate::handlers::tus::UploadLocks;
 use crate::handlers::*;
+use crate::transcode::{TranscodeCache, Transcoder};
+use crate::utils::cert::default_subject_alt_names;
+use crate::utils::{ensure_ssl_cert_exists, CertOptions};
+
+/// Sends plain HTTP clients to the same path on the HTTPS listener.
+async fn redirect_to_https(req: HttpRequest, https_port: u16) -> HttpResponse {
+    let host = req.connection_info().host().to_string();
+    let hostname = host
+        .rsplit_once(':')
+        .filter(|(_, port)| port.chars().all(|c| c.is_ascii_digit()))
+        .map_or(host.as_str(), |(name, _)| name);
+    let location = format!("https://{}:{}{}", hostname, https_port, req.uri());
+
+    HttpResponse::PermanentRedirect()
+        .insert_header((header::LOCATION, location))
+        .finish()
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     // Load environment variables from .env file
     dotenv().ok();
 
-    // Create uploads directory if it doesn't exist
-    fs::create_dir_all("./uploads")?;
+    // Load configuration from config.toml, environment and flags
+    let config = Config::load(Cli::parse())?;
 
-    // Generate SSL certificates if they don't exist
-    ensure_ssl_cert_exists()?;
+    // Create the upload staging directory and open the storage backend
+    fs::create_dir_all(storage::staging_dir(&config.storage.upload_root))?;
+    let storage = storage::from_config(&config.storage)?;
+    let transcode_cache =
+        TranscodeCache::open(&config.transcoding.cache_dir, config.transcoding.cache_size)?;
 
-    // Get secret key from environment variable or use a default
-    let secret_key = env::var("SECRET_KEY").unwrap_or_else(|_| "your_secret_key".to_string());
+    // Generate SSL certificates if they don't exist or are about to expire
+    let mut cert_options = CertOptions {
+        cert_path: config.tls.cert_path.clone(),
+        key_path: config.tls.key_path.clone(),
+        subject_alt_names: default_subject_alt_names(),
+        ..CertOptions::default()
+    };
+    cert_options
+        .subject_alt_names
+        .extend(config.tls.subject_alt_names.iter().cloned());
+    ensure_ssl_cert_exists(&cert_options)?;
 
     // Set up database connection pool
-    let db_pool = SqlitePoolOptions::new()
-        .max_connections(5)
-        .connect("sqlite:audio.db")
+    let db_pool = db::connect(&config.database)
         .await
-        .expect("Failed to create pool");
+        .map_err(|e| std::io::Error::other(format!("Failed to open database: {}", e)))?;
 
-    // Initialize database tables
+    // Apply pending schema migrations
     init_db(&db_pool)
         .await
-        .expect("Failed to initialize database");
+        .map_err(|e| std::io::Error::other(format!("Failed to initialize database: {}", e)))?;
+
+    let tls_config = load_rustls_config(&config.tls.cert_path, &config.tls.key_path)?;
+    let https_addr = config.server.bind.clone();
+    let https_port = https_addr
+        .parse::<std::net::SocketAddr>()
+        .map(|addr| addr.port())
+        .unwrap_or(443);
+    let http_redirect_addr = config.server.http_redirect_bind.clone();
 
     // Create the app state
     let app_state = web::Data::new(AppState {
         db_pool,
-        secret_key,
+        transcoder: Transcoder::new(&config.transcoding),
+        transcode_cache: Arc::new(transcode_cache),
+        config,
+        blobs: BlobStore::new(storage.clone()),
+        storage,
+        upload_locks: UploadLocks::default(),
     });
 
     // Configure routes
     let app_config = move |cfg: &mut ServiceConfig| {
         cfg.app_data(app_state.clone())
             .route("/login", web::post().to(login))
+            .route("/logout", web::post().to(logout))
+            .route("/token/refresh", web::post().to(refresh_token))
             .route("/audio", web::post().to(upload_audio))
+            .service(
+                web::scope("/uploads")
+                    .wrap(
+                        middleware::DefaultHeaders::new()
+                            .add(("Tus-Resumable", TUS_VERSION))
+                            .add(("Tus-Version", TUS_VERSION)),
+                    )
+                    .route("", web::method(Method::OPTIONS).to(tus_options))
+                    .route("", web::post().to(create_upload))
+                    .route("/{id}", web::head().to(upload_status))
+                    .route("/{id}", web::patch().to(append_upload))
+                    .route("/{id}", web::delete().to(terminate_upload)),
+            )
             .route("/audio/{id}", web::get().to(stream_audio))
             .route("/audio/{id}", web::delete().to(delete_audio))
+            .route("/audio/{id}/hls/link", web::get().to(audio_hls_link))
+            .route(
+                "/audio/{id}/hls/index.m3u8",
+                web::get().to(audio_hls_master),
+            )
+            .route(
+                "/audio/{id}/hls/{bitrate}/index.m3u8",
+                web::get().to(audio_hls_variant),
+            )
+            .route(
+                "/audio/{id}/hls/{bitrate}/{segment:\\d+}.ts",
+                web::get().to(audio_hls_segment),
+            )
             .route("/users/{id}/audio", web::get().to(get_user_audio))
             .route("/playlists", web::post().to(create_playlist))
             .route("/playlists", web::get().to(get_playlists))
+            .route("/playlists/import", web::post().to(import_playlist))
             .route("/playlists/{id}", web::get().to(get_playlist))
+            .route("/playlists/{id}", web::patch().to(update_playlist))
             .route("/playlists/{id}", web::delete().to(delete_playlist))
+            .route(
+                "/playlists/{id}/duplicate",
+                web::post().to(duplicate_playlist),
+            )
+            .route("/playlists/{id}/shares", web::get().to(get_playlist_shares))
+            .route("/playlists/{id}/shares", web::put().to(share_playlist))
+            .route(
+                "/playlists/{id}/shares/{share_id}",
+                web::delete().to(unshare_playlist),
+            )
             .route("/playlists/{id}/items", web::post().to(add_to_playlist))
+            .route(
+                "/playlists/{id}/items",
+                web::patch().to(update_playlist_items),
+            )
             .route(
                 "/playlists/{id}/items/{item_id}",
                 web::delete().to(remove_from_playlist),
             )
             .route("/playlists/{id}/stream", web::get().to(stream_playlist))
+            .route("/playlists/{id}/hls/link", web::get().to(playlist_hls_link))
+            .route(
+                "/playlists/{id}/hls/index.m3u8",
+                web::get().to(playlist_hls_master),
+            )
+            .route(
+                "/playlists/{id}/hls/{bitrate}/index.m3u8",
+                web::get().to(playlist_hls_variant),
+            )
+            .route("/groups", web::post().to(create_group))
+            .route("/groups", web::get().to(list_groups))
+            .route("/groups/{id}", web::delete().to(delete_group))
+            .route(
+                "/groups/{id}/members/{user_id}",
+                web::put().to(add_group_member),
+            )
+            .route(
+                "/groups/{id}/members/{user_id}",
+                web::delete().to(remove_group_member),
+            )
             .route("/users", web::post().to(create_user))
             .route("/users", web::get().to(list_users))
-            .route("/users/{id}", web::delete().to(delete_user));
+            .route("/users/{id}", web::delete().to(delete_user))
+            .route("/users/{id}/quota", web::put().to(set_user_quota))
+            .route("/users/{id}/usage", web::get().to(get_user_usage))
+            .route(
+                "/users/{id}/transcoding",
+                web::get().to(get_transcoding_preferences),
+            )
+            .route(
+                "/users/{id}/transcoding",
+                web::put().to(set_transcoding_preferences),
+            )
+            .route("/storage/stats", web::get().to(storage_stats));
     };
 
-    // Start HTTP server
-    HttpServer::new(move || {
+    // Start HTTPS server
+    let https_server = HttpServer::new(move || {
         App::new()
-            .wrap(middleware::Logger::default())
+            .wrap(middleware::Logger::new(
+                r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T %{X-Request-Id}o"#,
+            ))
             .configure(app_config.clone())
     })
-    .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+    .bind_rustls_0_23(https_addr, tls_config)?
+    .run();
+
+    // Redirect plain HTTP to HTTPS
+    match http_redirect_addr {
+        Some(addr) => {
+            let redirect_server = HttpServer::new(move || {
+                App::new().default_service(web::to(move |req| redirect_to_https(req, https_port)))
+            })
+            .bind(addr)?
+            .run();
+
+            futures::try_join!(https_server, redirect_server)?;
+        }
+        None => https_server.await?,
+    }
+    Ok(())
 }
@@ -0,0 +1,393 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, MetadataRevision, StandardTagKey};
+use symphonia::core::probe::Hint;
+
+use crate::error::AppError;
+
+/// Tags and stream properties read from an uploaded file. Every field is
+/// optional because files are often partially tagged.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, FromRow)]
+pub struct AudioMetadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track_number: Option<i64>,
+    pub disc_number: Option<i64>,
+    pub year: Option<i64>,
+    pub genre: Option<String>,
+    pub duration_ms: Option<i64>,
+    pub sample_rate: Option<i64>,
+    pub channels: Option<i64>,
+    /// Average bitrate of the audio stream, not counting tags or cover art,
+    /// in kbit/s
+    pub bitrate_kbps: Option<i64>,
+}
+
+/// The container formats accepted for upload, identified by magic bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioFormat {
+    Mp3,
+    Aac,
+    Wav,
+    Flac,
+    Ogg,
+    Mp4,
+}
+
+impl AudioFormat {
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            AudioFormat::Mp3 => "audio/mpeg",
+            AudioFormat::Aac => "audio/aac",
+            AudioFormat::Wav => "audio/wav",
+            AudioFormat::Flac => "audio/flac",
+            AudioFormat::Ogg => "audio/ogg",
+            AudioFormat::Mp4 => "audio/mp4",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            AudioFormat::Mp3 => "mp3",
+            AudioFormat::Aac => "aac",
+            AudioFormat::Wav => "wav",
+            AudioFormat::Flac => "flac",
+            AudioFormat::Ogg => "ogg",
+            AudioFormat::Mp4 => "m4a",
+        }
+    }
+
+    /// Identifies the format from the first bytes of a file, after any
+    /// ID3v2 tags (see `id3v2_tag_len`).
+    pub fn sniff(header: &[u8]) -> Option<AudioFormat> {
+        match header {
+            [b'f', b'L', b'a', b'C', ..] => Some(AudioFormat::Flac),
+            [b'O', b'g', b'g', b'S', ..] => Some(AudioFormat::Ogg),
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => {
+                Some(AudioFormat::Wav)
+            }
+            [_, _, _, _, b'f', b't', b'y', b'p', ..] => Some(AudioFormat::Mp4),
+            // ADTS: 12-bit sync word, MPEG layer bits 00
+            [0xFF, b, ..] if b & 0xF6 == 0xF0 => Some(AudioFormat::Aac),
+            // MPEG audio frame: 11-bit sync word, layer bits non-zero
+            [0xFF, b, ..] if b & 0xE0 == 0xE0 && b & 0x06 != 0 => Some(AudioFormat::Mp3),
+            _ => None,
+        }
+    }
+}
+
+/// Length of the ID3v2 tag at the start of `header`, including its header
+/// and footer, or `None` if there is none. Taggers put these in front of
+/// MP3, AAC and sometimes FLAC data, so the format is sniffed after them.
+pub fn id3v2_tag_len(header: &[u8]) -> Option<u64> {
+    match header {
+        [b'I', b'D', b'3', _, _, flags, size @ ..] if size.len() >= 4 => {
+            // The size is syncsafe: 7 bits per byte, most significant first
+            let size = size[..4]
+                .iter()
+                .fold(0u64, |acc, &b| (acc << 7) | u64::from(b & 0x7F));
+            let footer = if flags & 0x10 != 0 { 10 } else { 0 };
+            Some(10 + size + footer)
+        }
+        _ => None,
+    }
+}
+
+/// Sniffs the format of a file, skipping any ID3v2 tags in front of the
+/// audio data.
+fn sniff_file<R: Read + Seek>(file: &mut R) -> io::Result<Option<AudioFormat>> {
+    let mut start = 0;
+    loop {
+        let mut header = [0u8; 12];
+        file.seek(SeekFrom::Start(start))?;
+        let read = read_up_to(file, &mut header)?;
+        match id3v2_tag_len(&header[..read]) {
+            Some(len) => start += len,
+            None => return Ok(AudioFormat::sniff(&header[..read])),
+        }
+    }
+}
+
+/// Like `read_exact`, but stops early at the end of the file.
+fn read_up_to<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// What the server learned about an uploaded file by reading it.
+#[derive(Debug, Clone)]
+pub struct InspectedAudio {
+    pub format: AudioFormat,
+    pub metadata: AudioMetadata,
+}
+
+/// Number of packets that must decode before a file is considered valid.
+const PACKETS_TO_VERIFY: usize = 8;
+
+/// Verifies that a file is audio by checking its magic bytes, parsing the
+/// container and decoding its first packets, then reads ID3v2, Vorbis
+/// comment, FLAC and MP4 tags plus duration, sample rate, channel count and
+/// average bitrate. Tags that cannot be read are left as `None`.
+///
+/// This does blocking file I/O and may scan the whole file, so call it from
+/// `web::block`.
+pub fn inspect_audio(path: &Path) -> Result<InspectedAudio, AppError> {
+    let mut file = File::open(path)?;
+
+    let format = sniff_file(&mut file)?.ok_or_else(|| {
+        AppError::Validation(
+            "File is not a supported audio format (MP3/AAC/WAV/FLAC/OGG/M4A)".to_string(),
+        )
+    })?;
+    file.seek(SeekFrom::Start(0))?;
+
+    let corrupt = |detail: String| {
+        AppError::Validation(format!(
+            "File looks like {} but could not be read: {}",
+            format.extension().to_uppercase(),
+            detail
+        ))
+    };
+
+    let source = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    hint.with_extension(format.extension());
+
+    let mut probed = symphonia::default::get_probe()
+        .format(
+            &hint,
+            source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .map_err(|e| corrupt(e.to_string()))?;
+
+    let mut meta = AudioMetadata::default();
+
+    // Tags found before the container (e.g. ID3v2 ahead of MP3 frames) come
+    // from the probe; tags inside the container come from the reader. The
+    // container's tags are applied last so they win.
+    if let Some(revision) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+        apply_tags(&mut meta, revision);
+    }
+    if let Some(revision) = probed.format.metadata().current() {
+        apply_tags(&mut meta, revision);
+    }
+
+    let track = probed
+        .format
+        .default_track()
+        .ok_or_else(|| corrupt("no audio track".to_string()))?;
+    let track_id = track.id;
+    let params = track.codec_params.clone();
+
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&params, &DecoderOptions::default())
+        .map_err(|e| corrupt(e.to_string()))?;
+
+    meta.sample_rate = params.sample_rate.map(i64::from);
+    meta.channels = params.channels.map(|c| c.count() as i64);
+
+    // Decode the first packets; a truncated header or garbage after a valid
+    // magic number fails here. Packet durations are summed along the way in
+    // case the header has no frame count (e.g. MP3 without a Xing tag), and
+    // packet sizes for the bitrate, which would be skewed by tags and cover
+    // art if it were taken from the file size.
+    let mut decoded = 0;
+    let mut counted_frames = 0u64;
+    let mut payload_bytes = 0u64;
+    let mut last_error = None;
+    while decoded < PACKETS_TO_VERIFY {
+        let packet = match probed.format.next_packet() {
+            Ok(packet) => packet,
+            Err(_) => break,
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        counted_frames += packet.dur;
+        payload_bytes += packet.data.len() as u64;
+        match decoder.decode(&packet) {
+            Ok(_) => decoded += 1,
+            Err(e) => last_error = Some(e.to_string()),
+        }
+    }
+    if decoded == 0 {
+        return Err(corrupt(
+            last_error.unwrap_or_else(|| "no audio data".to_string()),
+        ));
+    }
+
+    let time_base = params.time_base.or_else(|| {
+        params
+            .sample_rate
+            .map(|rate| symphonia::core::units::TimeBase::new(1, rate))
+    });
+
+    while let Ok(packet) = probed.format.next_packet() {
+        if packet.track_id() == track_id {
+            counted_frames += packet.dur;
+            payload_bytes += packet.data.len() as u64;
+        }
+    }
+    let n_frames = params
+        .n_frames
+        .or((counted_frames > 0).then_some(counted_frames));
+
+    if let (Some(time_base), Some(n_frames)) = (time_base, n_frames) {
+        let time = time_base.calc_time(n_frames);
+        let duration_ms = time.seconds * 1000 + (time.frac * 1000.0) as u64;
+        if duration_ms > 0 {
+            meta.duration_ms = Some(duration_ms as i64);
+            meta.bitrate_kbps = Some((payload_bytes * 8 / duration_ms) as i64);
+        }
+    }
+
+    Ok(InspectedAudio {
+        format,
+        metadata: meta,
+    })
+}
+
+fn apply_tags(meta: &mut AudioMetadata, revision: &MetadataRevision) {
+    for tag in revision.tags() {
+        let value = tag.value.to_string();
+        // RIFF INFO and some ID3 writers pad values with NULs
+        let value = value.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+        if value.is_empty() {
+            continue;
+        }
+
+        match tag.std_key {
+            Some(StandardTagKey::TrackTitle) => meta.title = Some(value.to_string()),
+            Some(StandardTagKey::Artist) => meta.artist = Some(value.to_string()),
+            Some(StandardTagKey::Album) => meta.album = Some(value.to_string()),
+            Some(StandardTagKey::AlbumArtist) => meta.album_artist = Some(value.to_string()),
+            Some(StandardTagKey::Genre) => meta.genre = Some(value.to_string()),
+            Some(StandardTagKey::TrackNumber) => {
+                meta.track_number = parse_leading_number(value).or(meta.track_number)
+            }
+            Some(StandardTagKey::DiscNumber) => {
+                meta.disc_number = parse_leading_number(value).or(meta.disc_number)
+            }
+            Some(StandardTagKey::Date)
+            | Some(StandardTagKey::ReleaseDate)
+            | Some(StandardTagKey::OriginalDate) => {
+                meta.year = meta.year.or_else(|| parse_year(value))
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Parses "3" or "3/12" as 3.
+fn parse_leading_number(value: &str) -> Option<i64> {
+    value.split('/').next()?.trim().parse().ok()
+}
+
+/// Takes the year from "1997", "1997-05-21" or "1997-05-21T00:00:00".
+fn parse_year(value: &str) -> Option<i64> {
+    let year: String = value.chars().take_while(|c| c.is_ascii_digit()).collect();
+    (year.len() == 4).then(|| year.parse().ok()).flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const MPEG_FRAME: [u8; 4] = [0xFF, 0xFB, 0x90, 0x64];
+    const ADTS_FRAME: [u8; 4] = [0xFF, 0xF1, 0x50, 0x80];
+
+    fn id3v2_tag(body_len: usize, footer: bool) -> Vec<u8> {
+        let size = body_len as u32;
+        let mut tag = vec![b'I', b'D', b'3', 4, 0, if footer { 0x10 } else { 0 }];
+        tag.extend((0..4).rev().map(|i| ((size >> (7 * i)) & 0x7F) as u8));
+        tag.resize(10 + body_len, 0);
+        if footer {
+            tag.extend_from_slice(b"3DI");
+            tag.resize(tag.len() + 7, 0);
+        }
+        tag
+    }
+
+    #[test]
+    fn sniffs_magic_bytes() {
+        assert_eq!(
+            AudioFormat::sniff(b"fLaC\0\0\0\x22"),
+            Some(AudioFormat::Flac)
+        );
+        assert_eq!(AudioFormat::sniff(b"OggS\0\x02"), Some(AudioFormat::Ogg));
+        assert_eq!(
+            AudioFormat::sniff(b"RIFF\x24\0\0\0WAVEfmt "),
+            Some(AudioFormat::Wav)
+        );
+        assert_eq!(
+            AudioFormat::sniff(b"\0\0\0\x20ftypM4A "),
+            Some(AudioFormat::Mp4)
+        );
+        assert_eq!(AudioFormat::sniff(&ADTS_FRAME), Some(AudioFormat::Aac));
+        assert_eq!(AudioFormat::sniff(&MPEG_FRAME), Some(AudioFormat::Mp3));
+        assert_eq!(AudioFormat::sniff(b"RIFF\x24\0\0\0AVI LIST"), None);
+        assert_eq!(AudioFormat::sniff(b"%PDF-1.7"), None);
+        assert_eq!(AudioFormat::sniff(b""), None);
+    }
+
+    #[test]
+    fn reads_syncsafe_tag_size() {
+        assert_eq!(id3v2_tag_len(&id3v2_tag(0, false)), Some(10));
+        assert_eq!(id3v2_tag_len(&id3v2_tag(300, false)), Some(310));
+        assert_eq!(id3v2_tag_len(&id3v2_tag(300, true)), Some(320));
+        assert_eq!(id3v2_tag_len(b"ID3\x04\0\0\x01\x7F"), None);
+        assert_eq!(id3v2_tag_len(&MPEG_FRAME), None);
+    }
+
+    #[test]
+    fn sniffs_format_behind_id3v2_tags() {
+        let sniff = |parts: &[&[u8]]| sniff_file(&mut Cursor::new(parts.concat())).unwrap();
+
+        let tag = id3v2_tag(300, false);
+        assert_eq!(sniff(&[&tag, &MPEG_FRAME]), Some(AudioFormat::Mp3));
+        assert_eq!(sniff(&[&tag, &ADTS_FRAME]), Some(AudioFormat::Aac));
+        assert_eq!(sniff(&[&tag, b"fLaC\0\0\0\x22"]), Some(AudioFormat::Flac));
+        assert_eq!(
+            sniff(&[&id3v2_tag(20, true), &tag, &MPEG_FRAME]),
+            Some(AudioFormat::Mp3)
+        );
+        assert_eq!(sniff(&[&tag, b"%PDF-1.7"]), None);
+        assert_eq!(sniff(&[&tag]), None);
+    }
+
+    #[test]
+    fn parses_leading_numbers() {
+        assert_eq!(parse_leading_number("3"), Some(3));
+        assert_eq!(parse_leading_number("3/12"), Some(3));
+        assert_eq!(parse_leading_number(" 07 / 12"), Some(7));
+        assert_eq!(parse_leading_number("/12"), None);
+        assert_eq!(parse_leading_number("A1"), None);
+    }
+
+    #[test]
+    fn parses_years() {
+        assert_eq!(parse_year("1997"), Some(1997));
+        assert_eq!(parse_year("1997-05-21"), Some(1997));
+        assert_eq!(parse_year("1997-05-21T00:00:00"), Some(1997));
+        assert_eq!(parse_year("97"), None);
+        assert_eq!(parse_year("19970"), None);
+        assert_eq!(parse_year("May 1997"), None);
+    }
+}
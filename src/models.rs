@@ -1,11 +1,35 @@
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
+use uuid::Uuid;
+
+use crate::metadata::AudioMetadata;
+use crate::playlist_formats::PlaylistFormat;
+use crate::track_matcher::MatchKind;
+use crate::transcode::TranscodeFormat;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
     pub exp: usize,
+    pub jti: String,
+}
+
+/// Claims of a stream token, which only lets its holder stream one file or
+/// the HLS playlists of one playlist.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StreamClaims {
+    pub sub: String,
+    pub exp: usize,
+    pub jti: String,
+    /// Always `stream`, which keeps these from passing as access tokens
+    pub aud: String,
+    /// The audio file that may be streamed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio: Option<String>,
+    /// The playlist whose HLS playlists may be fetched
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub playlist: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -17,6 +41,13 @@ pub struct LoginRequest {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AuthResponse {
     pub token: String,
+    pub refresh_token: String,
+    pub expires_in: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefreshTokenRequest {
+    pub refresh_token: String,
 }
 
 #[derive(Debug, Serialize, FromRow)]
@@ -26,7 +57,18 @@ pub struct AudioFile {
     pub user_id: String,
     pub created_at: chrono::DateTime<Utc>,
     pub mime_type: String,
+    /// Size in bytes; `None` for uploads that predate quotas
+    pub size: Option<i64>,
     pub user_folder: String,
+    /// Location relative to the upload root; `None` for legacy uploads
+    #[serde(skip_serializing)]
+    pub storage_path: Option<String>,
+    /// SHA-256 of the contents; `None` for uploads that predate deduplication
+    #[serde(skip_serializing)]
+    pub blob_hash: Option<String>,
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub metadata: AudioMetadata,
 }
 
 #[derive(Debug, Serialize, Deserialize, FromRow)]
@@ -43,6 +85,41 @@ pub struct Playlist {
     pub name: String,
     pub user_id: String,
     pub created_at: chrono::DateTime<Utc>,
+    /// Incremented by every change to the playlist's items
+    pub version: i64,
+    pub description: Option<String>,
+    pub cover_image_url: Option<String>,
+    pub visibility: PlaylistVisibility,
+    /// When the playlist or its items last changed
+    pub updated_at: chrono::DateTime<Utc>,
+}
+
+impl Playlist {
+    /// A new, empty private playlist.
+    pub fn new(user_id: &str, name: &str) -> Self {
+        let now = Utc::now();
+        Playlist {
+            id: Uuid::new_v4().to_string(),
+            name: name.to_string(),
+            user_id: user_id.to_string(),
+            created_at: now,
+            version: 1,
+            description: None,
+            cover_image_url: None,
+            visibility: PlaylistVisibility::Private,
+            updated_at: now,
+        }
+    }
+}
+
+/// Who can see a playlist besides its owner and admins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum PlaylistVisibility {
+    Private,
+    /// Every user can view and duplicate it
+    Public,
 }
 
 #[derive(Debug, Serialize, Deserialize, FromRow)]
@@ -60,26 +137,166 @@ pub struct CreatePlaylistRequest {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PlaylistWithItems {
+    #[serde(flatten)]
+    pub playlist: Playlist,
+    pub items: Vec<PlaylistAudioItem>,
+}
+
+/// Changes to a playlist's details; omitted fields are left as they are.
+#[derive(Debug, Deserialize)]
+pub struct UpdatePlaylistRequest {
+    pub name: Option<String>,
+    /// An empty string removes the description
+    pub description: Option<String>,
+    /// An http(s) URL of the cover image; an empty string removes it
+    pub cover_image_url: Option<String>,
+    pub visibility: Option<PlaylistVisibility>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GetPlaylistsOptions {
+    /// Lists the playlists shared with you instead of your own
+    #[serde(default)]
+    pub shared: bool,
+}
+
+/// A playlist shared with the requesting user, directly or through a group.
+#[derive(Debug, Serialize, FromRow)]
+pub struct SharedPlaylist {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub playlist: Playlist,
+    pub owner_username: String,
+    /// The highest access any of the shares grants
+    pub access: ShareAccess,
+}
+
+/// What a share lets its users do with a playlist. Editing includes reading.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, sqlx::Type,
+)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum ShareAccess {
+    /// View, stream and duplicate the playlist
+    Read,
+    /// Also add, move and remove items
+    Edit,
+}
+
+/// Shares a playlist with one user or one group, or changes the access of
+/// an existing share.
+#[derive(Debug, Deserialize)]
+pub struct SharePlaylistRequest {
+    pub username: Option<String>,
+    /// Name of the group
+    pub group: Option<String>,
+    pub access: ShareAccess,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct PlaylistShare {
     pub id: String,
-    pub name: String,
-    pub user_id: String,
+    pub playlist_id: String,
+    pub user_id: Option<String>,
+    pub username: Option<String>,
+    pub group_id: Option<String>,
+    pub group_name: Option<String>,
+    pub access: ShareAccess,
     pub created_at: chrono::DateTime<Utc>,
-    pub items: Vec<PlaylistAudioItem>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+pub struct CreateGroupRequest {
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct Group {
+    pub id: String,
+    pub name: String,
+    pub created_at: chrono::DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GroupWithMembers {
+    #[serde(flatten)]
+    pub group: Group,
+    pub members: Vec<GroupMember>,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct GroupMember {
+    pub user_id: String,
+    pub username: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DuplicatePlaylistOptions {
+    /// Name of the copy; by default the original's, marked as a copy if
+    /// the original is your own
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
 pub struct PlaylistAudioItem {
     pub id: String,
     pub audio_id: String,
     pub position: i32,
     pub filename: String,
     pub mime_type: String,
+    /// The user who added the item, unless they have since been deleted
+    pub added_by: Option<String>,
+    pub added_by_username: Option<String>,
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub metadata: AudioMetadata,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AddToPlaylistRequest {
     pub audio_id: String,
-    pub position: Option<i32>,
+    /// Position to insert at, from 1; appended when omitted
+    pub position: Option<i64>,
+}
+
+/// A batch of edits to a playlist's items, applied together or not at all.
+#[derive(Debug, Deserialize)]
+pub struct EditPlaylistItemsRequest {
+    /// The playlist version the edits were made against; they are refused
+    /// if the playlist has changed since
+    pub version: Option<i64>,
+    pub operations: Vec<PlaylistItemEdit>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum PlaylistItemEdit {
+    /// Moves items, in the order given, to one place
+    Move {
+        item_ids: Vec<String>,
+        #[serde(flatten)]
+        placement: ItemPlacement,
+    },
+    /// Adds audio files, in the order given, at one place
+    Insert {
+        audio_ids: Vec<String>,
+        #[serde(flatten)]
+        placement: ItemPlacement,
+    },
+    Remove {
+        item_ids: Vec<String>,
+    },
+}
+
+/// Where moved or inserted items go: before or after another item, or at
+/// a position from 1. At most one may be given; items go at the end when
+/// none is.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ItemPlacement {
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub position: Option<i64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -87,6 +304,9 @@ pub struct CreateUserRequest {
     pub username: String,
     pub password: String,
     pub is_admin: bool,
+    /// Storage quota in bytes; omitted or `null` for unlimited
+    #[serde(default)]
+    pub quota_bytes: Option<i64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -94,9 +314,156 @@ pub struct UserResponse {
     pub id: String,
     pub username: String,
     pub is_admin: bool,
+    pub quota_bytes: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetQuotaRequest {
+    /// Storage quota in bytes; `null` for unlimited
+    pub quota_bytes: Option<i64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+pub struct UsageResponse {
+    pub user_id: String,
+    pub used_bytes: i64,
+    pub file_count: i64,
+    pub quota_bytes: Option<i64>,
+    /// `None` when the quota is unlimited
+    pub remaining_bytes: Option<i64>,
+}
+
+/// A URL a media player can open without an `Authorization` header.
+#[derive(Debug, Serialize)]
+pub struct HlsLink {
+    /// The master playlist, with a stream token
+    pub url: String,
+    /// Seconds until the token expires
+    pub expires_in: i64,
+}
+
+#[derive(Debug, Deserialize)]
 pub struct StreamPlaylistOptions {
+    #[serde(default)]
     pub shuffle: bool,
+    /// Format of the playlist file; extended M3U by default
+    #[serde(default)]
+    pub format: PlaylistFormat,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportPlaylistOptions {
+    /// Name for the new playlist, instead of the one in the file
+    pub name: Option<String>,
+    /// Imports only the playlist of this name from a file holding several,
+    /// such as an iTunes library
+    pub playlist: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlaylistImportResponse {
+    pub playlists: Vec<ImportedPlaylist>,
+}
+
+/// A playlist created by an import, with what became of each entry.
+#[derive(Debug, Serialize)]
+pub struct ImportedPlaylist {
+    pub playlist: Playlist,
+    pub matched: Vec<MatchedEntry>,
+    pub unmatched: Vec<UnmatchedEntry>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MatchedEntry {
+    /// Position of the entry in the file, from 1
+    pub entry: usize,
+    pub audio_id: String,
+    pub matched_by: MatchKind,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UnmatchedEntry {
+    /// Position of the entry in the file, from 1
+    pub entry: usize,
+    pub location: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+}
+
+/// How much space deduplication saves. Only files uploaded since
+/// deduplication was introduced are counted.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StorageStats {
+    /// Audio files referring to a blob
+    pub files: i64,
+    /// Distinct blobs stored
+    pub blobs: i64,
+    /// Bytes the files would take if each were stored separately
+    pub logical_bytes: i64,
+    /// Bytes actually stored
+    pub stored_bytes: i64,
+    pub saved_bytes: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadOptions {
+    /// Name of a playlist to append the uploaded files to
+    pub playlist: Option<String>,
+}
+
+/// The outcome of storing one file of an upload.
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum UploadResult {
+    Stored {
+        /// The file's name as sent, including any folders
+        path: String,
+        file: Box<AudioFile>,
+    },
+    Failed {
+        path: String,
+        code: String,
+        message: String,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct UploadResponse {
+    pub results: Vec<UploadResult>,
+    /// The playlist the files were added to, if one was requested
+    pub playlist: Option<Playlist>,
+}
+
+/// A resumable upload in progress; see `handlers::tus`.
+#[derive(Debug, FromRow)]
+pub struct ResumableUpload {
+    pub id: String,
+    pub user_id: String,
+    /// Total size declared when the upload was created
+    pub length: i64,
+    /// Bytes received so far
+    pub received: i64,
+    pub filename: String,
+    /// Playlist to append the finished file to
+    pub playlist: Option<String>,
+    pub upload_metadata: Option<String>,
+    pub created_at: chrono::DateTime<Utc>,
+    pub expires_at: chrono::DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StreamQuery {
+    /// `opus`, `mp3`, `aac`, or `original` to skip a preferred format
+    pub format: Option<String>,
+    /// Target bitrate in kbit/s
+    pub bitrate: Option<u32>,
+}
+
+/// How a user's streams are transcoded when the request doesn't say.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TranscodingPreferences {
+    /// `None` streams original files
+    pub format: Option<TranscodeFormat>,
+    /// `None` uses the format's default
+    pub bitrate_kbps: Option<u32>,
 }
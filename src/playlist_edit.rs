@@ -0,0 +1,237 @@
+//! Edits to the order of a playlist's items. A batch of edits is applied to
+//! the items in memory first, so it can be checked as a whole before any
+//! row is written.
+
+use std::collections::HashSet;
+use uuid::Uuid;
+
+use crate::error::AppError;
+use crate::models::{ItemPlacement, PlaylistItemEdit};
+
+/// A playlist item as it stands after the edits so far.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EditedItem {
+    pub id: String,
+    pub audio_id: String,
+    /// Added by the edits, so not yet stored
+    pub added: bool,
+}
+
+impl EditedItem {
+    pub fn stored(id: String, audio_id: String) -> Self {
+        EditedItem {
+            id,
+            audio_id,
+            added: false,
+        }
+    }
+}
+
+/// Applies edits in order to items listed by position.
+pub fn apply(items: &mut Vec<EditedItem>, edits: &[PlaylistItemEdit]) -> Result<(), AppError> {
+    for edit in edits {
+        match edit {
+            PlaylistItemEdit::Move {
+                item_ids,
+                placement,
+            } => {
+                let moved = take(items, item_ids)?;
+                let anchors = [&placement.before, &placement.after];
+                if anchors
+                    .into_iter()
+                    .flatten()
+                    .any(|anchor| item_ids.contains(anchor))
+                {
+                    return Err(AppError::Validation(
+                        "Items can't be moved next to themselves".to_string(),
+                    ));
+                }
+                let index = index_of(items, placement)?;
+                items.splice(index..index, moved);
+            }
+            PlaylistItemEdit::Insert {
+                audio_ids,
+                placement,
+            } => {
+                if audio_ids.is_empty() {
+                    return Err(AppError::Validation("No audio files to insert".to_string()));
+                }
+                let index = index_of(items, placement)?;
+                let added = audio_ids.iter().map(|audio_id| EditedItem {
+                    id: Uuid::new_v4().to_string(),
+                    audio_id: audio_id.clone(),
+                    added: true,
+                });
+                items.splice(index..index, added);
+            }
+            PlaylistItemEdit::Remove { item_ids } => {
+                take(items, item_ids)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Takes the items with the given ids out of the list, in the order of the
+/// ids.
+fn take(items: &mut Vec<EditedItem>, item_ids: &[String]) -> Result<Vec<EditedItem>, AppError> {
+    if item_ids.is_empty() {
+        return Err(AppError::Validation("No items given".to_string()));
+    }
+    let mut seen = HashSet::new();
+    if let Some(repeated) = item_ids.iter().find(|id| !seen.insert(id.as_str())) {
+        return Err(AppError::Validation(format!(
+            "Item {} is given more than once",
+            repeated
+        )));
+    }
+
+    let mut taken = Vec::with_capacity(item_ids.len());
+    for item_id in item_ids {
+        let index = find(items, item_id)?;
+        taken.push(items.remove(index));
+    }
+    Ok(taken)
+}
+
+/// Where in the list items placed by `placement` go.
+fn index_of(items: &[EditedItem], placement: &ItemPlacement) -> Result<usize, AppError> {
+    match (&placement.before, &placement.after, placement.position) {
+        (None, None, None) => Ok(items.len()),
+        (Some(before), None, None) => find(items, before),
+        (None, Some(after), None) => Ok(find(items, after)? + 1),
+        (None, None, Some(position)) => match usize::try_from(position) {
+            Ok(position) if (1..=items.len() + 1).contains(&position) => Ok(position - 1),
+            _ => Err(AppError::Validation(format!(
+                "Position must be between 1 and {}",
+                items.len() + 1
+            ))),
+        },
+        _ => Err(AppError::Validation(
+            "Give at most one of before, after and position".to_string(),
+        )),
+    }
+}
+
+fn find(items: &[EditedItem], item_id: &str) -> Result<usize, AppError> {
+    items
+        .iter()
+        .position(|item| item.id == item_id)
+        .ok_or_else(|| AppError::NotFound(format!("Item {} not found in playlist", item_id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items(ids: &[&str]) -> Vec<EditedItem> {
+        ids.iter()
+            .map(|id| EditedItem::stored(id.to_string(), format!("audio-{}", id)))
+            .collect()
+    }
+
+    fn ids(items: &[EditedItem]) -> Vec<&str> {
+        items.iter().map(|item| item.id.as_str()).collect()
+    }
+
+    fn strings(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    fn placed(before: Option<&str>, after: Option<&str>, position: Option<i64>) -> ItemPlacement {
+        ItemPlacement {
+            before: before.map(str::to_string),
+            after: after.map(str::to_string),
+            position,
+        }
+    }
+
+    fn moved(item_ids: &[&str], placement: ItemPlacement) -> PlaylistItemEdit {
+        PlaylistItemEdit::Move {
+            item_ids: strings(item_ids),
+            placement,
+        }
+    }
+
+    #[test]
+    fn moves_items_in_the_order_given() {
+        let mut list = items(&["a", "b", "c", "d", "e"]);
+        apply(
+            &mut list,
+            &[moved(&["d", "a"], placed(Some("c"), None, None))],
+        )
+        .unwrap();
+        assert_eq!(ids(&list), ["b", "d", "a", "c", "e"]);
+
+        apply(&mut list, &[moved(&["b"], placed(None, Some("e"), None))]).unwrap();
+        assert_eq!(ids(&list), ["d", "a", "c", "e", "b"]);
+
+        apply(&mut list, &[moved(&["b"], placed(None, None, Some(1)))]).unwrap();
+        assert_eq!(ids(&list), ["b", "d", "a", "c", "e"]);
+
+        apply(&mut list, &[moved(&["d"], ItemPlacement::default())]).unwrap();
+        assert_eq!(ids(&list), ["b", "a", "c", "e", "d"]);
+    }
+
+    #[test]
+    fn inserts_and_removes_in_sequence() {
+        let mut list = items(&["a", "b", "c"]);
+        apply(
+            &mut list,
+            &[
+                PlaylistItemEdit::Remove {
+                    item_ids: strings(&["b"]),
+                },
+                PlaylistItemEdit::Insert {
+                    audio_ids: strings(&["x", "y"]),
+                    placement: placed(None, Some("a"), None),
+                },
+                PlaylistItemEdit::Insert {
+                    audio_ids: strings(&["z"]),
+                    placement: ItemPlacement::default(),
+                },
+            ],
+        )
+        .unwrap();
+
+        let audio: Vec<&str> = list.iter().map(|item| item.audio_id.as_str()).collect();
+        assert_eq!(audio, ["audio-a", "x", "y", "audio-c", "z"]);
+        let added: Vec<bool> = list.iter().map(|item| item.added).collect();
+        assert_eq!(added, [false, true, true, false, true]);
+    }
+
+    #[test]
+    fn invalid_edits_are_refused() {
+        let list = items(&["a", "b", "c"]);
+        let refused = |edit: PlaylistItemEdit| apply(&mut list.clone(), &[edit]).unwrap_err();
+
+        assert!(matches!(
+            refused(moved(&["a"], placed(None, None, Some(4)))),
+            AppError::Validation(_)
+        ));
+        assert!(matches!(
+            refused(moved(&["a"], placed(None, None, Some(0)))),
+            AppError::Validation(_)
+        ));
+        assert!(matches!(
+            refused(moved(&["a", "b"], placed(Some("b"), None, None))),
+            AppError::Validation(_)
+        ));
+        assert!(matches!(
+            refused(moved(&["a"], placed(Some("c"), Some("c"), None))),
+            AppError::Validation(_)
+        ));
+        assert!(matches!(
+            refused(moved(&["a", "a"], ItemPlacement::default())),
+            AppError::Validation(_)
+        ));
+        assert!(matches!(
+            refused(moved(&["q"], ItemPlacement::default())),
+            AppError::NotFound(_)
+        ));
+        assert!(matches!(
+            refused(PlaylistItemEdit::Remove { item_ids: vec![] }),
+            AppError::Validation(_)
+        ));
+    }
+}
@@ -0,0 +1,853 @@
+//! Playlist files in the formats media players read: extended M3U, PLS,
+//! XSPF and its JSON form JSPF, and for importing, iTunes library exports.
+
+use quick_xml::escape::resolve_predefined_entity;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+use crate::error::AppError;
+use crate::models::AudioFile;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaylistFormat {
+    /// Extended M3U in UTF-8
+    #[default]
+    M3u,
+    Pls,
+    Xspf,
+    Jspf,
+}
+
+impl PlaylistFormat {
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            PlaylistFormat::M3u => "audio/mpegurl",
+            PlaylistFormat::Pls => "audio/x-scpls",
+            PlaylistFormat::Xspf => "application/xspf+xml",
+            PlaylistFormat::Jspf => "application/jspf+json",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            PlaylistFormat::M3u => "m3u8",
+            PlaylistFormat::Pls => "pls",
+            PlaylistFormat::Xspf => "xspf",
+            PlaylistFormat::Jspf => "jspf",
+        }
+    }
+}
+
+/// A track in a playlist file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PlaylistEntry {
+    /// URL the track is played from
+    pub location: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track_number: Option<i64>,
+    pub duration_ms: Option<i64>,
+}
+
+impl PlaylistEntry {
+    /// An entry for `audio`, played from `location`.
+    pub fn for_audio(audio: &AudioFile, location: String) -> Self {
+        PlaylistEntry {
+            location,
+            // Players show the title, so untagged files get their filename
+            title: Some(
+                audio
+                    .metadata
+                    .title
+                    .clone()
+                    .unwrap_or_else(|| audio.filename.clone()),
+            ),
+            artist: audio.metadata.artist.clone(),
+            album: audio.metadata.album.clone(),
+            track_number: audio.metadata.track_number,
+            duration_ms: audio.metadata.duration_ms,
+        }
+    }
+
+    /// "Artist - Title", as M3U and PLS show it.
+    fn display_name(&self) -> String {
+        match (&self.artist, &self.title) {
+            (Some(artist), Some(title)) => format!("{} - {}", artist, title),
+            (None, Some(title)) => title.clone(),
+            (Some(artist), None) => artist.clone(),
+            (None, None) => String::new(),
+        }
+    }
+
+    /// Whole seconds, or -1 if unknown, as M3U and PLS expect.
+    fn seconds(&self) -> i64 {
+        self.duration_ms.map_or(-1, |ms| (ms + 500) / 1000)
+    }
+}
+
+/// Writes the playlist called `title` with `entries` in `format`.
+pub fn write(format: PlaylistFormat, title: &str, entries: &[PlaylistEntry]) -> String {
+    match format {
+        PlaylistFormat::M3u => write_m3u(title, entries),
+        PlaylistFormat::Pls => write_pls(entries),
+        PlaylistFormat::Xspf => write_xspf(title, entries),
+        PlaylistFormat::Jspf => write_jspf(title, entries),
+    }
+}
+
+fn write_m3u(title: &str, entries: &[PlaylistEntry]) -> String {
+    let mut out = String::from("#EXTM3U\n");
+    let _ = writeln!(out, "#PLAYLIST:{}", single_line(title));
+    for entry in entries {
+        let _ = writeln!(
+            out,
+            "#EXTINF:{},{}\n{}",
+            entry.seconds(),
+            single_line(&entry.display_name()),
+            entry.location
+        );
+    }
+    out
+}
+
+fn write_pls(entries: &[PlaylistEntry]) -> String {
+    let mut out = String::from("[playlist]\n");
+    for (index, entry) in entries.iter().enumerate() {
+        let number = index + 1;
+        let _ = writeln!(out, "File{}={}", number, entry.location);
+        let _ = writeln!(
+            out,
+            "Title{}={}",
+            number,
+            single_line(&entry.display_name())
+        );
+        let _ = writeln!(out, "Length{}={}", number, entry.seconds());
+    }
+    let _ = writeln!(out, "NumberOfEntries={}\nVersion=2", entries.len());
+    out
+}
+
+fn write_xspf(title: &str, entries: &[PlaylistEntry]) -> String {
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n",
+    );
+    let _ = writeln!(out, "  <title>{}</title>\n  <trackList>", escape_xml(title));
+    for entry in entries {
+        out.push_str("    <track>\n");
+        let _ = writeln!(
+            out,
+            "      <location>{}</location>",
+            escape_xml(&entry.location)
+        );
+        for (element, value) in [
+            ("title", &entry.title),
+            ("creator", &entry.artist),
+            ("album", &entry.album),
+        ] {
+            if let Some(value) = value {
+                let _ = writeln!(out, "      <{0}>{1}</{0}>", element, escape_xml(value));
+            }
+        }
+        if let Some(number) = entry.track_number.filter(|&n| n > 0) {
+            let _ = writeln!(out, "      <trackNum>{}</trackNum>", number);
+        }
+        if let Some(ms) = entry.duration_ms.filter(|&ms| ms >= 0) {
+            let _ = writeln!(out, "      <duration>{}</duration>", ms);
+        }
+        out.push_str("    </track>\n");
+    }
+    out.push_str("  </trackList>\n</playlist>\n");
+    out
+}
+
+#[derive(Serialize)]
+struct Jspf<'a> {
+    playlist: JspfPlaylist<'a>,
+}
+
+#[derive(Serialize)]
+struct JspfPlaylist<'a> {
+    title: &'a str,
+    track: Vec<JspfTrack<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JspfTrack<'a> {
+    location: [&'a str; 1],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    creator: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    album: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    track_num: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration: Option<i64>,
+}
+
+fn write_jspf(title: &str, entries: &[PlaylistEntry]) -> String {
+    let jspf = Jspf {
+        playlist: JspfPlaylist {
+            title,
+            track: entries
+                .iter()
+                .map(|entry| JspfTrack {
+                    location: [&entry.location],
+                    title: entry.title.as_deref(),
+                    creator: entry.artist.as_deref(),
+                    album: entry.album.as_deref(),
+                    track_num: entry.track_number.filter(|&n| n > 0),
+                    duration: entry.duration_ms.filter(|&ms| ms >= 0),
+                })
+                .collect(),
+        },
+    };
+    // Serializing plain strings and numbers can't fail
+    serde_json::to_string_pretty(&jspf).unwrap_or_default()
+}
+
+/// Line-based formats can't hold line breaks in names.
+fn single_line(text: &str) -> String {
+    text.replace(['\r', '\n'], " ")
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            // Not allowed in XML 1.0 at all
+            c if c < ' ' && !matches!(c, '\t' | '\n' | '\r') => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// A playlist read from a file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ParsedPlaylist {
+    pub title: Option<String>,
+    pub entries: Vec<PlaylistEntry>,
+}
+
+/// Reads the playlists in a playlist file, telling the format from its
+/// contents: M3U, PLS, XSPF, JSPF or an iTunes library, which may hold
+/// many playlists. Anything unrecognised is read as a plain M3U list.
+pub fn parse(contents: &str) -> Result<Vec<ParsedPlaylist>, AppError> {
+    let contents = contents.trim_start_matches('\u{feff}');
+    let start = contents.trim_start();
+    if start.starts_with('{') {
+        parse_jspf(contents).map(|playlist| vec![playlist])
+    } else if start.starts_with('<') {
+        let root = XmlElement::parse(contents)?;
+        match root.name.as_str() {
+            "playlist" => Ok(vec![parse_xspf(&root)]),
+            "plist" => parse_itunes(&root),
+            other => Err(invalid(format!("unexpected <{}> document", other))),
+        }
+    } else if start
+        .get(..10)
+        .is_some_and(|head| head.eq_ignore_ascii_case("[playlist]"))
+    {
+        Ok(vec![parse_pls(contents)])
+    } else {
+        Ok(vec![parse_m3u(contents)])
+    }
+}
+
+fn invalid(reason: impl std::fmt::Display) -> AppError {
+    AppError::Validation(format!("Invalid playlist file: {}", reason))
+}
+
+fn parse_m3u(contents: &str) -> ParsedPlaylist {
+    let mut playlist = ParsedPlaylist::default();
+    // `#EXTINF` describes the location on the next line
+    let mut info = PlaylistEntry::default();
+    for line in contents.lines().map(str::trim) {
+        if let Some(rest) = line.strip_prefix("#EXTINF:") {
+            let (duration, name) = rest.split_once(',').unwrap_or((rest, ""));
+            // Attributes may follow the duration, e.g. `#EXTINF:-1 tvg-id="x",Name`
+            let seconds = duration
+                .split_whitespace()
+                .next()
+                .and_then(|seconds| seconds.parse::<f64>().ok());
+            info = PlaylistEntry {
+                duration_ms: seconds
+                    .filter(|&seconds| seconds >= 0.0)
+                    .map(|seconds| (seconds * 1000.0) as i64),
+                ..entry_named(name)
+            };
+        } else if let Some(title) = line.strip_prefix("#PLAYLIST:") {
+            playlist.title = non_empty(title);
+        } else if !line.is_empty() && !line.starts_with('#') {
+            playlist.entries.push(PlaylistEntry {
+                location: line.to_string(),
+                ..std::mem::take(&mut info)
+            });
+        }
+    }
+    playlist
+}
+
+fn parse_pls(contents: &str) -> ParsedPlaylist {
+    let mut entries: BTreeMap<u32, PlaylistEntry> = BTreeMap::new();
+    for line in contents.lines().map(str::trim) {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let key = key.trim().to_ascii_lowercase();
+        let value = value.trim();
+        let field = ["file", "title", "length"]
+            .into_iter()
+            .find_map(|field| Some((field, key.strip_prefix(field)?.parse::<u32>().ok()?)));
+        let Some((field, number)) = field else {
+            continue;
+        };
+
+        let entry = entries.entry(number).or_default();
+        match field {
+            "file" => entry.location = value.to_string(),
+            "title" => {
+                let named = entry_named(value);
+                entry.title = named.title;
+                entry.artist = named.artist;
+            }
+            _ => {
+                entry.duration_ms = value
+                    .parse::<i64>()
+                    .ok()
+                    .filter(|&seconds| seconds >= 0)
+                    .map(|seconds| seconds * 1000)
+            }
+        }
+    }
+
+    ParsedPlaylist {
+        title: None,
+        entries: entries
+            .into_values()
+            .filter(|entry| !entry.location.is_empty())
+            .collect(),
+    }
+}
+
+fn parse_xspf(root: &XmlElement) -> ParsedPlaylist {
+    let entries = root
+        .child("trackList")
+        .map(|list| {
+            list.children_named("track")
+                .map(|track| PlaylistEntry {
+                    location: track.child_text("location").unwrap_or_default(),
+                    title: track.child_text("title"),
+                    artist: track.child_text("creator"),
+                    album: track.child_text("album"),
+                    track_number: track.child_text("trackNum").and_then(|n| n.parse().ok()),
+                    duration_ms: track.child_text("duration").and_then(|ms| ms.parse().ok()),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    ParsedPlaylist {
+        title: root.child_text("title"),
+        entries,
+    }
+}
+
+fn parse_jspf(contents: &str) -> Result<ParsedPlaylist, AppError> {
+    let document: serde_json::Value = serde_json::from_str(contents).map_err(invalid)?;
+    let playlist = &document["playlist"];
+    if !playlist.is_object() {
+        return Err(invalid("no \"playlist\" object"));
+    }
+
+    let text = |value: &serde_json::Value| value.as_str().and_then(non_empty);
+    let entries = playlist["track"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .map(|track| {
+            // One location, or a list of alternatives
+            let location = match &track["location"] {
+                serde_json::Value::Array(locations) => locations.first().and_then(text),
+                location => text(location),
+            };
+            PlaylistEntry {
+                location: location.unwrap_or_default(),
+                title: text(&track["title"]),
+                artist: text(&track["creator"]),
+                album: text(&track["album"]),
+                track_number: track["trackNum"].as_i64(),
+                duration_ms: track["duration"].as_i64(),
+            }
+        })
+        .collect();
+
+    Ok(ParsedPlaylist {
+        title: text(&playlist["title"]),
+        entries,
+    })
+}
+
+/// Reads the user-made playlists of an iTunes (or Music app) library
+/// export, leaving out the whole library and the built-in ones.
+fn parse_itunes(root: &XmlElement) -> Result<Vec<ParsedPlaylist>, AppError> {
+    let library = root
+        .elements()
+        .next()
+        .map(Plist::from_element)
+        .ok_or_else(|| invalid("empty property list"))?;
+
+    let mut tracks: HashMap<i64, PlaylistEntry> = HashMap::new();
+    if let Some(Plist::Dict(entries)) = library.get("Tracks") {
+        for (_, track) in entries {
+            let Some(id) = track.get("Track ID").and_then(Plist::as_integer) else {
+                continue;
+            };
+            let text = |key| track.get(key).and_then(Plist::as_str).and_then(non_empty);
+            tracks.insert(
+                id,
+                PlaylistEntry {
+                    location: text("Location").unwrap_or_default(),
+                    title: text("Name"),
+                    artist: text("Artist"),
+                    album: text("Album"),
+                    track_number: track.get("Track Number").and_then(Plist::as_integer),
+                    duration_ms: track.get("Total Time").and_then(Plist::as_integer),
+                },
+            );
+        }
+    }
+
+    let Some(Plist::Array(playlists)) = library.get("Playlists") else {
+        return Err(invalid("no playlists in the library"));
+    };
+    let built_in = |playlist: &Plist| {
+        ["Master", "Folder"].iter().any(|key| {
+            playlist
+                .get(key)
+                .is_some_and(|flag| flag.as_bool() == Some(true))
+        }) || playlist.get("Distinguished Kind").is_some()
+            || playlist.get("Visible").and_then(Plist::as_bool) == Some(false)
+    };
+
+    Ok(playlists
+        .iter()
+        .filter(|playlist| !built_in(playlist))
+        .map(|playlist| {
+            let entries = match playlist.get("Playlist Items") {
+                Some(Plist::Array(items)) => items
+                    .iter()
+                    .filter_map(|item| item.get("Track ID")?.as_integer())
+                    .filter_map(|id| tracks.get(&id).cloned())
+                    .collect(),
+                _ => Vec::new(),
+            };
+            ParsedPlaylist {
+                title: playlist
+                    .get("Name")
+                    .and_then(Plist::as_str)
+                    .and_then(non_empty),
+                entries,
+            }
+        })
+        .collect())
+}
+
+/// An entry described only by a display name, which is usually
+/// "Artist - Title".
+fn entry_named(name: &str) -> PlaylistEntry {
+    let name = name.trim();
+    match name.split_once(" - ") {
+        Some((artist, title)) => PlaylistEntry {
+            title: non_empty(title),
+            artist: non_empty(artist),
+            ..PlaylistEntry::default()
+        },
+        None => PlaylistEntry {
+            title: non_empty(name),
+            ..PlaylistEntry::default()
+        },
+    }
+}
+
+fn non_empty(text: &str) -> Option<String> {
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+/// Just enough of an XML document to read playlists from: elements by
+/// local name and their text.
+#[derive(Debug, Default)]
+struct XmlElement {
+    name: String,
+    children: Vec<XmlNode>,
+}
+
+#[derive(Debug)]
+enum XmlNode {
+    Element(XmlElement),
+    Text(String),
+}
+
+impl XmlElement {
+    /// Reads the root element of `document`.
+    fn parse(document: &str) -> Result<XmlElement, AppError> {
+        let mut reader = Reader::from_str(document);
+        let mut open: Vec<XmlElement> = Vec::new();
+        let mut root = None;
+
+        let element = |start: &BytesStart| XmlElement {
+            name: String::from_utf8_lossy(start.local_name().as_ref()).into_owned(),
+            children: Vec::new(),
+        };
+        let add = |open: &mut Vec<XmlElement>, root: &mut Option<XmlElement>, node| match (
+            open.last_mut(),
+            node,
+        ) {
+            (Some(parent), node) => parent.children.push(node),
+            (None, XmlNode::Element(element)) => {
+                root.get_or_insert(element);
+            }
+            // Whitespace around the root
+            (None, XmlNode::Text(_)) => {}
+        };
+
+        loop {
+            match reader.read_event().map_err(invalid)? {
+                Event::Start(start) => open.push(element(&start)),
+                Event::Empty(start) => add(&mut open, &mut root, XmlNode::Element(element(&start))),
+                Event::End(_) => {
+                    let done = open.pop().ok_or_else(|| invalid("unbalanced tags"))?;
+                    add(&mut open, &mut root, XmlNode::Element(done));
+                }
+                Event::Text(text) => {
+                    let text = text.xml_content().map_err(invalid)?;
+                    add(&mut open, &mut root, XmlNode::Text(text.into_owned()));
+                }
+                Event::CData(text) => {
+                    let text = text.decode().map_err(invalid)?;
+                    add(&mut open, &mut root, XmlNode::Text(text.into_owned()));
+                }
+                Event::GeneralRef(reference) => {
+                    let text = match reference.resolve_char_ref().map_err(invalid)? {
+                        Some(c) => c.to_string(),
+                        None => {
+                            let name = reference.decode().map_err(invalid)?;
+                            resolve_predefined_entity(&name)
+                                .ok_or_else(|| invalid(format!("unknown entity &{};", name)))?
+                                .to_string()
+                        }
+                    };
+                    add(&mut open, &mut root, XmlNode::Text(text));
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+
+        if !open.is_empty() {
+            return Err(invalid("unexpected end of document"));
+        }
+        root.ok_or_else(|| invalid("no root element"))
+    }
+
+    fn elements(&self) -> impl Iterator<Item = &XmlElement> {
+        self.children.iter().filter_map(|node| match node {
+            XmlNode::Element(element) => Some(element),
+            XmlNode::Text(_) => None,
+        })
+    }
+
+    fn children_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a XmlElement> {
+        self.elements().filter(move |element| element.name == name)
+    }
+
+    fn child(&self, name: &str) -> Option<&XmlElement> {
+        self.elements().find(|element| element.name == name)
+    }
+
+    fn text(&self) -> String {
+        let mut text = String::new();
+        for node in &self.children {
+            if let XmlNode::Text(part) = node {
+                text.push_str(part);
+            }
+        }
+        text
+    }
+
+    /// The trimmed text of the first child called `name`, if not empty.
+    fn child_text(&self, name: &str) -> Option<String> {
+        non_empty(&self.child(name)?.text())
+    }
+}
+
+/// A value in an Apple property list.
+#[derive(Debug)]
+enum Plist {
+    Dict(Vec<(String, Plist)>),
+    Array(Vec<Plist>),
+    String(String),
+    Integer(i64),
+    Bool(bool),
+    /// Dates, data and reals, which playlists don't need
+    Other,
+}
+
+impl Plist {
+    fn from_element(element: &XmlElement) -> Plist {
+        match element.name.as_str() {
+            "dict" => {
+                let mut entries = Vec::new();
+                let mut key = None;
+                for child in element.elements() {
+                    if child.name == "key" {
+                        key = Some(child.text());
+                    } else if let Some(key) = key.take() {
+                        entries.push((key, Plist::from_element(child)));
+                    }
+                }
+                Plist::Dict(entries)
+            }
+            "array" => Plist::Array(element.elements().map(Plist::from_element).collect()),
+            "string" => Plist::String(element.text()),
+            "integer" => element
+                .text()
+                .trim()
+                .parse()
+                .map_or(Plist::Other, Plist::Integer),
+            "true" => Plist::Bool(true),
+            "false" => Plist::Bool(false),
+            _ => Plist::Other,
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&Plist> {
+        match self {
+            Plist::Dict(entries) => entries
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Plist::String(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    fn as_integer(&self) -> Option<i64> {
+        match self {
+            Plist::Integer(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    fn as_bool(&self) -> Option<bool> {
+        match self {
+            Plist::Bool(flag) => Some(*flag),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<PlaylistEntry> {
+        vec![
+            PlaylistEntry {
+                location: "https://nas/audio/a?token=x".to_string(),
+                title: Some("Blue & Green".to_string()),
+                artist: Some("Band".to_string()),
+                album: Some("Colours".to_string()),
+                track_number: Some(3),
+                duration_ms: Some(183_600),
+            },
+            PlaylistEntry {
+                location: "https://nas/audio/b?token=y".to_string(),
+                title: Some("untitled.flac".to_string()),
+                ..PlaylistEntry::default()
+            },
+        ]
+    }
+
+    #[test]
+    fn m3u_has_extended_info() {
+        assert_eq!(
+            write(PlaylistFormat::M3u, "Kitchen", &entries()),
+            "#EXTM3U\n#PLAYLIST:Kitchen\n\
+             #EXTINF:184,Band - Blue & Green\nhttps://nas/audio/a?token=x\n\
+             #EXTINF:-1,untitled.flac\nhttps://nas/audio/b?token=y\n"
+        );
+    }
+
+    #[test]
+    fn pls_numbers_entries() {
+        assert_eq!(
+            write(PlaylistFormat::Pls, "Kitchen", &entries()),
+            "[playlist]\n\
+             File1=https://nas/audio/a?token=x\nTitle1=Band - Blue & Green\nLength1=184\n\
+             File2=https://nas/audio/b?token=y\nTitle2=untitled.flac\nLength2=-1\n\
+             NumberOfEntries=2\nVersion=2\n"
+        );
+    }
+
+    #[test]
+    fn xspf_is_escaped() {
+        let xspf = write(PlaylistFormat::Xspf, "Rock <'n'> Roll", &entries());
+        assert!(xspf.contains("<title>Rock &lt;&apos;n&apos;&gt; Roll</title>"));
+        assert!(xspf.contains("<title>Blue &amp; Green</title>"));
+        assert!(xspf.contains("<creator>Band</creator>"));
+        assert!(xspf.contains("<trackNum>3</trackNum>"));
+        assert!(xspf.contains("<duration>183600</duration>"));
+        assert_eq!(xspf.matches("<track>").count(), 2);
+    }
+
+    #[test]
+    fn jspf_lists_locations() {
+        let jspf: serde_json::Value =
+            serde_json::from_str(&write(PlaylistFormat::Jspf, "Kitchen", &entries())).unwrap();
+        let tracks = &jspf["playlist"]["track"];
+        assert_eq!(jspf["playlist"]["title"], "Kitchen");
+        assert_eq!(tracks[0]["location"][0], "https://nas/audio/a?token=x");
+        assert_eq!(tracks[0]["trackNum"], 3);
+        assert_eq!(tracks[1]["title"], "untitled.flac");
+        assert!(tracks[1].get("creator").is_none());
+    }
+
+    #[test]
+    fn written_playlists_read_back() {
+        for format in [
+            PlaylistFormat::M3u,
+            PlaylistFormat::Xspf,
+            PlaylistFormat::Jspf,
+        ] {
+            let parsed = parse(&write(format, "Kitchen", &entries())).unwrap();
+            assert_eq!(parsed.len(), 1, "{:?}", format);
+            assert_eq!(parsed[0].title.as_deref(), Some("Kitchen"), "{:?}", format);
+            assert_eq!(parsed[0].entries[1].location, "https://nas/audio/b?token=y");
+            assert_eq!(parsed[0].entries[1].title.as_deref(), Some("untitled.flac"));
+        }
+
+        let pls = parse(&write(PlaylistFormat::Pls, "Kitchen", &entries())).unwrap();
+        assert_eq!(
+            pls[0].entries[0],
+            PlaylistEntry {
+                location: "https://nas/audio/a?token=x".to_string(),
+                title: Some("Blue & Green".to_string()),
+                artist: Some("Band".to_string()),
+                duration_ms: Some(184_000),
+                ..PlaylistEntry::default()
+            }
+        );
+        assert_eq!(pls[0].entries[1].duration_ms, None);
+    }
+
+    #[test]
+    fn m3u_tolerates_attributes_and_bare_paths() {
+        let parsed = parse(
+            "\u{feff}#EXTM3U\r\n#EXTINF:61 tvg-id=\"x\",Solo\r\n\
+             C:\\Music\\solo.mp3\r\n\r\n# comment\r\nmusic/other.flac\r\n",
+        )
+        .unwrap();
+        let entries = &parsed[0].entries;
+        assert_eq!(parsed[0].title, None);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].location, "C:\\Music\\solo.mp3");
+        assert_eq!(entries[0].title.as_deref(), Some("Solo"));
+        assert_eq!(entries[0].duration_ms, Some(61_000));
+        assert_eq!(
+            entries[1],
+            PlaylistEntry {
+                location: "music/other.flac".to_string(),
+                ..PlaylistEntry::default()
+            }
+        );
+    }
+
+    #[test]
+    fn xspf_entities_are_decoded() {
+        let parsed = parse(
+            "<?xml version=\"1.0\"?>\n\
+             <playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\
+             <title>R&amp;B</title><trackList><track>\
+             <location>file:///music/a%20b.mp3</location>\
+             <title>Caf&#233; &lt;live&gt;</title><creator><![CDATA[A & B]]></creator>\
+             </track></trackList></playlist>",
+        )
+        .unwrap();
+        assert_eq!(parsed[0].title.as_deref(), Some("R&B"));
+        assert_eq!(parsed[0].entries[0].location, "file:///music/a%20b.mp3");
+        assert_eq!(parsed[0].entries[0].title.as_deref(), Some("Café <live>"));
+        assert_eq!(parsed[0].entries[0].artist.as_deref(), Some("A & B"));
+    }
+
+    #[test]
+    fn itunes_library_skips_built_in_playlists() {
+        let parsed = parse(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0"><dict>
+  <key>Tracks</key><dict>
+    <key>101</key><dict>
+      <key>Track ID</key><integer>101</integer>
+      <key>Name</key><string>Song</string>
+      <key>Artist</key><string>Band</string>
+      <key>Total Time</key><integer>200000</integer>
+      <key>Location</key><string>file:///Users/me/Music/Song.m4a</string>
+    </dict>
+  </dict>
+  <key>Playlists</key><array>
+    <dict><key>Name</key><string>Library</string><key>Master</key><true/>
+      <key>Playlist Items</key><array><dict><key>Track ID</key><integer>101</integer></dict></array></dict>
+    <dict><key>Name</key><string>Music</string><key>Distinguished Kind</key><integer>4</integer></dict>
+    <dict><key>Name</key><string>Road trip</string>
+      <key>Playlist Items</key><array>
+        <dict><key>Track ID</key><integer>101</integer></dict>
+        <dict><key>Track ID</key><integer>999</integer></dict>
+      </array></dict>
+  </array>
+</dict></plist>"#,
+        )
+        .unwrap();
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].title.as_deref(), Some("Road trip"));
+        assert_eq!(
+            parsed[0].entries,
+            vec![PlaylistEntry {
+                location: "file:///Users/me/Music/Song.m4a".to_string(),
+                title: Some("Song".to_string()),
+                artist: Some("Band".to_string()),
+                duration_ms: Some(200_000),
+                ..PlaylistEntry::default()
+            }]
+        );
+    }
+
+    #[test]
+    fn malformed_files_are_rejected() {
+        assert!(parse("{\"title\": \"no playlist\"}").is_err());
+        assert!(parse("<html><body/></html>").is_err());
+        assert!(parse("<playlist><trackList>").is_err());
+    }
+}
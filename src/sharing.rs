@@ -0,0 +1,295 @@
+//! Who may see and change a playlist, and which audio files a playlist
+//! shared with a user lets them stream.
+//!
+//! A playlist can be shared with users and groups at read or edit level.
+//! The tracks of a playlist someone else can read are only streamable by
+//! them where the item was added by the file's owner, so adding another
+//! user's file to a playlist never exposes it.
+
+use sqlx::SqlitePool;
+
+use crate::auth::AuthenticatedUser;
+use crate::error::AppError;
+use crate::models::{AudioFile, Playlist, PlaylistVisibility, ShareAccess};
+
+/// Shares that apply to a user, directly or through a group. Bind the
+/// user's id twice.
+pub const SHARES_FOR_USER: &str = "SELECT playlist_id, access FROM playlist_shares
+     WHERE user_id = ?
+        OR group_id IN (SELECT group_id FROM user_group_members WHERE user_id = ?)";
+
+/// The most a user may do with a playlist: owners edit, shares grant their
+/// level, and admins and public playlists allow reading.
+pub async fn playlist_access(
+    user: &AuthenticatedUser,
+    playlist: &Playlist,
+    pool: &SqlitePool,
+) -> Result<Option<ShareAccess>, AppError> {
+    if playlist.user_id == user.user_id {
+        return Ok(Some(ShareAccess::Edit));
+    }
+
+    let shared: Vec<ShareAccess> = sqlx::query_scalar(&format!(
+        "SELECT access FROM ({}) WHERE playlist_id = ?",
+        SHARES_FOR_USER
+    ))
+    .bind(&user.user_id)
+    .bind(&user.user_id)
+    .bind(&playlist.id)
+    .fetch_all(pool)
+    .await?;
+
+    let readable = user.is_admin || playlist.visibility == PlaylistVisibility::Public;
+    Ok(shared
+        .into_iter()
+        .max()
+        .max(readable.then_some(ShareAccess::Read)))
+}
+
+/// Fails with 403 unless the user has at least `needed` access.
+pub async fn require_access(
+    user: &AuthenticatedUser,
+    playlist: &Playlist,
+    needed: ShareAccess,
+    pool: &SqlitePool,
+) -> Result<(), AppError> {
+    if playlist_access(user, playlist, pool).await? >= Some(needed) {
+        return Ok(());
+    }
+    Err(AppError::Forbidden(match needed {
+        ShareAccess::Read => "Not authorized to access this playlist".to_string(),
+        ShareAccess::Edit => "Not authorized to modify this playlist".to_string(),
+    }))
+}
+
+/// Whether a user may stream a file: their own, any if they are an admin,
+/// and files their owner added to a playlist the user can read.
+pub async fn can_stream(
+    user: &AuthenticatedUser,
+    audio: &AudioFile,
+    pool: &SqlitePool,
+) -> Result<bool, AppError> {
+    if user.can_access(&audio.user_id) {
+        return Ok(true);
+    }
+
+    let shared: bool = sqlx::query_scalar(&format!(
+        "SELECT EXISTS (
+             SELECT 1 FROM playlist_items pi
+             JOIN playlists p ON pi.playlist_id = p.id
+             WHERE pi.audio_id = ? AND pi.added_by = ?
+               AND (p.user_id = ? OR p.visibility = 'public'
+                    OR p.id IN (SELECT playlist_id FROM ({})))
+         )",
+        SHARES_FOR_USER
+    ))
+    .bind(&audio.id)
+    .bind(&audio.user_id)
+    .bind(&user.user_id)
+    .bind(&user.user_id)
+    .bind(&user.user_id)
+    .fetch_one(pool)
+    .await?;
+    Ok(shared)
+}
+
+/// The files of a playlist the user can read that they may stream, in
+/// order.
+pub async fn streamable_tracks(
+    user: &AuthenticatedUser,
+    playlist_id: &str,
+    pool: &SqlitePool,
+) -> Result<Vec<AudioFile>, AppError> {
+    let tracks = sqlx::query_as::<_, AudioFile>(
+        "SELECT af.* FROM playlist_items pi
+         JOIN audio_files af ON pi.audio_id = af.id
+         WHERE pi.playlist_id = ?
+           AND (? OR af.user_id = ? OR pi.added_by = af.user_id)
+         ORDER BY pi.position",
+    )
+    .bind(playlist_id)
+    .bind(user.is_admin)
+    .bind(&user.user_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(tracks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::db;
+
+    /// olive owns the private playlist `mix`, shared for reading with rita,
+    /// for editing with eddie, and for reading with the group `family`,
+    /// which gina is in and sam has left, plus the public playlist `open`.
+    /// `mix` holds olive's own track and one of rita's that eddie added.
+    async fn seed() -> SqlitePool {
+        let pool = db::memory_pool().await;
+        sqlx::raw_sql(
+            "INSERT INTO users (id, username, password) VALUES
+                 ('olive', 'olive', 'x'), ('rita', 'rita', 'x'), ('eddie', 'eddie', 'x'),
+                 ('gina', 'gina', 'x'), ('sam', 'sam', 'x'), ('nobody', 'nobody', 'x');
+             INSERT INTO user_groups (id, name, created_at)
+             VALUES ('family', 'family', '2024-01-01T00:00:00Z');
+             INSERT INTO user_group_members (group_id, user_id)
+             VALUES ('family', 'gina'), ('family', 'sam');
+             INSERT INTO audio_files (id, filename, user_id, created_at, mime_type, user_folder) VALUES
+                 ('olive-track', 'o.mp3', 'olive', '2024-01-01T00:00:00Z', 'audio/mpeg', 'olive'),
+                 ('rita-track', 'r.mp3', 'rita', '2024-01-01T00:00:00Z', 'audio/mpeg', 'rita');
+             INSERT INTO playlists (id, name, user_id, created_at, updated_at, visibility) VALUES
+                 ('mix', 'Mix', 'olive', '2024-01-01T00:00:00Z', '2024-01-01T00:00:00Z', 'private'),
+                 ('open', 'Open', 'olive', '2024-01-01T00:00:00Z', '2024-01-01T00:00:00Z', 'public');
+             INSERT INTO playlist_items (id, playlist_id, audio_id, position, added_by) VALUES
+                 ('i1', 'mix', 'olive-track', 1, 'olive'),
+                 ('i2', 'mix', 'rita-track', 2, 'eddie');
+             INSERT INTO playlist_shares (id, playlist_id, user_id, group_id, access, created_at) VALUES
+                 ('s1', 'mix', 'rita', NULL, 'read', '2024-01-01T00:00:00Z'),
+                 ('s2', 'mix', 'eddie', NULL, 'edit', '2024-01-01T00:00:00Z'),
+                 ('s3', 'mix', NULL, 'family', 'read', '2024-01-01T00:00:00Z');
+             DELETE FROM user_group_members WHERE user_id = 'sam';",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        pool
+    }
+
+    fn user(id: &str) -> AuthenticatedUser {
+        AuthenticatedUser {
+            user_id: id.to_string(),
+            is_admin: false,
+            token_id: String::new(),
+        }
+    }
+
+    async fn playlist(pool: &SqlitePool, id: &str) -> Playlist {
+        sqlx::query_as("SELECT * FROM playlists WHERE id = ?")
+            .bind(id)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    async fn audio(pool: &SqlitePool, id: &str) -> AudioFile {
+        sqlx::query_as("SELECT * FROM audio_files WHERE id = ?")
+            .bind(id)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    async fn access(pool: &SqlitePool, user_id: &str, playlist_id: &str) -> Option<ShareAccess> {
+        playlist_access(&user(user_id), &playlist(pool, playlist_id).await, pool)
+            .await
+            .unwrap()
+    }
+
+    async fn tracks(pool: &SqlitePool, user_id: &str) -> Vec<String> {
+        streamable_tracks(&user(user_id), "mix", pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|audio| audio.id)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn owners_edit_and_shares_grant_their_level() {
+        let pool = seed().await;
+        assert_eq!(access(&pool, "olive", "mix").await, Some(ShareAccess::Edit));
+        assert_eq!(access(&pool, "rita", "mix").await, Some(ShareAccess::Read));
+        assert_eq!(access(&pool, "eddie", "mix").await, Some(ShareAccess::Edit));
+        assert_eq!(access(&pool, "nobody", "mix").await, None);
+
+        let mix = playlist(&pool, "mix").await;
+        assert!(
+            require_access(&user("rita"), &mix, ShareAccess::Read, &pool)
+                .await
+                .is_ok()
+        );
+        assert!(matches!(
+            require_access(&user("rita"), &mix, ShareAccess::Edit, &pool).await,
+            Err(AppError::Forbidden(_))
+        ));
+        assert!(
+            require_access(&user("eddie"), &mix, ShareAccess::Edit, &pool)
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn group_shares_follow_membership() {
+        let pool = seed().await;
+        assert_eq!(access(&pool, "gina", "mix").await, Some(ShareAccess::Read));
+        assert_eq!(access(&pool, "sam", "mix").await, None);
+        assert!(
+            !can_stream(&user("sam"), &audio(&pool, "olive-track").await, &pool)
+                .await
+                .unwrap()
+        );
+
+        // A direct share and a group share give the higher of the two
+        sqlx::query(
+            "INSERT INTO playlist_shares (id, playlist_id, user_id, access, created_at) VALUES ('s4', 'mix', 'gina', 'edit', '2024-01-01T00:00:00Z')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        assert_eq!(access(&pool, "gina", "mix").await, Some(ShareAccess::Edit));
+    }
+
+    #[tokio::test]
+    async fn public_playlists_can_be_read_by_anyone() {
+        let pool = seed().await;
+        assert_eq!(
+            access(&pool, "nobody", "open").await,
+            Some(ShareAccess::Read)
+        );
+        assert_eq!(
+            access(&pool, "olive", "open").await,
+            Some(ShareAccess::Edit)
+        );
+
+        sqlx::query(
+            "INSERT INTO playlist_items (id, playlist_id, audio_id, position, added_by) VALUES ('i3', 'open', 'olive-track', 1, 'olive')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        assert!(
+            can_stream(&user("nobody"), &audio(&pool, "olive-track").await, &pool)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn tracks_added_by_someone_else_are_not_streamable() {
+        let pool = seed().await;
+        let olive_track = audio(&pool, "olive-track").await;
+        let rita_track = audio(&pool, "rita-track").await;
+
+        // eddie added rita's track, so sharing mix doesn't expose it
+        for reader in ["gina", "eddie"] {
+            assert!(can_stream(&user(reader), &olive_track, &pool)
+                .await
+                .unwrap());
+            assert!(!can_stream(&user(reader), &rita_track, &pool).await.unwrap());
+            assert_eq!(tracks(&pool, reader).await, ["olive-track"]);
+        }
+
+        // Owners stream their own files wherever they are
+        assert!(can_stream(&user("rita"), &rita_track, &pool).await.unwrap());
+        assert_eq!(tracks(&pool, "rita").await, ["olive-track", "rita-track"]);
+        assert_eq!(tracks(&pool, "olive").await, ["olive-track"]);
+
+        let admin = AuthenticatedUser {
+            is_admin: true,
+            ..user("admin-user-id")
+        };
+        assert!(can_stream(&admin, &rita_track, &pool).await.unwrap());
+    }
+}
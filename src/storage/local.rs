@@ -0,0 +1,257 @@
+use std::io::{ErrorKind, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::StreamExt;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use uuid::Uuid;
+
+use super::{validate_key, ObjectData, ObjectInfo, StorageBackend};
+use crate::error::AppError;
+
+/// Size of the chunks files are streamed in.
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Stores objects as files under a root directory, one sub-folder per
+/// user. Every path is resolved and checked to stay inside the root, so a
+/// symlink cannot lead outside it either.
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    /// Uses `root`, creating it if needed.
+    pub fn new(root: &Path) -> std::io::Result<Self> {
+        std::fs::create_dir_all(root)?;
+        Ok(LocalStorage {
+            root: std::fs::canonicalize(root)?,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The path of `key`, confined to the root. The file and its parent
+    /// folders need not exist yet.
+    pub fn path_for(&self, key: &str) -> Result<PathBuf, AppError> {
+        validate_key(key)?;
+        confine(&self.root, Path::new(key))
+    }
+}
+
+/// Resolves `relative` against the canonical `root`, following symlinks in
+/// whatever part of it exists, and checks the result stays inside `root`.
+fn confine(root: &Path, relative: &Path) -> Result<PathBuf, AppError> {
+    let escape = || AppError::Forbidden("Path escapes the upload directory".to_string());
+
+    // Find the deepest ancestor that exists; the rest is created later and
+    // consists only of plain components, so it cannot leave that ancestor.
+    let candidate = root.join(relative);
+    let mut existing = candidate.as_path();
+    let mut missing = Vec::new();
+    let resolved = loop {
+        match std::fs::canonicalize(existing) {
+            Ok(resolved) => break resolved,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                missing.push(existing.file_name().ok_or_else(escape)?);
+                existing = existing.parent().ok_or_else(escape)?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    };
+    let resolved = missing
+        .into_iter()
+        .rev()
+        .fold(resolved, |path, name| path.join(name));
+
+    if resolved.starts_with(root) && resolved != root {
+        Ok(resolved)
+    } else {
+        Err(escape())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalStorage {
+    async fn put(&self, key: &str, src: &Path) -> Result<(), AppError> {
+        let dest = self.path_for(key)?;
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        // A rename is atomic but only works within one filesystem. Otherwise
+        // the file is copied next to its destination under a hidden name
+        // first, so it never appears half-written.
+        if fs::rename(src, &dest).await.is_err() {
+            let temp = dest.with_file_name(format!(".{}.tmp", Uuid::new_v4()));
+            let copied = async {
+                fs::copy(src, &temp).await?;
+                fs::rename(&temp, &dest).await
+            }
+            .await;
+            if let Err(e) = copied {
+                let _ = fs::remove_file(&temp).await;
+                return Err(e.into());
+            }
+            let _ = fs::remove_file(src).await;
+        }
+        Ok(())
+    }
+
+    async fn get_range(
+        &self,
+        key: &str,
+        range: Option<Range<u64>>,
+    ) -> Result<ObjectData, AppError> {
+        let path = self.path_for(key)?;
+        let mut file = fs::File::open(&path).await?;
+        let size = file.metadata().await?.len();
+
+        let range = range.unwrap_or(0..size);
+        if range.start > range.end || range.end > size {
+            return Err(AppError::Validation("Range out of bounds".to_string()));
+        }
+        file.seek(SeekFrom::Start(range.start)).await?;
+
+        let reader = file.take(range.end - range.start);
+        let stream = futures::stream::unfold(reader, |mut reader| async move {
+            let mut buf = vec![0u8; READ_CHUNK_SIZE];
+            match reader.read(&mut buf).await {
+                Ok(0) => None,
+                Ok(n) => {
+                    buf.truncate(n);
+                    Some((Ok(Bytes::from(buf)), reader))
+                }
+                Err(e) => Some((Err(AppError::from(e)), reader)),
+            }
+        });
+
+        Ok(ObjectData {
+            stream: stream.boxed(),
+            range,
+            size,
+        })
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), AppError> {
+        let path = self.path_for(key)?;
+        fs::remove_file(&path).await?;
+
+        // Drop folders left empty, stopping at the root
+        let mut dir = path.parent();
+        while let Some(parent) = dir {
+            if parent == self.root || fs::remove_dir(parent).await.is_err() {
+                break;
+            }
+            dir = parent.parent();
+        }
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, AppError> {
+        let mut objects = Vec::new();
+        let mut pending = vec![(self.path_for(prefix)?, prefix.to_string())];
+
+        while let Some((dir, dir_key)) = pending.pop() {
+            let mut entries = match fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                // Hidden entries are staging areas, not stored objects
+                if name.starts_with('.') {
+                    continue;
+                }
+                let key = format!("{}/{}", dir_key, name);
+                let file_type = entry.file_type().await?;
+                if file_type.is_dir() {
+                    pending.push((entry.path(), key));
+                } else if file_type.is_file() {
+                    objects.push(ObjectInfo {
+                        key,
+                        size: entry.metadata().await?.len(),
+                    });
+                }
+            }
+        }
+        Ok(objects)
+    }
+
+    async fn stat(&self, key: &str) -> Result<ObjectInfo, AppError> {
+        let metadata = fs::metadata(self.path_for(key)?).await?;
+        if !metadata.is_file() {
+            return Err(AppError::NotFound("File not found".to_string()));
+        }
+        Ok(ObjectInfo {
+            key: key.to_string(),
+            size: metadata.len(),
+        })
+    }
+
+    fn local_path(&self, key: &str) -> Result<Option<PathBuf>, AppError> {
+        self.path_for(key).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+
+    #[test]
+    fn paths_stay_inside_the_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(dir.path()).unwrap();
+        std::fs::create_dir(dir.path().join("user")).unwrap();
+        std::fs::write(dir.path().join("user/a.mp3"), b"x").unwrap();
+
+        assert!(storage.path_for("user/a.mp3").is_ok());
+        assert!(storage.path_for("user/new/b.mp3").is_ok());
+        assert!(storage.path_for("../outside.mp3").is_err());
+        assert!(storage.path_for("user/../../outside.mp3").is_err());
+        assert!(storage.path_for("/etc/passwd").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_cannot_escape_the_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("secret"), b"x").unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("link")).unwrap();
+        let storage = LocalStorage::new(dir.path()).unwrap();
+
+        assert!(storage.path_for("link/secret").is_err());
+        assert!(storage.path_for("link/new/file").is_err());
+    }
+
+    #[tokio::test]
+    async fn put_read_list_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(dir.path()).unwrap();
+        let src = dir.path().join(".incoming");
+        std::fs::write(&src, b"0123456789").unwrap();
+
+        storage.put("user/a.wav", &src).await.unwrap();
+        assert_eq!(storage.stat("user/a.wav").await.unwrap().size, 10);
+
+        let data = storage.get_range("user/a.wav", Some(2..5)).await.unwrap();
+        let bytes: Vec<Bytes> = data.stream.try_collect().await.unwrap();
+        assert_eq!(bytes.concat(), b"234");
+        assert!(storage.get_range("user/a.wav", Some(5..11)).await.is_err());
+
+        let listed = storage.list("user").await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].key, "user/a.wav");
+
+        storage.delete("user/a.wav").await.unwrap();
+        assert!(!dir.path().join("user").exists());
+        assert!(storage.list("user").await.unwrap().is_empty());
+    }
+}
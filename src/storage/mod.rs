@@ -0,0 +1,277 @@
+//! Where uploaded files live.
+//!
+//! Files are addressed by a key built only from server-generated values,
+//! `blobs/{hash prefix}/{sha256}.{ext}` for uploads since deduplication and
+//! `{user_id}/...` before; the name the client sent is kept for display in
+//! the database and never becomes part of a key. A
+//! [`StorageBackend`] maps keys to actual storage: a directory tree under
+//! the upload root ([`LocalStorage`]) or an S3-compatible bucket
+//! ([`S3Storage`]).
+
+mod local;
+mod s3;
+
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+
+use crate::config::{StorageBackendKind, StorageSettings};
+use crate::error::AppError;
+use crate::models::AudioFile;
+
+pub use local::LocalStorage;
+pub use s3::S3Storage;
+
+/// Longest display filename kept, in characters.
+const MAX_FILENAME_CHARS: usize = 255;
+
+/// A stored object and its size.
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    pub key: String,
+    pub size: u64,
+}
+
+/// The bytes of an object, or of part of it.
+pub struct ObjectData {
+    pub stream: BoxStream<'static, Result<Bytes, AppError>>,
+    /// The byte range returned
+    pub range: Range<u64>,
+    /// Total size of the object
+    pub size: u64,
+}
+
+/// Storage for uploaded files. Keys are `/`-separated relative paths that
+/// have passed [`validate_key`]; implementations reject anything else.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Stores the local file `src` under `key`, replacing any existing
+    /// object. `src` may be moved in the process.
+    async fn put(&self, key: &str, src: &Path) -> Result<(), AppError>;
+
+    /// Reads `range` of the object, or all of it. The range must lie within
+    /// the object.
+    async fn get_range(&self, key: &str, range: Option<Range<u64>>)
+        -> Result<ObjectData, AppError>;
+
+    async fn delete(&self, key: &str) -> Result<(), AppError>;
+
+    /// Lists every object under `prefix/`.
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, AppError>;
+
+    async fn stat(&self, key: &str) -> Result<ObjectInfo, AppError>;
+
+    /// The path of `key` on the local filesystem, for backends that keep
+    /// objects there, so external tools can read it directly.
+    fn local_path(&self, key: &str) -> Result<Option<PathBuf>, AppError> {
+        validate_key(key)?;
+        Ok(None)
+    }
+}
+
+/// Builds the backend selected in the configuration.
+pub fn from_config(settings: &StorageSettings) -> std::io::Result<Arc<dyn StorageBackend>> {
+    Ok(match settings.backend {
+        StorageBackendKind::Local => Arc::new(LocalStorage::new(&settings.upload_root)?),
+        StorageBackendKind::S3 => Arc::new(S3Storage::new(&settings.s3)?),
+    })
+}
+
+/// Directory where uploads are written while they are received and
+/// checked, before being handed to the backend.
+pub fn staging_dir(upload_root: &Path) -> PathBuf {
+    upload_root.join(".incoming")
+}
+
+/// Reduces a client-supplied filename to something safe to display: the
+/// last path component, without control characters, trimmed and capped.
+pub fn sanitize_filename(name: &str) -> String {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or("");
+    let cleaned: String = base
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_FILENAME_CHARS)
+        .collect();
+    let cleaned = cleaned.trim().trim_start_matches('.').trim();
+
+    if cleaned.is_empty() {
+        "untitled".to_string()
+    } else {
+        cleaned.to_string()
+    }
+}
+
+/// Like [`sanitize_filename`], but keeps the folders of a path such as the
+/// `Album/01 Track.flac` sent by folder uploads. `.` and `..` are dropped.
+pub fn sanitize_relative_path(name: &str) -> String {
+    let segments: Vec<String> = name
+        .split(['/', '\\'])
+        .filter(|segment| !matches!(segment.trim(), "" | "." | ".."))
+        .map(sanitize_filename)
+        .collect();
+
+    if segments.is_empty() {
+        "untitled".to_string()
+    } else {
+        segments.join("/")
+    }
+}
+
+/// Returns true if `id` can be used as a single key segment. Ids are
+/// generated by the server, so anything else indicates a tampered row.
+fn is_safe_component(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Checks that `key` is a relative path of plain segments: no `.`/`..`,
+/// no hidden or empty segments, no backslashes or control characters.
+pub fn validate_key(key: &str) -> Result<(), AppError> {
+    let valid = !key.is_empty()
+        && key.split('/').all(|segment| {
+            !segment.is_empty()
+                && !segment.starts_with('.')
+                && !segment.chars().any(|c| c == '\\' || c.is_control())
+        });
+
+    if valid {
+        Ok(())
+    } else {
+        Err(AppError::Forbidden(
+            "Path escapes the upload directory".to_string(),
+        ))
+    }
+}
+
+/// The key of the blob with SHA-256 `hash`. Blobs are spread over 256
+/// folders by the first byte of the hash.
+pub fn blob_key(hash: &str, extension: &str) -> Result<String, AppError> {
+    let valid_hash = hash.len() == 64 && hash.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'));
+    if !valid_hash || !is_safe_component(extension) {
+        return Err(AppError::Validation("Invalid storage path".to_string()));
+    }
+    Ok(format!("blobs/{}/{}.{}", &hash[..2], hash, extension))
+}
+
+/// The key of an audio file.
+///
+/// Rows written before `storage_path` existed were stored as
+/// `{user_id}/{id}_{filename}` under the upload root; the key is rebuilt
+/// from the row and validated like any other.
+pub fn audio_key(audio: &AudioFile) -> Result<String, AppError> {
+    let key = match &audio.storage_path {
+        Some(storage_path) => storage_path.clone(),
+        None => format!("{}/{}_{}", audio.user_id, audio.id, audio.filename),
+    };
+    validate_key(&key)?;
+    Ok(key)
+}
+
+/// Checks that `user_id` can be used as the prefix of that user's keys.
+pub fn user_prefix(user_id: &str) -> Result<&str, AppError> {
+    if is_safe_component(user_id) {
+        Ok(user_id)
+    } else {
+        Err(AppError::Validation("Invalid user id".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_strips_directories() {
+        assert_eq!(sanitize_filename("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_filename("..\\..\\windows\\win.ini"), "win.ini");
+        assert_eq!(sanitize_filename("/abs/path/song.mp3"), "song.mp3");
+        assert_eq!(
+            sanitize_filename("album/01 - Intro.flac"),
+            "01 - Intro.flac"
+        );
+    }
+
+    #[test]
+    fn sanitize_rejects_dot_names_and_control_chars() {
+        assert_eq!(sanitize_filename(".."), "untitled");
+        assert_eq!(sanitize_filename("."), "untitled");
+        assert_eq!(sanitize_filename(""), "untitled");
+        assert_eq!(sanitize_filename("../"), "untitled");
+        assert_eq!(sanitize_filename(".hidden.mp3"), "hidden.mp3");
+        assert_eq!(sanitize_filename("a\0b\nc\r.mp3"), "abc.mp3");
+    }
+
+    #[test]
+    fn relative_paths_keep_only_plain_folders() {
+        assert_eq!(
+            sanitize_relative_path("Album/CD1/01 Intro.flac"),
+            "Album/CD1/01 Intro.flac"
+        );
+        assert_eq!(sanitize_relative_path("../../etc/./passwd"), "etc/passwd");
+        assert_eq!(sanitize_relative_path("C:\\Music\\a.mp3"), "C:/Music/a.mp3");
+        assert_eq!(sanitize_relative_path("/"), "untitled");
+        assert_eq!(sanitize_relative_path(""), "untitled");
+    }
+
+    #[test]
+    fn sanitize_caps_length() {
+        let long = "a".repeat(1000) + ".mp3";
+        assert_eq!(sanitize_filename(&long).chars().count(), MAX_FILENAME_CHARS);
+    }
+
+    #[test]
+    fn keys_use_only_generated_values() {
+        let hash = "ab".repeat(32);
+        assert_eq!(
+            blob_key(&hash, "mp3").unwrap(),
+            format!("blobs/ab/{}.mp3", hash)
+        );
+        assert!(blob_key("../../etc", "mp3").is_err());
+        assert!(blob_key(&"AB".repeat(32), "mp3").is_err());
+        assert!(blob_key(&hash, "mp3/../../x").is_err());
+        assert!(user_prefix("..").is_err());
+        assert!(user_prefix("").is_err());
+    }
+
+    #[test]
+    fn keys_reject_traversal() {
+        assert!(validate_key("user/a.mp3").is_ok());
+        assert!(validate_key("user/a b (live).mp3").is_ok());
+        assert!(validate_key("").is_err());
+        assert!(validate_key("/etc/passwd").is_err());
+        assert!(validate_key("../outside.mp3").is_err());
+        assert!(validate_key("user/../../outside.mp3").is_err());
+        assert!(validate_key("user//a.mp3").is_err());
+        assert!(validate_key("user/..\\..\\a.mp3").is_err());
+        assert!(validate_key("user/a\0.mp3").is_err());
+    }
+
+    #[test]
+    fn legacy_keys_are_validated() {
+        let mut audio = AudioFile {
+            id: "id1".to_string(),
+            filename: "song.mp3".to_string(),
+            user_id: "user".to_string(),
+            created_at: chrono::Utc::now(),
+            mime_type: "audio/mpeg".to_string(),
+            size: None,
+            user_folder: "./uploads/user".to_string(),
+            storage_path: None,
+            blob_hash: None,
+            metadata: Default::default(),
+        };
+        assert_eq!(audio_key(&audio).unwrap(), "user/id1_song.mp3");
+
+        audio.filename = "../../../../etc/passwd".to_string();
+        assert!(audio_key(&audio).is_err());
+
+        audio.storage_path = Some("user/id1.mp3".to_string());
+        assert_eq!(audio_key(&audio).unwrap(), "user/id1.mp3");
+    }
+}
@@ -0,0 +1,224 @@
+use std::ops::Range;
+use std::path::Path;
+
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use object_store::aws::{AmazonS3, AmazonS3Builder};
+use object_store::path::Path as ObjectPath;
+use object_store::{GetOptions, GetRange, ObjectMeta, ObjectStore, WriteMultipart};
+use tokio::io::AsyncReadExt;
+
+use super::{validate_key, ObjectData, ObjectInfo, StorageBackend};
+use crate::config::S3Settings;
+use crate::error::AppError;
+
+/// Size of the parts a file is uploaded in; S3 requires at least 5 MiB for
+/// every part but the last.
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// Parts uploaded concurrently.
+const MAX_CONCURRENT_PARTS: usize = 4;
+
+/// Stores objects in an S3-compatible bucket (AWS S3, MinIO, Garage, ...).
+///
+/// Credentials come from the configuration or, if unset there, from the
+/// usual `AWS_*` environment variables.
+pub struct S3Storage {
+    store: AmazonS3,
+}
+
+impl S3Storage {
+    pub fn new(settings: &S3Settings) -> std::io::Result<Self> {
+        let mut builder = AmazonS3Builder::from_env()
+            .with_bucket_name(&settings.bucket)
+            .with_region(&settings.region);
+        if let Some(endpoint) = &settings.endpoint {
+            // Self-hosted stores on the LAN are commonly plain HTTP
+            builder = builder
+                .with_endpoint(endpoint)
+                .with_allow_http(endpoint.starts_with("http://"));
+        }
+        if let Some(key_id) = &settings.access_key_id {
+            builder = builder.with_access_key_id(key_id);
+        }
+        if let Some(secret) = &settings.secret_access_key {
+            builder = builder.with_secret_access_key(secret);
+        }
+
+        let store = builder.build().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid S3 storage settings: {}", e),
+            )
+        })?;
+
+        Ok(S3Storage { store })
+    }
+
+    fn object_path(key: &str) -> Result<ObjectPath, AppError> {
+        validate_key(key)?;
+        ObjectPath::parse(key).map_err(|e| AppError::Validation(e.to_string()))
+    }
+}
+
+fn object_info(meta: ObjectMeta) -> ObjectInfo {
+    ObjectInfo {
+        key: meta.location.to_string(),
+        size: meta.size,
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3Storage {
+    async fn put(&self, key: &str, src: &Path) -> Result<(), AppError> {
+        let location = Self::object_path(key)?;
+        let mut file = tokio::fs::File::open(src).await?;
+
+        let upload = self.store.put_multipart(&location).await?;
+        let mut writer = WriteMultipart::new_with_chunk_size(upload, PART_SIZE);
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = match file.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) => {
+                    let _ = writer.abort().await;
+                    return Err(e.into());
+                }
+            };
+            if let Err(e) = writer.wait_for_capacity(MAX_CONCURRENT_PARTS).await {
+                let _ = writer.abort().await;
+                return Err(e.into());
+            }
+            writer.write(&buf[..n]);
+        }
+        writer.finish().await?;
+        Ok(())
+    }
+
+    async fn get_range(
+        &self,
+        key: &str,
+        range: Option<Range<u64>>,
+    ) -> Result<ObjectData, AppError> {
+        let location = Self::object_path(key)?;
+
+        // An empty range cannot be requested from S3; read nothing instead
+        if let Some(range) = range.as_ref().filter(|r| r.is_empty()) {
+            let size = self.store.head(&location).await?.size;
+            return Ok(ObjectData {
+                stream: futures::stream::empty().boxed(),
+                range: range.clone(),
+                size,
+            });
+        }
+
+        let options = GetOptions {
+            range: range.map(GetRange::Bounded),
+            ..Default::default()
+        };
+        let result = self.store.get_opts(&location, options).await?;
+        let range = result.range.clone();
+        let size = result.meta.size;
+
+        Ok(ObjectData {
+            stream: result.into_stream().map_err(AppError::from).boxed(),
+            range,
+            size,
+        })
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), AppError> {
+        self.store.delete(&Self::object_path(key)?).await?;
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, AppError> {
+        let prefix = Self::object_path(prefix)?;
+        let objects: Vec<ObjectMeta> = self.store.list(Some(&prefix)).try_collect().await?;
+        Ok(objects.into_iter().map(object_info).collect())
+    }
+
+    async fn stat(&self, key: &str) -> Result<ObjectInfo, AppError> {
+        let meta = self.store.head(&Self::object_path(key)?).await?;
+        Ok(object_info(meta))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    /// Connects to the bucket named by `HOME_AUDIO_TEST_S3_BUCKET` at
+    /// `HOME_AUDIO_TEST_S3_ENDPOINT`, with credentials from `AWS_*`.
+    fn test_storage() -> S3Storage {
+        let var = |name: &str| {
+            std::env::var(name).unwrap_or_else(|_| panic!("{} must be set for S3 tests", name))
+        };
+        let settings = S3Settings {
+            bucket: var("HOME_AUDIO_TEST_S3_BUCKET"),
+            endpoint: Some(var("HOME_AUDIO_TEST_S3_ENDPOINT")),
+            ..S3Settings::default()
+        };
+        S3Storage::new(&settings).unwrap()
+    }
+
+    async fn read(storage: &S3Storage, key: &str, range: Option<Range<u64>>) -> Vec<u8> {
+        let data = storage.get_range(key, range).await.unwrap();
+        let bytes: Vec<Bytes> = data.stream.try_collect().await.unwrap();
+        bytes.concat()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs an S3-compatible store, see the README"]
+    async fn put_read_list_delete() {
+        let storage = test_storage();
+        let dir = tempfile::tempdir().unwrap();
+        // Objects go under a fresh prefix so runs never see each other's
+        let prefix = uuid::Uuid::new_v4().to_string();
+        let small_key = format!("{}/small.wav", prefix);
+        let large_key = format!("{}/large.wav", prefix);
+
+        let src = dir.path().join("small");
+        std::fs::write(&src, b"0123456789").unwrap();
+        storage.put(&small_key, &src).await.unwrap();
+
+        // Larger than one part, so it is uploaded in several
+        let large: Vec<u8> = (0..PART_SIZE + 1000).map(|i| i as u8).collect();
+        let src = dir.path().join("large");
+        std::fs::write(&src, &large).unwrap();
+        storage.put(&large_key, &src).await.unwrap();
+
+        assert_eq!(storage.stat(&small_key).await.unwrap().size, 10);
+        assert_eq!(read(&storage, &small_key, None).await, b"0123456789");
+        assert_eq!(read(&storage, &small_key, Some(2..5)).await, b"234");
+        assert!(read(&storage, &small_key, Some(4..4)).await.is_empty());
+        let tail = PART_SIZE as u64 - 10..PART_SIZE as u64 + 1000;
+        assert_eq!(
+            read(&storage, &large_key, Some(tail.clone())).await,
+            &large[tail.start as usize..tail.end as usize]
+        );
+
+        let mut listed: Vec<(String, u64)> = storage
+            .list(&prefix)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|object| (object.key, object.size))
+            .collect();
+        listed.sort();
+        assert_eq!(
+            listed,
+            [
+                (large_key.clone(), large.len() as u64),
+                (small_key.clone(), 10)
+            ]
+        );
+
+        storage.delete(&small_key).await.unwrap();
+        storage.delete(&large_key).await.unwrap();
+        assert!(storage.stat(&small_key).await.is_err());
+        assert!(storage.list(&prefix).await.unwrap().is_empty());
+    }
+}
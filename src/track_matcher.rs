@@ -0,0 +1,486 @@
+//! Finding the stored audio files that entries of an imported playlist
+//! refer to. Playlists written elsewhere point at files on another machine,
+//! so entries are matched by what they have in common with uploads: a link
+//! to this server, the end of the path, the filename, or the tags.
+
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::models::AudioFile;
+use crate::playlist_formats::PlaylistEntry;
+
+/// How similar titles (and artists) must be, from 0 to 1, for a fuzzy match.
+const FUZZY_THRESHOLD: f64 = 0.8;
+
+/// Tracks whose durations differ by more than this are different recordings.
+const DURATION_TOLERANCE_MS: i64 = 10_000;
+
+/// What an entry was matched by, from most to least certain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchKind {
+    /// A link to `/audio/{id}` on this server, e.g. from an exported playlist
+    Link,
+    /// The end of the entry's path is the file's uploaded path
+    Path,
+    Filename,
+    /// Same title and artist
+    Tags,
+    /// Similar title and artist
+    Fuzzy,
+}
+
+/// Matches entries against one user's audio files.
+pub struct TrackMatcher<'a> {
+    files: &'a [AudioFile],
+    by_id: HashMap<&'a str, usize>,
+    /// Lowercased paths of files uploaded with their folders
+    paths: Vec<(usize, String)>,
+    /// Lowercased basename to files
+    by_filename: HashMap<String, Vec<usize>>,
+    /// Normalized title to files
+    by_title: HashMap<String, Vec<usize>>,
+    /// Words of normalized titles to files, to find fuzzy candidates
+    by_word: HashMap<String, Vec<usize>>,
+    /// Normalized title of each file, from its tags or else its filename
+    titles: Vec<String>,
+}
+
+impl<'a> TrackMatcher<'a> {
+    pub fn new(files: &'a [AudioFile]) -> Self {
+        let mut matcher = TrackMatcher {
+            files,
+            by_id: HashMap::new(),
+            paths: Vec::new(),
+            by_filename: HashMap::new(),
+            by_title: HashMap::new(),
+            by_word: HashMap::new(),
+            titles: Vec::with_capacity(files.len()),
+        };
+
+        for (index, file) in files.iter().enumerate() {
+            matcher.by_id.insert(&file.id, index);
+            if file.filename.contains('/') {
+                matcher.paths.push((index, file.filename.to_lowercase()));
+            }
+            matcher
+                .by_filename
+                .entry(basename(&file.filename).to_lowercase())
+                .or_default()
+                .push(index);
+
+            if let Some(title) = file.metadata.title.as_deref().map(normalize) {
+                matcher.by_title.entry(title).or_default().push(index);
+            }
+            let title = normalize(&title_of(file));
+            for word in title.split(' ').filter(|word| word.len() > 1) {
+                let files = matcher.by_word.entry(word.to_string()).or_default();
+                if files.last() != Some(&index) {
+                    files.push(index);
+                }
+            }
+            matcher.titles.push(title);
+        }
+        matcher
+    }
+
+    /// The file `entry` most likely refers to, and how it was found.
+    pub fn find(&self, entry: &PlaylistEntry) -> Option<(&'a AudioFile, MatchKind)> {
+        let path = local_path(&entry.location);
+        let found = self
+            .by_link(&entry.location)
+            .map(|index| (index, MatchKind::Link))
+            .or_else(|| self.by_path(&path).map(|index| (index, MatchKind::Path)))
+            .or_else(|| {
+                self.by_filename(&path, entry)
+                    .map(|index| (index, MatchKind::Filename))
+            })
+            .or_else(|| self.by_tags(entry).map(|index| (index, MatchKind::Tags)))
+            .or_else(|| {
+                self.by_similarity(&path, entry)
+                    .map(|index| (index, MatchKind::Fuzzy))
+            });
+        found.map(|(index, kind)| (&self.files[index], kind))
+    }
+
+    fn by_link(&self, location: &str) -> Option<usize> {
+        let path = location.split(['?', '#']).next()?;
+        let mut segments = path.rsplit('/');
+        let id = segments.next()?;
+        (segments.next()? == "audio")
+            .then(|| self.by_id.get(id).copied())
+            .flatten()
+    }
+
+    /// Files uploaded with their folders are matched by the whole relative
+    /// path, the longest match winning.
+    fn by_path(&self, path: &str) -> Option<usize> {
+        let path = path.to_lowercase();
+        self.paths
+            .iter()
+            .filter(|(_, name)| {
+                path.strip_suffix(name.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.ends_with('/'))
+            })
+            .max_by_key(|(_, name)| name.len())
+            .map(|(index, _)| *index)
+    }
+
+    /// Names like `01 Intro.mp3` recur across albums, so a filename shared
+    /// by several files only matches if the tags settle which one.
+    fn by_filename(&self, path: &str, entry: &PlaylistEntry) -> Option<usize> {
+        let candidates = self.by_filename.get(&basename(path).to_lowercase())?;
+        match candidates.as_slice() {
+            [only] => Some(*only),
+            _ => self.best_by_tags(candidates, entry),
+        }
+    }
+
+    fn by_tags(&self, entry: &PlaylistEntry) -> Option<usize> {
+        let candidates = self.by_title.get(&normalize(entry.title.as_deref()?))?;
+        self.best_by_tags(candidates, entry)
+    }
+
+    /// The candidate whose title and artist equal the entry's, preferring
+    /// the same album and then the closest duration.
+    fn best_by_tags(&self, candidates: &[usize], entry: &PlaylistEntry) -> Option<usize> {
+        let title = normalize(entry.title.as_deref()?);
+        let artist = entry.artist.as_deref().map(normalize);
+        let album = entry.album.as_deref().map(normalize);
+
+        candidates
+            .iter()
+            .copied()
+            .filter(|&index| {
+                let file = &self.files[index].metadata;
+                file.title.as_deref().map(normalize).as_ref() == Some(&title)
+                    && (artist.is_none() || file.artist.as_deref().map(normalize) == artist)
+                    && durations_agree(file.duration_ms, entry.duration_ms)
+            })
+            .min_by_key(|&index| {
+                let file = &self.files[index].metadata;
+                let other_album = album.is_some() && file.album.as_deref().map(normalize) != album;
+                let duration_gap = match (file.duration_ms, entry.duration_ms) {
+                    (Some(a), Some(b)) => (a - b).abs(),
+                    _ => i64::MAX,
+                };
+                (other_album, duration_gap)
+            })
+    }
+
+    /// The file with the most similar title and artist, among those sharing
+    /// a word of the title. Entries without a title are compared by the
+    /// name of the file they point at.
+    fn by_similarity(&self, path: &str, entry: &PlaylistEntry) -> Option<usize> {
+        let title = match &entry.title {
+            Some(title) => normalize(title),
+            None => normalize(stem(basename(path))),
+        };
+        let artist = entry.artist.as_deref().map(normalize);
+
+        let mut candidates: Vec<usize> = title
+            .split(' ')
+            .filter_map(|word| self.by_word.get(word))
+            .flatten()
+            .copied()
+            .collect();
+        candidates.sort_unstable();
+        candidates.dedup();
+
+        candidates
+            .into_iter()
+            .filter(|&index| {
+                durations_agree(self.files[index].metadata.duration_ms, entry.duration_ms)
+            })
+            .map(|index| {
+                let mut score = similarity(&title, &self.titles[index]);
+                let file_artist = self.files[index].metadata.artist.as_deref();
+                if let (Some(artist), Some(file_artist)) = (&artist, file_artist) {
+                    score = 0.7 * score + 0.3 * similarity(artist, &normalize(file_artist));
+                }
+                (index, score)
+            })
+            .filter(|&(_, score)| score >= FUZZY_THRESHOLD)
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(index, _)| index)
+    }
+}
+
+fn durations_agree(a: Option<i64>, b: Option<i64>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => (a - b).abs() <= DURATION_TOLERANCE_MS,
+        _ => true,
+    }
+}
+
+/// The title a file is known by: its tag, or else its filename.
+fn title_of(file: &AudioFile) -> String {
+    match &file.metadata.title {
+        Some(title) => title.clone(),
+        None => stem(basename(&file.filename)).to_string(),
+    }
+}
+
+/// The path of a location as a `/`-separated path, whether it is written
+/// as a `file://` URL, a Windows path or a plain path.
+fn local_path(location: &str) -> String {
+    let path = match location.strip_prefix("file://") {
+        // `file://localhost/Users/...` or `file:///C:/Music/...`
+        Some(rest) => {
+            let path = &rest[rest.find('/').unwrap_or(rest.len())..];
+            percent_decode(path)
+        }
+        None => location.to_string(),
+    };
+    path.replace('\\', "/")
+}
+
+fn basename(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+fn stem(name: &str) -> &str {
+    match name.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() => stem,
+        _ => name,
+    }
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| text.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Lowercase letters and digits separated by single spaces, without
+/// bracketed additions like "(Remastered 2011)" or "[Live]".
+fn normalize(text: &str) -> String {
+    let mut normalized = String::with_capacity(text.len());
+    let mut depth = 0usize;
+    for c in text.chars() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth = depth.saturating_sub(1),
+            _ if depth > 0 => {}
+            c if c.is_alphanumeric() => normalized.extend(c.to_lowercase()),
+            _ => {
+                if !normalized.is_empty() && !normalized.ends_with(' ') {
+                    normalized.push(' ');
+                }
+            }
+        }
+    }
+    normalized.trim_end().to_string()
+}
+
+/// 1 minus the edit distance relative to the longer string.
+fn similarity(a: &str, b: &str) -> f64 {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 0.0;
+    }
+
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (i, ca) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    1.0 - previous[b.len()] as f64 / longest as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::AudioMetadata;
+    use chrono::Utc;
+
+    fn file(id: &str, filename: &str, title: Option<&str>, artist: Option<&str>) -> AudioFile {
+        AudioFile {
+            id: id.to_string(),
+            filename: filename.to_string(),
+            user_id: "u".to_string(),
+            created_at: Utc::now(),
+            mime_type: "audio/flac".to_string(),
+            size: None,
+            user_folder: "u".to_string(),
+            storage_path: None,
+            blob_hash: None,
+            metadata: AudioMetadata {
+                title: title.map(str::to_string),
+                artist: artist.map(str::to_string),
+                duration_ms: Some(200_000),
+                ..AudioMetadata::default()
+            },
+        }
+    }
+
+    fn library() -> Vec<AudioFile> {
+        vec![
+            file("a", "Band/First/01 Intro.flac", Some("Intro"), Some("Band")),
+            file(
+                "b",
+                "Band/Second/01 Intro.flac",
+                Some("Intro"),
+                Some("Other Band"),
+            ),
+            file(
+                "c",
+                "Wish You Were Here.flac",
+                Some("Wish You Were Here"),
+                Some("Pink Floyd"),
+            ),
+            file("d", "untagged song.mp3", None, None),
+        ]
+    }
+
+    fn entry(location: &str, title: Option<&str>, artist: Option<&str>) -> PlaylistEntry {
+        PlaylistEntry {
+            location: location.to_string(),
+            title: title.map(str::to_string),
+            artist: artist.map(str::to_string),
+            ..PlaylistEntry::default()
+        }
+    }
+
+    fn matched(files: &[AudioFile], entry: &PlaylistEntry) -> Option<(String, MatchKind)> {
+        TrackMatcher::new(files)
+            .find(entry)
+            .map(|(file, kind)| (file.id.clone(), kind))
+    }
+
+    #[test]
+    fn links_to_this_server_match_directly() {
+        let files = library();
+        let link = entry("https://nas:8443/audio/c?token=abc", None, None);
+        assert_eq!(
+            matched(&files, &link),
+            Some(("c".to_string(), MatchKind::Link))
+        );
+    }
+
+    #[test]
+    fn paths_match_by_their_ending() {
+        let files = library();
+        let itunes = entry(
+            "file://localhost/Users/me/Music/Band/Second/01%20Intro.flac",
+            None,
+            None,
+        );
+        assert_eq!(
+            matched(&files, &itunes),
+            Some(("b".to_string(), MatchKind::Path))
+        );
+        let windows = entry("C:\\Music\\Band\\First\\01 Intro.flac", None, None);
+        assert_eq!(
+            matched(&files, &windows),
+            Some(("a".to_string(), MatchKind::Path))
+        );
+    }
+
+    #[test]
+    fn shared_filenames_need_tags() {
+        let files = library();
+        assert_eq!(
+            matched(&files, &entry("/elsewhere/01 Intro.flac", None, None)),
+            None
+        );
+        assert_eq!(
+            matched(
+                &files,
+                &entry(
+                    "/elsewhere/01 Intro.flac",
+                    Some("Intro"),
+                    Some("Other Band")
+                )
+            ),
+            Some(("b".to_string(), MatchKind::Filename))
+        );
+        assert_eq!(
+            matched(&files, &entry("/x/UNTAGGED SONG.mp3", None, None)),
+            Some(("d".to_string(), MatchKind::Filename))
+        );
+    }
+
+    #[test]
+    fn tags_and_similar_titles_match() {
+        let files = library();
+        assert_eq!(
+            matched(
+                &files,
+                &entry(
+                    "/x/wywh.mp3",
+                    Some("wish you were here"),
+                    Some("PINK FLOYD")
+                )
+            ),
+            Some(("c".to_string(), MatchKind::Tags))
+        );
+        assert_eq!(
+            matched(
+                &files,
+                &entry(
+                    "/x/track.mp3",
+                    Some("Wish You Were Here (2011 Remaster)"),
+                    Some("Pink Floyd")
+                )
+            ),
+            Some(("c".to_string(), MatchKind::Tags))
+        );
+        assert_eq!(
+            matched(
+                &files,
+                &entry(
+                    "/x/track.mp3",
+                    Some("Wish You Where Here"),
+                    Some("Pink Floyd")
+                )
+            ),
+            Some(("c".to_string(), MatchKind::Fuzzy))
+        );
+        assert_eq!(
+            matched(
+                &files,
+                &entry("/x/track.mp3", Some("Comfortably Numb"), None)
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn different_durations_do_not_match() {
+        let files = library();
+        let mut live = entry("/x/track.mp3", Some("Wish You Were Here"), None);
+        live.duration_ms = Some(400_000);
+        assert_eq!(matched(&files, &live), None);
+    }
+
+    #[test]
+    fn normalizing_ignores_case_punctuation_and_brackets() {
+        assert_eq!(normalize("  Don't Stop (Live) [2004]!"), "don t stop");
+        assert_eq!(percent_decode("a%20b%zz%C3%A9"), "a b%zzé");
+    }
+}
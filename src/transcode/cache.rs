@@ -0,0 +1,411 @@
+//! A disk cache of transcoded files, so replaying a track doesn't repeat
+//! the work. An output is cached once it has been transcoded completely;
+//! cached files are served like stored ones, ranges included. When the
+//! cache grows past its size limit the least recently used files go.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+use super::TranscodeOptions;
+use crate::error::AppError;
+use crate::models::AudioFile;
+use crate::storage::{LocalStorage, StorageBackend};
+
+pub struct TranscodeCache {
+    files: LocalStorage,
+    /// Total size the cached files may take up; 0 disables the cache
+    max_size: u64,
+    index: Mutex<CacheIndex>,
+}
+
+#[derive(Default)]
+struct CacheIndex {
+    entries: HashMap<String, CacheEntry>,
+    total_size: u64,
+    /// Increases with every use, ordering entries by how recently they
+    /// were used
+    clock: u64,
+}
+
+struct CacheEntry {
+    size: u64,
+    last_used: u64,
+}
+
+impl CacheIndex {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn insert(&mut self, key: String, size: u64) {
+        let last_used = self.tick();
+        if let Some(old) = self.entries.insert(key, CacheEntry { size, last_used }) {
+            self.total_size -= old.size;
+        }
+        self.total_size += size;
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(old) = self.entries.remove(key) {
+            self.total_size -= old.size;
+        }
+    }
+}
+
+impl TranscodeCache {
+    /// Opens the cache in `dir`, creating it if needed and picking up the
+    /// files already there, oldest first.
+    pub fn open(dir: &Path, max_size: u64) -> std::io::Result<Self> {
+        let files = LocalStorage::new(dir)?;
+
+        let mut found = Vec::new();
+        for entry in std::fs::read_dir(files.root())? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') {
+                // Left over from a transcode that was cut short
+                let _ = std::fs::remove_file(entry.path());
+            } else if entry.file_type()?.is_dir() {
+                for file in std::fs::read_dir(entry.path())? {
+                    let file = file?;
+                    let metadata = file.metadata()?;
+                    if metadata.is_file() {
+                        let key = format!("{}/{}", name, file.file_name().to_string_lossy());
+                        let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                        found.push((modified, key, metadata.len()));
+                    }
+                }
+            }
+        }
+        found.sort();
+
+        let mut index = CacheIndex::default();
+        for (_, key, size) in found {
+            index.insert(key, size);
+        }
+        Ok(TranscodeCache {
+            files,
+            max_size,
+            index: Mutex::new(index),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_size > 0
+    }
+
+    /// Where cached files are kept, for serving them.
+    pub fn files(&self) -> &LocalStorage {
+        &self.files
+    }
+
+    /// The key of `source` transcoded with `options`. It includes the hash
+    /// of the source's contents, so an entry can never be served for
+    /// different contents.
+    pub fn key(source: &AudioFile, options: TranscodeOptions) -> String {
+        format!(
+            "{}/{}-{}k-{}.{}",
+            source.id,
+            options.format.name(),
+            options.bitrate_kbps,
+            contents_id(source),
+            options.format.extension()
+        )
+    }
+
+    /// The key of HLS segment `index` of `source`, cut every `segment_ms`.
+    pub fn segment_key(
+        source: &AudioFile,
+        bitrate_kbps: u32,
+        segment_ms: u64,
+        index: u64,
+    ) -> String {
+        format!(
+            "{}/hls-{}ms-{}k-{}-{}.ts",
+            source.id,
+            segment_ms,
+            bitrate_kbps,
+            contents_id(source),
+            index
+        )
+    }
+
+    /// Returns true if `key` is cached, marking it as used.
+    pub fn lookup(&self, key: &str) -> bool {
+        let mut index = self.lock_index();
+        let tick = index.tick();
+        match index.entries.get_mut(key) {
+            Some(entry) => {
+                entry.last_used = tick;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drops `key` from the index, e.g. when its file turned out to be gone.
+    pub fn forget(&self, key: &str) {
+        self.lock_index().remove(key);
+    }
+
+    /// Passes `output` through, storing a copy as `key` if it ends
+    /// successfully. A stream that is dropped or fails is not cached.
+    pub fn record(
+        self: &Arc<Self>,
+        key: String,
+        output: BoxStream<'static, Result<Bytes, AppError>>,
+    ) -> BoxStream<'static, Result<Bytes, AppError>> {
+        if !self.is_enabled() {
+            return output;
+        }
+
+        let recording = Recording {
+            cache: self.clone(),
+            key,
+            temp: self.files.root().join(format!(".{}.part", Uuid::new_v4())),
+            file: None,
+            size: 0,
+            failed: false,
+        };
+
+        futures::stream::unfold(
+            (output, Some(recording)),
+            |(mut output, mut recording)| async move {
+                match output.next().await {
+                    Some(Ok(chunk)) => {
+                        if let Some(rec) = recording.as_mut() {
+                            rec.write(&chunk).await;
+                        }
+                        Some((Ok(chunk), (output, recording)))
+                    }
+                    Some(Err(e)) => Some((Err(e), (output, None))),
+                    None => {
+                        if let Some(rec) = recording.take() {
+                            rec.finish().await;
+                        }
+                        None
+                    }
+                }
+            },
+        )
+        .boxed()
+    }
+
+    /// Removes everything cached for the audio file `audio_id`.
+    pub async fn invalidate(&self, audio_id: &str) {
+        let Ok(objects) = self.files.list(audio_id).await else {
+            return;
+        };
+        for object in objects {
+            self.forget(&object.key);
+            let _ = self.files.delete(&object.key).await;
+        }
+    }
+
+    /// Removes the least recently used files until the cache fits its size
+    /// limit again.
+    async fn evict(&self) {
+        let victims = {
+            let mut index = self.lock_index();
+            let mut by_age: Vec<(u64, String)> = index
+                .entries
+                .iter()
+                .map(|(key, entry)| (entry.last_used, key.clone()))
+                .collect();
+            by_age.sort();
+
+            let mut victims = Vec::new();
+            for (_, key) in by_age {
+                if index.total_size <= self.max_size {
+                    break;
+                }
+                index.remove(&key);
+                victims.push(key);
+            }
+            victims
+        };
+
+        // Files being served stay readable until they are closed
+        for key in victims {
+            let _ = self.files.delete(&key).await;
+        }
+    }
+
+    fn lock_index(&self) -> std::sync::MutexGuard<'_, CacheIndex> {
+        self.index.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Identifies the contents of `source` in cache keys.
+fn contents_id(source: &AudioFile) -> &str {
+    source
+        .blob_hash
+        .as_deref()
+        .map_or("original", |hash| &hash[..hash.len().min(16)])
+}
+
+/// A transcode output being copied into the cache.
+struct Recording {
+    cache: Arc<TranscodeCache>,
+    key: String,
+    temp: PathBuf,
+    file: Option<tokio::fs::File>,
+    size: u64,
+    /// Writing the copy failed; the stream itself carries on
+    failed: bool,
+}
+
+impl Recording {
+    async fn write(&mut self, chunk: &[u8]) {
+        if self.failed {
+            return;
+        }
+        if self.file.is_none() {
+            match tokio::fs::File::create(&self.temp).await {
+                Ok(file) => self.file = Some(file),
+                Err(_) => {
+                    self.failed = true;
+                    return;
+                }
+            }
+        }
+        if let Some(file) = self.file.as_mut() {
+            if file.write_all(chunk).await.is_err() {
+                self.failed = true;
+                return;
+            }
+        }
+        self.size += chunk.len() as u64;
+    }
+
+    async fn finish(mut self) {
+        let Some(mut file) = self.file.take() else {
+            return;
+        };
+        if self.failed || file.flush().await.is_err() || self.size > self.cache.max_size {
+            return;
+        }
+        drop(file);
+
+        if self.cache.files.put(&self.key, &self.temp).await.is_ok() {
+            self.cache.lock_index().insert(self.key.clone(), self.size);
+            self.cache.evict().await;
+        }
+    }
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        // Gone already if the copy made it into the cache
+        let temp = std::mem::take(&mut self.temp);
+        tokio::task::spawn_blocking(move || {
+            let _ = std::fs::remove_file(temp);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transcode::TranscodeFormat;
+    use futures::TryStreamExt;
+
+    async fn transcoded(cache: &Arc<TranscodeCache>, key: &str, data: &'static [u8]) {
+        let output = futures::stream::iter(vec![Ok(Bytes::from_static(data))]).boxed();
+        let passed: Vec<Bytes> = cache
+            .record(key.to_string(), output)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(passed.concat(), data);
+    }
+
+    #[tokio::test]
+    async fn completed_outputs_are_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Arc::new(TranscodeCache::open(dir.path(), 100).unwrap());
+
+        assert!(!cache.lookup("a/mp3-128k-x.mp3"));
+        transcoded(&cache, "a/mp3-128k-x.mp3", b"0123456789").await;
+        assert!(cache.lookup("a/mp3-128k-x.mp3"));
+        assert_eq!(
+            cache.files().stat("a/mp3-128k-x.mp3").await.unwrap().size,
+            10
+        );
+
+        // Picked up again after a restart
+        let reopened = TranscodeCache::open(dir.path(), 100).unwrap();
+        assert!(reopened.lookup("a/mp3-128k-x.mp3"));
+    }
+
+    #[tokio::test]
+    async fn failed_outputs_are_not_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Arc::new(TranscodeCache::open(dir.path(), 100).unwrap());
+
+        let output = futures::stream::iter(vec![
+            Ok(Bytes::from_static(b"01234")),
+            Err(AppError::Internal("ffmpeg failed".to_string())),
+        ])
+        .boxed();
+        let passed: Vec<Result<Bytes, AppError>> =
+            cache.record("a/x.mp3".to_string(), output).collect().await;
+        assert_eq!(passed.len(), 2);
+        assert!(!cache.lookup("a/x.mp3"));
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_files_are_evicted() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Arc::new(TranscodeCache::open(dir.path(), 25).unwrap());
+
+        transcoded(&cache, "a/1.mp3", b"0123456789").await;
+        transcoded(&cache, "b/1.mp3", b"0123456789").await;
+        assert!(cache.lookup("a/1.mp3"));
+        transcoded(&cache, "c/1.mp3", b"0123456789").await;
+
+        assert!(cache.lookup("a/1.mp3"));
+        assert!(!cache.lookup("b/1.mp3"));
+        assert!(cache.lookup("c/1.mp3"));
+        assert!(cache.files().stat("b/1.mp3").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn invalidation_removes_every_variant() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Arc::new(TranscodeCache::open(dir.path(), 100).unwrap());
+
+        transcoded(&cache, "a/mp3-128k-x.mp3", b"0123").await;
+        transcoded(&cache, "a/opus-96k-x.opus", b"0123").await;
+        transcoded(&cache, "b/mp3-128k-x.mp3", b"0123").await;
+        cache.invalidate("a").await;
+
+        assert!(!cache.lookup("a/mp3-128k-x.mp3"));
+        assert!(!cache.lookup("a/opus-96k-x.opus"));
+        assert!(cache.lookup("b/mp3-128k-x.mp3"));
+    }
+
+    #[test]
+    fn keys_include_the_source_contents() {
+        let options = TranscodeOptions::new(TranscodeFormat::Opus, Some(64)).unwrap();
+        let mut source = crate::transcode::tests::source("audio/flac", None);
+        source.blob_hash = Some("ab".repeat(32));
+        assert_eq!(
+            TranscodeCache::key(&source, options),
+            "a/opus-64k-abababababababab.opus"
+        );
+        assert_eq!(
+            TranscodeCache::segment_key(&source, 128, 6000, 3),
+            "a/hls-6000ms-128k-abababababababab-3.ts"
+        );
+    }
+}
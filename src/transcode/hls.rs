@@ -0,0 +1,216 @@
+//! HTTP Live Streaming, for players that cope better with it than with
+//! progressive downloads. A track is cut into fixed-length segments of AAC
+//! in MPEG-TS, each transcoded on its own when first asked for, and offered
+//! at several bitrates the player switches between.
+
+use std::fmt::Write;
+
+/// Content type of `.m3u8` playlists.
+pub const PLAYLIST_MIME_TYPE: &str = "application/vnd.apple.mpegurl";
+
+/// Content type of segments.
+pub const SEGMENT_MIME_TYPE: &str = "video/mp2t";
+
+/// Codec of every variant, as given in the master playlist.
+const CODECS: &str = "mp4a.40.2";
+
+/// A segment's place in its track.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentSpan {
+    pub start_ms: u64,
+    pub duration_ms: u64,
+}
+
+/// The segments a track of `duration_ms` is cut into, all `segment_ms`
+/// long but the last.
+pub fn segments(duration_ms: u64, segment_ms: u64) -> impl Iterator<Item = SegmentSpan> {
+    (0..duration_ms)
+        .step_by(segment_ms as usize)
+        .map(move |start_ms| SegmentSpan {
+            start_ms,
+            duration_ms: segment_ms.min(duration_ms - start_ms),
+        })
+}
+
+/// Segment `index` of a track of `duration_ms`, if it has one.
+pub fn segment(duration_ms: u64, segment_ms: u64, index: u64) -> Option<SegmentSpan> {
+    let start_ms = index.checked_mul(segment_ms)?;
+    (start_ms < duration_ms).then(|| SegmentSpan {
+        start_ms,
+        duration_ms: segment_ms.min(duration_ms - start_ms),
+    })
+}
+
+/// `uri` with a stream token appended as `?token=`, if there is one.
+/// Players fetch every URI themselves and can't send an `Authorization`
+/// header. Tokens are URL-safe as they are.
+fn with_token(uri: String, token: Option<&str>) -> String {
+    match token {
+        Some(token) => format!("{}?token={}", uri, token),
+        None => uri,
+    }
+}
+
+/// The master playlist offering a variant per bitrate, each a media
+/// playlist at `{bitrate}/index.m3u8` relative to it, fetched with `token`.
+pub fn master_playlist(bitrates: &[u32], token: Option<&str>) -> String {
+    let mut playlist = String::from("#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-INDEPENDENT-SEGMENTS\n");
+    for bitrate in bitrates {
+        // MPEG-TS adds roughly a tenth on top of the audio
+        let average = bitrate * 1000;
+        let _ = writeln!(
+            playlist,
+            "#EXT-X-STREAM-INF:BANDWIDTH={},AVERAGE-BANDWIDTH={},CODECS=\"{}\"\n{}",
+            average + average / 10,
+            average,
+            CODECS,
+            with_token(format!("{}/index.m3u8", bitrate), token)
+        );
+    }
+    playlist
+}
+
+/// A track in a media playlist.
+pub struct MediaTrack {
+    pub duration_ms: u64,
+    /// Prepended to a segment's number and `.ts` to form its URI
+    pub segment_prefix: String,
+    /// Stream token the track's segments are fetched with
+    pub token: Option<String>,
+}
+
+/// A media playlist of `tracks` played one after another.
+pub fn media_playlist(tracks: &[MediaTrack], segment_ms: u64) -> String {
+    let mut playlist = String::from("#EXTM3U\n#EXT-X-VERSION:3\n");
+    let _ = writeln!(
+        playlist,
+        "#EXT-X-TARGETDURATION:{}",
+        segment_ms.div_ceil(1000)
+    );
+    playlist.push_str("#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n");
+
+    for (position, track) in tracks.iter().enumerate() {
+        // Each track's timestamps start again from zero
+        if position > 0 {
+            playlist.push_str("#EXT-X-DISCONTINUITY\n");
+        }
+        for (index, span) in segments(track.duration_ms, segment_ms).enumerate() {
+            let _ = writeln!(
+                playlist,
+                "#EXTINF:{}.{:03},\n{}",
+                span.duration_ms / 1000,
+                span.duration_ms % 1000,
+                with_token(
+                    format!("{}{}.ts", track.segment_prefix, index),
+                    track.token.as_deref()
+                )
+            );
+        }
+    }
+    playlist.push_str("#EXT-X-ENDLIST\n");
+    playlist
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_are_cut_into_fixed_segments() {
+        let spans: Vec<SegmentSpan> = segments(13_500, 6_000).collect();
+        assert_eq!(
+            spans,
+            vec![
+                SegmentSpan {
+                    start_ms: 0,
+                    duration_ms: 6_000
+                },
+                SegmentSpan {
+                    start_ms: 6_000,
+                    duration_ms: 6_000
+                },
+                SegmentSpan {
+                    start_ms: 12_000,
+                    duration_ms: 1_500
+                },
+            ]
+        );
+        assert_eq!(segment(13_500, 6_000, 2), Some(spans[2]));
+        assert_eq!(segment(13_500, 6_000, 3), None);
+        assert_eq!(segment(12_000, 6_000, 2), None);
+        assert_eq!(segment(12_000, 6_000, u64::MAX), None);
+    }
+
+    #[test]
+    fn master_playlist_lists_every_bitrate() {
+        let playlist = master_playlist(&[64, 128], None);
+        assert_eq!(
+            playlist,
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-INDEPENDENT-SEGMENTS\n\
+             #EXT-X-STREAM-INF:BANDWIDTH=70400,AVERAGE-BANDWIDTH=64000,CODECS=\"mp4a.40.2\"\n64/index.m3u8\n\
+             #EXT-X-STREAM-INF:BANDWIDTH=140800,AVERAGE-BANDWIDTH=128000,CODECS=\"mp4a.40.2\"\n128/index.m3u8\n"
+        );
+    }
+
+    #[test]
+    fn master_playlist_passes_the_token_on() {
+        let playlist = master_playlist(&[64, 128], Some("t.o.k"));
+        let uris: Vec<&str> = playlist.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(
+            uris,
+            ["64/index.m3u8?token=t.o.k", "128/index.m3u8?token=t.o.k"]
+        );
+    }
+
+    #[test]
+    fn media_playlist_separates_tracks() {
+        let tracks = [
+            MediaTrack {
+                duration_ms: 7_250,
+                segment_prefix: "/audio/a/hls/64/".to_string(),
+                token: None,
+            },
+            MediaTrack {
+                duration_ms: 2_000,
+                segment_prefix: "/audio/b/hls/64/".to_string(),
+                token: None,
+            },
+        ];
+        assert_eq!(
+            media_playlist(&tracks, 6_000),
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:6\n\
+             #EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n\
+             #EXTINF:6.000,\n/audio/a/hls/64/0.ts\n\
+             #EXTINF:1.250,\n/audio/a/hls/64/1.ts\n\
+             #EXT-X-DISCONTINUITY\n\
+             #EXTINF:2.000,\n/audio/b/hls/64/0.ts\n\
+             #EXT-X-ENDLIST\n"
+        );
+    }
+
+    #[test]
+    fn segment_uris_carry_each_tracks_token() {
+        let tracks = [
+            MediaTrack {
+                duration_ms: 7_250,
+                segment_prefix: "/audio/a/hls/64/".to_string(),
+                token: Some("token-a".to_string()),
+            },
+            MediaTrack {
+                duration_ms: 2_000,
+                segment_prefix: "/audio/b/hls/64/".to_string(),
+                token: Some("token-b".to_string()),
+            },
+        ];
+        let playlist = media_playlist(&tracks, 6_000);
+        let uris: Vec<&str> = playlist.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(
+            uris,
+            [
+                "/audio/a/hls/64/0.ts?token=token-a",
+                "/audio/a/hls/64/1.ts?token=token-a",
+                "/audio/b/hls/64/0.ts?token=token-b",
+            ]
+        );
+    }
+}
@@ -0,0 +1,531 @@
+//! On-the-fly transcoding, so lossless files can be played over slow
+//! connections. The work is done by an `ffmpeg` process per stream, whose
+//! output is sent to the client as it is produced.
+
+mod cache;
+pub mod hls;
+
+pub use cache::TranscodeCache;
+
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, ChildStdout, Command};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+
+use crate::config::TranscodingSettings;
+use crate::error::AppError;
+use crate::models::{AudioFile, StreamQuery, TranscodingPreferences};
+use crate::storage::StorageBackend;
+
+/// Size of the chunks transcoded output is streamed in.
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// How long a segment waits for a transcode slot before it is refused.
+/// Players fetch segments back to back, so they are queued briefly rather
+/// than refused outright like whole files.
+const SEGMENT_SLOT_WAIT: Duration = Duration::from_secs(10);
+
+/// The formats files can be transcoded to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TranscodeFormat {
+    /// Opus in an Ogg container
+    Opus,
+    Mp3,
+    /// AAC in ADTS frames
+    Aac,
+}
+
+impl TranscodeFormat {
+    pub fn parse(name: &str) -> Option<TranscodeFormat> {
+        match name {
+            "opus" => Some(TranscodeFormat::Opus),
+            "mp3" => Some(TranscodeFormat::Mp3),
+            "aac" => Some(TranscodeFormat::Aac),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TranscodeFormat::Opus => "opus",
+            TranscodeFormat::Mp3 => "mp3",
+            TranscodeFormat::Aac => "aac",
+        }
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            TranscodeFormat::Opus => "audio/ogg",
+            TranscodeFormat::Mp3 => "audio/mpeg",
+            TranscodeFormat::Aac => "audio/aac",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            TranscodeFormat::Opus => "opus",
+            TranscodeFormat::Mp3 => "mp3",
+            TranscodeFormat::Aac => "aac",
+        }
+    }
+
+    pub fn default_bitrate(self) -> u32 {
+        match self {
+            TranscodeFormat::Opus => 96,
+            TranscodeFormat::Mp3 => 192,
+            TranscodeFormat::Aac => 128,
+        }
+    }
+
+    /// Bitrates the encoder accepts, in kbit/s.
+    pub fn bitrates(self) -> RangeInclusive<u32> {
+        match self {
+            TranscodeFormat::Opus => 16..=256,
+            TranscodeFormat::Mp3 | TranscodeFormat::Aac => 32..=320,
+        }
+    }
+
+    /// The ffmpeg encoder.
+    fn encoder(self) -> &'static str {
+        match self {
+            TranscodeFormat::Opus => "libopus",
+            TranscodeFormat::Mp3 => "libmp3lame",
+            TranscodeFormat::Aac => "aac",
+        }
+    }
+
+    /// The ffmpeg output format for a whole file.
+    fn muxer(self) -> &'static str {
+        match self {
+            TranscodeFormat::Opus => "ogg",
+            TranscodeFormat::Mp3 => "mp3",
+            TranscodeFormat::Aac => "adts",
+        }
+    }
+}
+
+/// What a file is transcoded to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TranscodeOptions {
+    pub format: TranscodeFormat,
+    pub bitrate_kbps: u32,
+}
+
+impl TranscodeOptions {
+    /// Uses the format's default bitrate if none is given.
+    pub fn new(format: TranscodeFormat, bitrate_kbps: Option<u32>) -> Result<Self, AppError> {
+        let bitrate_kbps = bitrate_kbps.unwrap_or_else(|| format.default_bitrate());
+        let allowed = format.bitrates();
+        if !allowed.contains(&bitrate_kbps) {
+            return Err(AppError::Validation(format!(
+                "Bitrate for {} must be between {} and {} kbit/s",
+                format.name(),
+                allowed.start(),
+                allowed.end()
+            )));
+        }
+        Ok(TranscodeOptions {
+            format,
+            bitrate_kbps,
+        })
+    }
+}
+
+/// Decides how `source` is streamed: `None` for the original file. The
+/// request's `format` and `bitrate` win over the user's preferences, and
+/// `format=original` turns a preferred format off. A source that is already
+/// in the format at no more than the bitrate is sent as it is.
+pub fn resolve(
+    query: &StreamQuery,
+    preferences: &TranscodingPreferences,
+    source: &AudioFile,
+) -> Result<Option<TranscodeOptions>, AppError> {
+    let (format, preferred_bitrate) = match query.format.as_deref() {
+        Some("original") => return Ok(None),
+        Some(name) => {
+            let format = TranscodeFormat::parse(name).ok_or_else(|| {
+                AppError::Validation(format!(
+                    "Unknown format '{}'; use opus, mp3, aac or original",
+                    name
+                ))
+            })?;
+            let preferred = preferences
+                .bitrate_kbps
+                .filter(|_| preferences.format == Some(format));
+            (format, preferred)
+        }
+        None => match preferences.format {
+            Some(format) => (format, preferences.bitrate_kbps),
+            None if query.bitrate.is_some() => {
+                return Err(AppError::Validation("A bitrate needs a format".to_string()))
+            }
+            None => return Ok(None),
+        },
+    };
+
+    let options = TranscodeOptions::new(format, query.bitrate.or(preferred_bitrate))?;
+    let already_fits = source.mime_type == format.mime_type()
+        && format != TranscodeFormat::Opus
+        && source
+            .metadata
+            .bitrate_kbps
+            .is_some_and(|bitrate| bitrate <= options.bitrate_kbps as i64);
+    Ok((!already_fits).then_some(options))
+}
+
+/// Runs ffmpeg, at most `max_concurrent` processes at a time.
+pub struct Transcoder {
+    ffmpeg: PathBuf,
+    slots: Arc<Semaphore>,
+}
+
+/// A running ffmpeg process. It is killed when this is dropped, e.g. when
+/// the client goes away mid-stream.
+struct Running {
+    child: Child,
+    stdout: ChildStdout,
+    /// The task feeding a stored object that isn't a local file to stdin
+    feeder: Option<JoinHandle<Result<(), AppError>>>,
+    _slot: OwnedSemaphorePermit,
+}
+
+impl Transcoder {
+    pub fn new(settings: &TranscodingSettings) -> Self {
+        Transcoder {
+            ffmpeg: settings.ffmpeg_path.clone(),
+            slots: Arc::new(Semaphore::new(settings.max_concurrent)),
+        }
+    }
+
+    /// Starts transcoding the stored object `key` and returns the output as
+    /// it is produced. A stream that ends in an error was cut short.
+    pub async fn transcode(
+        &self,
+        storage: Arc<dyn StorageBackend>,
+        key: &str,
+        options: TranscodeOptions,
+    ) -> Result<BoxStream<'static, Result<Bytes, AppError>>, AppError> {
+        let slot = self.slots.clone().try_acquire_owned().map_err(|_| busy())?;
+        let mut command = self.command(storage.as_ref(), key, None)?;
+        command
+            .args(["-map", "0:a:0", "-map_metadata", "0"])
+            .args(["-c:a", options.format.encoder()])
+            .arg("-b:a")
+            .arg(format!("{}k", options.bitrate_kbps))
+            .args(["-f", options.format.muxer()]);
+        self.run(command, storage, key, slot).await
+    }
+
+    /// Transcodes `span` of the stored object `key` into an HLS segment:
+    /// AAC in MPEG-TS, timestamped from the span's start so consecutive
+    /// segments play back to back.
+    pub async fn transcode_segment(
+        &self,
+        storage: Arc<dyn StorageBackend>,
+        key: &str,
+        bitrate_kbps: u32,
+        span: hls::SegmentSpan,
+    ) -> Result<BoxStream<'static, Result<Bytes, AppError>>, AppError> {
+        let slot = tokio::time::timeout(SEGMENT_SLOT_WAIT, self.slots.clone().acquire_owned())
+            .await
+            .map_err(|_| busy())?
+            .map_err(|_| busy())?;
+        let mut command = self.command(storage.as_ref(), key, Some(span.start_ms))?;
+        command
+            .arg("-t")
+            .arg(seconds(span.duration_ms))
+            .args(["-map", "0:a:0", "-map_metadata", "-1"])
+            .args(["-c:a", TranscodeFormat::Aac.encoder()])
+            .arg("-b:a")
+            .arg(format!("{}k", bitrate_kbps))
+            .arg("-output_ts_offset")
+            .arg(seconds(span.start_ms))
+            .args(["-f", "mpegts"]);
+        self.run(command, storage, key, slot).await
+    }
+
+    /// An ffmpeg command reading `key`, from `start_ms` if given.
+    fn command(
+        &self,
+        storage: &dyn StorageBackend,
+        key: &str,
+        start_ms: Option<u64>,
+    ) -> Result<Command, AppError> {
+        let mut command = Command::new(&self.ffmpeg);
+        command.args(["-hide_banner", "-loglevel", "error"]);
+        if let Some(start_ms) = start_ms {
+            command.arg("-ss").arg(seconds(start_ms));
+        }
+
+        // ffmpeg reads local files itself, which lets it seek in formats
+        // that need it; anything else is fed through stdin
+        command.arg("-i");
+        match storage.local_path(key)? {
+            Some(path) => command.arg(path).stdin(Stdio::null()),
+            None => command.arg("pipe:0").stdin(Stdio::piped()),
+        };
+        Ok(command)
+    }
+
+    /// Runs `command`, which is still missing its output, and returns what
+    /// it writes.
+    async fn run(
+        &self,
+        mut command: Command,
+        storage: Arc<dyn StorageBackend>,
+        key: &str,
+        slot: OwnedSemaphorePermit,
+    ) -> Result<BoxStream<'static, Result<Bytes, AppError>>, AppError> {
+        command
+            .arg("pipe:1")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true);
+
+        let mut child = command.spawn().map_err(|e| {
+            AppError::Internal(format!("Cannot run {}: {}", self.ffmpeg.display(), e))
+        })?;
+
+        let feeder = match child.stdin.take() {
+            Some(mut stdin) => {
+                let mut source = storage.get_range(key, None).await?.stream;
+                Some(tokio::spawn(async move {
+                    while let Some(chunk) = source.next().await {
+                        // ffmpeg only sees the input end, so a failed read
+                        // is reported through here rather than as ffmpeg
+                        // failing
+                        let chunk = chunk?;
+                        // A closed pipe means ffmpeg has read all it needs
+                        if stdin.write_all(&chunk).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(())
+                }))
+            }
+            None => None,
+        };
+
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| AppError::Internal("ffmpeg output is not piped".to_string()))?;
+        let running = Running {
+            child,
+            stdout,
+            feeder,
+            _slot: slot,
+        };
+
+        let stream = futures::stream::unfold(Some(running), |running| async move {
+            let mut running = running?;
+            let mut buf = vec![0u8; READ_CHUNK_SIZE];
+            match running.stdout.read(&mut buf).await {
+                Ok(0) => {
+                    let result = match running.child.wait().await {
+                        Ok(status) if status.success() => match running.feeder.take() {
+                            Some(feeder) => feeder.await.unwrap_or_else(|e| {
+                                Err(AppError::Internal(format!("Input feeder failed: {}", e)))
+                            }),
+                            None => Ok(()),
+                        },
+                        Ok(status) => Err(AppError::Internal(format!("ffmpeg failed: {}", status))),
+                        Err(e) => Err(e.into()),
+                    };
+                    // Output that ends in an error is incomplete and is not
+                    // cached
+                    result.err().map(|e| (Err(e), None))
+                }
+                Ok(n) => {
+                    buf.truncate(n);
+                    Some((Ok(Bytes::from(buf)), Some(running)))
+                }
+                Err(e) => Some((Err(e.into()), None)),
+            }
+        });
+        Ok(stream.boxed())
+    }
+}
+
+fn busy() -> AppError {
+    AppError::Unavailable("Too many transcodes in progress; try again shortly".to_string())
+}
+
+/// Milliseconds as ffmpeg's seconds, e.g. `6.000`.
+fn seconds(ms: u64) -> String {
+    format!("{}.{:03}", ms / 1000, ms % 1000)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::metadata::AudioMetadata;
+    use crate::storage::{ObjectData, ObjectInfo};
+    use async_trait::async_trait;
+    use chrono::Utc;
+    use std::path::Path;
+
+    pub(crate) fn source(mime_type: &str, bitrate_kbps: Option<i64>) -> AudioFile {
+        AudioFile {
+            id: "a".to_string(),
+            filename: "a".to_string(),
+            user_id: "u".to_string(),
+            created_at: Utc::now(),
+            mime_type: mime_type.to_string(),
+            size: None,
+            user_folder: "u".to_string(),
+            storage_path: None,
+            blob_hash: None,
+            metadata: AudioMetadata {
+                bitrate_kbps,
+                ..AudioMetadata::default()
+            },
+        }
+    }
+
+    fn query(format: Option<&str>, bitrate: Option<u32>) -> StreamQuery {
+        StreamQuery {
+            format: format.map(str::to_string),
+            bitrate,
+        }
+    }
+
+    #[test]
+    fn request_wins_over_preferences() {
+        let flac = source("audio/flac", Some(1411));
+        let preferences = TranscodingPreferences {
+            format: Some(TranscodeFormat::Opus),
+            bitrate_kbps: Some(64),
+        };
+
+        let options = resolve(&query(None, None), &preferences, &flac).unwrap();
+        assert_eq!(
+            options,
+            TranscodeOptions::new(TranscodeFormat::Opus, Some(64)).ok()
+        );
+
+        let options = resolve(&query(Some("mp3"), None), &preferences, &flac).unwrap();
+        assert_eq!(options.unwrap().bitrate_kbps, 192);
+
+        let options = resolve(&query(Some("opus"), Some(128)), &preferences, &flac).unwrap();
+        assert_eq!(options.unwrap().bitrate_kbps, 128);
+
+        assert_eq!(
+            resolve(&query(Some("original"), None), &preferences, &flac).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn originals_are_kept_without_a_format() {
+        let flac = source("audio/flac", None);
+        let none = TranscodingPreferences::default();
+        assert_eq!(resolve(&query(None, None), &none, &flac).unwrap(), None);
+        assert!(resolve(&query(None, Some(96)), &none, &flac).is_err());
+    }
+
+    #[test]
+    fn invalid_requests_are_rejected() {
+        let flac = source("audio/flac", None);
+        let none = TranscodingPreferences::default();
+        assert!(resolve(&query(Some("wma"), None), &none, &flac).is_err());
+        assert!(resolve(&query(Some("opus"), Some(8)), &none, &flac).is_err());
+        assert!(resolve(&query(Some("mp3"), Some(999)), &none, &flac).is_err());
+    }
+
+    #[test]
+    fn small_enough_sources_are_not_transcoded() {
+        let none = TranscodingPreferences::default();
+        let mp3 = source("audio/mpeg", Some(128));
+        assert_eq!(
+            resolve(&query(Some("mp3"), None), &none, &mp3).unwrap(),
+            None
+        );
+        assert!(resolve(&query(Some("mp3"), Some(96)), &none, &mp3)
+            .unwrap()
+            .is_some());
+        assert!(resolve(&query(Some("aac"), None), &none, &mp3)
+            .unwrap()
+            .is_some());
+    }
+
+    /// Storage whose objects break off with an error after a few bytes.
+    struct BrokenStorage;
+
+    #[async_trait]
+    impl StorageBackend for BrokenStorage {
+        async fn put(&self, _: &str, _: &Path) -> Result<(), AppError> {
+            Err(AppError::Storage("connection reset".to_string()))
+        }
+
+        async fn get_range(
+            &self,
+            _: &str,
+            _: Option<std::ops::Range<u64>>,
+        ) -> Result<ObjectData, AppError> {
+            let chunks = vec![
+                Ok(Bytes::from_static(b"0123")),
+                Err(AppError::Storage("connection reset".to_string())),
+            ];
+            Ok(ObjectData {
+                stream: futures::stream::iter(chunks).boxed(),
+                range: 0..10,
+                size: 10,
+            })
+        }
+
+        async fn delete(&self, _: &str) -> Result<(), AppError> {
+            Err(AppError::Storage("connection reset".to_string()))
+        }
+
+        async fn list(&self, _: &str) -> Result<Vec<ObjectInfo>, AppError> {
+            Err(AppError::Storage("connection reset".to_string()))
+        }
+
+        async fn stat(&self, _: &str) -> Result<ObjectInfo, AppError> {
+            Err(AppError::Storage("connection reset".to_string()))
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn failed_source_read_fails_the_transcode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Stands in for ffmpeg, copying its input to its output
+        let dir = tempfile::tempdir().unwrap();
+        let ffmpeg = dir.path().join("ffmpeg");
+        std::fs::write(&ffmpeg, "#!/bin/sh\nexec cat\n").unwrap();
+        std::fs::set_permissions(&ffmpeg, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let transcoder = Transcoder::new(&TranscodingSettings {
+            ffmpeg_path: ffmpeg,
+            ..TranscodingSettings::default()
+        });
+
+        let cache = Arc::new(TranscodeCache::open(&dir.path().join("cache"), 100).unwrap());
+        let options = TranscodeOptions::new(TranscodeFormat::Mp3, None).unwrap();
+        let output = transcoder
+            .transcode(Arc::new(BrokenStorage), "a.flac", options)
+            .await
+            .unwrap();
+        let chunks: Vec<Result<Bytes, AppError>> = cache
+            .record("a/mp3.mp3".to_string(), output)
+            .collect()
+            .await;
+
+        assert!(matches!(chunks.last(), Some(Err(AppError::Storage(_)))));
+        assert!(!cache.lookup("a/mp3.mp3"));
+        assert!(cache.files().list("a").await.unwrap().is_empty());
+    }
+}
@@ -1,56 +1,162 @@
+use chrono::{Datelike, Duration, Utc};
+use rcgen::{
+    date_time_ymd, CertificateParams, DistinguishedName, DnType, KeyPair, PKCS_ECDSA_P256_SHA256,
+};
+use std::collections::HashSet;
 use std::fs;
-use std::path::Path;
-use std::process::Command;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
 
-pub fn ensure_ssl_cert_exists() -> std::io::Result<()> {
-    let cert_path = Path::new("cert.pem");
-    let key_path = Path::new("key.pem");
+/// Settings for the self-signed certificate generated at startup.
+#[derive(Debug, Clone)]
+pub struct CertOptions {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    /// DNS names and IP addresses the certificate is valid for
+    pub subject_alt_names: Vec<String>,
+    pub validity_days: i64,
+    /// Regenerate a self-signed certificate this many days before it expires
+    pub renew_before_days: i64,
+}
+
+impl Default for CertOptions {
+    fn default() -> Self {
+        CertOptions {
+            cert_path: PathBuf::from("cert.pem"),
+            key_path: PathBuf::from("key.pem"),
+            subject_alt_names: default_subject_alt_names(),
+            validity_days: 365,
+            renew_before_days: 30,
+        }
+    }
+}
+
+/// Names a LAN client is likely to use to reach this machine: localhost,
+/// the hostname and its mDNS `.local` form, and every non-loopback address.
+pub fn default_subject_alt_names() -> Vec<String> {
+    let mut names = vec![
+        "localhost".to_string(),
+        "127.0.0.1".to_string(),
+        "::1".to_string(),
+    ];
+
+    if let Some(hostname) = gethostname::gethostname().to_str() {
+        let hostname = hostname.trim().to_lowercase();
+        if !hostname.is_empty() && hostname != "localhost" {
+            let short = hostname.split('.').next().unwrap_or(&hostname).to_string();
+            names.push(hostname.clone());
+            names.push(format!("{}.local", short));
+        }
+    }
 
-    // Check if certificates already exist
-    if cert_path.exists() && key_path.exists() {
-        println!("SSL certificates already exist");
-        return Ok(());
+    if let Ok(interfaces) = if_addrs::get_if_addrs() {
+        for iface in interfaces.iter().filter(|i| !i.is_loopback()) {
+            let ip = iface.ip();
+            // Link-local IPv6 addresses need a zone id and can't go in a SAN
+            if matches!(ip, IpAddr::V6(v6) if (v6.segments()[0] & 0xffc0) == 0xfe80) {
+                continue;
+            }
+            names.push(ip.to_string());
+        }
     }
 
-    println!("Generating SSL certificates...");
-
-    // Create OpenSSL command to generate self-signed certificate
-    let output = Command::new("openssl")
-        .args([
-            "req",
-            "-x509",
-            "-newkey",
-            "rsa:4096",
-            "-keyout",
-            "key.pem",
-            "-out",
-            "cert.pem",
-            "-days",
-            "365",
-            "-nodes",
-            "-subj",
-            "/CN=localhost",
-        ])
-        .output()?;
-
-    if !output.status.success() {
-        let error = String::from_utf8_lossy(&output.stderr);
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("Failed to generate SSL certificates: {}", error),
-        ));
+    let mut seen = HashSet::new();
+    names.retain(|name| seen.insert(name.clone()));
+    names
+}
+
+/// Makes sure a usable certificate and key exist, generating an ECDSA P-256
+/// self-signed certificate if they are missing or if our own self-signed
+/// certificate is about to expire. Certificates issued by someone else are
+/// never replaced; we only warn about their expiry.
+pub fn ensure_ssl_cert_exists(options: &CertOptions) -> io::Result<()> {
+    if options.cert_path.exists() && options.key_path.exists() {
+        let pem = fs::read(&options.cert_path)?;
+        let (expires_at, self_signed) = inspect_certificate(&pem)?;
+        let renew_at = expires_at - Duration::days(options.renew_before_days);
+
+        if Utc::now() < renew_at {
+            println!("SSL certificates already exist");
+            return Ok(());
+        }
+        if !self_signed {
+            eprintln!(
+                "Warning: certificate {} expires at {}; replace it soon",
+                options.cert_path.display(),
+                expires_at
+            );
+            return Ok(());
+        }
+        println!(
+            "Self-signed certificate expires at {}, renewing...",
+            expires_at
+        );
+    } else {
+        println!("Generating SSL certificates...");
     }
 
+    generate_self_signed(options)?;
     println!("SSL certificates generated successfully");
+    Ok(())
+}
+
+fn generate_self_signed(options: &CertOptions) -> io::Result<()> {
+    let cert_error =
+        |e: rcgen::Error| io::Error::other(format!("Failed to generate SSL certificates: {}", e));
+
+    let mut params =
+        CertificateParams::new(options.subject_alt_names.clone()).map_err(cert_error)?;
+
+    let mut subject = DistinguishedName::new();
+    subject.push(
+        DnType::CommonName,
+        options
+            .subject_alt_names
+            .first()
+            .map(String::as_str)
+            .unwrap_or("localhost"),
+    );
+    subject.push(DnType::OrganizationName, "Home Audio Server");
+    params.distinguished_name = subject;
+
+    let now = Utc::now();
+    let expires = now + Duration::days(options.validity_days);
+    params.not_before = date_time_ymd(now.year(), now.month() as u8, now.day() as u8);
+    params.not_after = date_time_ymd(expires.year(), expires.month() as u8, expires.day() as u8);
+
+    let key_pair = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).map_err(cert_error)?;
+    let cert = params.self_signed(&key_pair).map_err(cert_error)?;
+
+    fs::write(&options.cert_path, cert.pem())?;
+    fs::write(&options.key_path, key_pair.serialize_pem())?;
 
     // Set appropriate permissions for key file
     #[cfg(unix)]
     {
         use std::os::unix::fs::PermissionsExt;
-        let mut perms = fs::metadata(key_path)?.permissions();
+        let mut perms = fs::metadata(&options.key_path)?.permissions();
         perms.set_mode(0o600); // Read/write for owner only
-        fs::set_permissions(key_path, perms)?;
+        fs::set_permissions(&options.key_path, perms)?;
     }
 
     Ok(())
 }
+
+/// Returns the expiry time of the first certificate in a PEM file and
+/// whether it is self-signed (issuer equals subject).
+fn inspect_certificate(pem: &[u8]) -> io::Result<(chrono::DateTime<Utc>, bool)> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    let (_, pem) = x509_parser::pem::parse_x509_pem(pem)
+        .map_err(|e| invalid(format!("Invalid certificate PEM: {}", e)))?;
+    let cert = pem
+        .parse_x509()
+        .map_err(|e| invalid(format!("Invalid certificate: {}", e)))?;
+
+    let expires_at = chrono::DateTime::from_timestamp(cert.validity().not_after.timestamp(), 0)
+        .ok_or_else(|| invalid("Certificate expiry out of range".to_string()))?;
+    let self_signed = cert.issuer() == cert.subject();
+
+    Ok((expires_at, self_signed))
+}
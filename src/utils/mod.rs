@@ -1,3 +1,3 @@
 pub mod cert;
 
-pub use cert::ensure_ssl_cert_exists;
+pub use cert::{ensure_ssl_cert_exists, CertOptions};